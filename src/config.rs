@@ -0,0 +1,105 @@
+// Server configuration, read from a TOML file
+//
+//     # How ETags are made: "strong" (default), "weak" or "content" (a hash of the file)
+//     etag = "strong"
+//
+//     # Content types for extensions the built-in table gets wrong or doesn't know
+//     [mime_types]
+//     log = "text/plain"
+//     webmanifest = "application/manifest+json"
+//
+//     # Cache-Control by path or type (see CachePolicies)
+//     [cache]
+//     listings = "no-cache"
+//     [[cache.rules]]
+//     type = "image/*"
+//     cache_control = "public, max-age=86400"
+//
+//     # Compression of text and other compressible types (see Compression)
+//     [compression]
+//     min_size = 1024
+//     encodings = ["br", "zstd", "gzip"]
+//
+//     # Index files of directories, and listings of those without one (see Directories)
+//     [directories]
+//     index = ["index.html", "index.htm"]
+//     listings = true
+//
+//     # In-memory cache of small files (see FileCache)
+//     [file_cache]
+//     max_size = 33554432
+//     max_file_size = 262144
+//
+//     # Watch the root for changes with inotify (Linux), e.g. to drop changed files from the cache
+//     [watch]
+//     enabled = true
+//
+//     # Resumable tus uploads at /_tus into the root; anyone can upload unless a middleware
+//     # such as BasicAuth guards them
+//     # Partial uploads are kept in `staging` (mode 0700), by default `.<root name>.uploads`
+//     # next to the root
+//     [uploads]
+//     enabled = false
+//     staging = "/var/lib/simple-http/uploads"
+//
+// Every section is optional; anything left out keeps its default.
+
+use crate::http::cache_control::CachePolicies;
+use crate::http::compression::Compression;
+use crate::http::conditional::ETagKind;
+use crate::http::directory::Directories;
+use crate::http::file_cache::FileCacheConfig;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub etag: ETagKind,
+    pub mime_types: HashMap<String, String>, // Extension (without the dot) -> Content-Type
+    pub cache: CachePolicies,
+    pub compression: Compression,
+    pub directories: Directories,
+    pub file_cache: FileCacheConfig,
+    pub watch: WatchConfig,
+    pub uploads: UploadsConfig,
+}
+
+// Settings of the `[watch]` config section
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WatchConfig {
+    pub enabled: bool,
+}
+
+impl Default for WatchConfig {
+    fn default() -> WatchConfig {
+        WatchConfig { enabled: true }
+    }
+}
+
+// Settings of the `[uploads]` config section
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UploadsConfig {
+    pub enabled: bool,
+    pub staging: Option<PathBuf>, // Partial uploads; `.<root name>.uploads` next to the root if unset
+}
+
+impl Config {
+    // Reads and parses a config file
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Config> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)?;
+        Config::parse(&text)
+            .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", path.display(), err)))
+    }
+
+    // Parses a config from TOML text
+    pub fn parse(text: &str) -> io::Result<Config> {
+        toml::from_str(text).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+}
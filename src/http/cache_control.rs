@@ -0,0 +1,270 @@
+use globset::{Glob, GlobBuilder, GlobMatcher};
+use serde::Deserialize;
+use std::io;
+
+// Cache-Control (and Expires) policies for served files and directory listings
+//
+// Rules match the request path, the Content-Type, or both, and the first rule that
+// matches decides. Path patterns are globs (`*` stays within a segment, `**` crosses
+// them); a pattern without a slash matches the file name in any directory. Type patterns
+// match the MIME type without parameters, e.g. `image/*`.
+//
+//     [cache]
+//     listings = "no-cache"
+//
+//     [[cache.rules]]
+//     path = "assets/**/*.[0-9a-f][0-9a-f][0-9a-f][0-9a-f]*.{js,css}"
+//     cache_control = "public, max-age=31536000, immutable"
+//
+//     [[cache.rules]]
+//     type = "image/*"
+//     cache_control = "public, max-age=86400"
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(try_from = "CacheConfig")]
+pub struct CachePolicies {
+    rules: Vec<CacheRule>,
+    listings: Option<CacheControl>,
+}
+
+// One rule; a rule without patterns matches everything
+#[derive(Debug, Clone)]
+struct CacheRule {
+    path: Option<PathPattern>,
+    content_type: Option<GlobMatcher>,
+    cache_control: CacheControl,
+}
+
+// A path glob and what it is matched against
+#[derive(Debug, Clone)]
+struct PathPattern {
+    matcher: GlobMatcher,
+    file_name_only: bool, // Patterns without a slash match the last segment
+}
+
+// A Cache-Control value, sent with an Expires header when it has a max-age
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheControl {
+    pub value: String,
+    pub max_age: Option<u64>, // Seconds
+}
+
+impl CacheControl {
+    pub fn new(value: &str) -> CacheControl {
+        let max_age = value
+            .split(',')
+            .filter_map(|directive| directive.split_once('='))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("max-age"))
+            .and_then(|(_, seconds)| seconds.trim().trim_matches('"').parse().ok());
+        CacheControl {
+            value: value.trim().to_string(),
+            max_age,
+        }
+    }
+}
+
+impl CachePolicies {
+    pub fn new() -> CachePolicies {
+        CachePolicies::default()
+    }
+
+    // Adds a rule for files matching a path pattern and/or a type pattern
+    pub fn rule(
+        mut self,
+        path: Option<&str>,
+        content_type: Option<&str>,
+        cache_control: &str,
+    ) -> io::Result<CachePolicies> {
+        let path = match path {
+            Some(pattern) => {
+                let pattern = pattern.trim_start_matches('/');
+                let glob = GlobBuilder::new(pattern)
+                    .literal_separator(true)
+                    .build()
+                    .map_err(invalid_pattern)?;
+                Some(PathPattern {
+                    matcher: glob.compile_matcher(),
+                    file_name_only: !pattern.contains('/'),
+                })
+            }
+            None => None,
+        };
+        let content_type = match content_type {
+            Some(pattern) => Some(
+                Glob::new(&pattern.to_ascii_lowercase())
+                    .map_err(invalid_pattern)?
+                    .compile_matcher(),
+            ),
+            None => None,
+        };
+        self.rules.push(CacheRule {
+            path,
+            content_type,
+            cache_control: CacheControl::new(cache_control),
+        });
+        Ok(self)
+    }
+
+    // Sets the Cache-Control of directory listings
+    pub fn listings(mut self, cache_control: &str) -> CachePolicies {
+        self.listings = Some(CacheControl::new(cache_control));
+        self
+    }
+
+    // The policy for a file, given its decoded request path and Content-Type
+    pub fn for_file(&self, path: &str, content_type: &str) -> Option<CacheControl> {
+        let path = path.trim_start_matches('/');
+        let file_name = path.rsplit('/').next().unwrap_or(path);
+        let essence = content_type
+            .split(';')
+            .next()
+            .unwrap_or("")
+            .trim()
+            .to_ascii_lowercase();
+        self.rules
+            .iter()
+            .find(|rule| {
+                rule.path.as_ref().is_none_or(|pattern| {
+                    pattern.matcher.is_match(if pattern.file_name_only {
+                        file_name
+                    } else {
+                        path
+                    })
+                }) && rule
+                    .content_type
+                    .as_ref()
+                    .is_none_or(|matcher| matcher.is_match(&essence))
+            })
+            .map(|rule| rule.cache_control.clone())
+    }
+
+    // The policy for directory listings
+    pub fn for_listing(&self) -> Option<CacheControl> {
+        self.listings.clone()
+    }
+}
+
+// The `[cache]` section of the config file
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct CacheConfig {
+    listings: Option<String>,
+    rules: Vec<CacheRuleConfig>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CacheRuleConfig {
+    path: Option<String>,
+    #[serde(rename = "type")]
+    content_type: Option<String>,
+    cache_control: String,
+}
+
+impl TryFrom<CacheConfig> for CachePolicies {
+    type Error = io::Error;
+
+    fn try_from(config: CacheConfig) -> io::Result<CachePolicies> {
+        let mut policies = CachePolicies::new();
+        for rule in config.rules {
+            policies = policies.rule(
+                rule.path.as_deref(),
+                rule.content_type.as_deref(),
+                &rule.cache_control,
+            )?;
+        }
+        if let Some(listings) = config.listings {
+            policies = policies.listings(&listings);
+        }
+        Ok(policies)
+    }
+}
+
+fn invalid_pattern(err: globset::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(policies: &CachePolicies, path: &str, content_type: &str) -> Option<String> {
+        policies
+            .for_file(path, content_type)
+            .map(|cache_control| cache_control.value)
+    }
+
+    #[test]
+    fn first_matching_rule_decides() {
+        let policies = CachePolicies::new()
+            .rule(Some("/assets/*.js"), None, "immutable")
+            .unwrap()
+            .rule(Some("*.html"), None, "no-cache")
+            .unwrap()
+            .rule(None, Some("image/*"), "max-age=60")
+            .unwrap()
+            .rule(Some("docs/**"), Some("text/*"), "max-age=10")
+            .unwrap();
+        let cases = [
+            (
+                "/assets/app.js",
+                "application/javascript",
+                Some("immutable"),
+            ),
+            ("/assets/lib/app.js", "application/javascript", None), // `*` stays in a segment
+            ("/deep/down/page.html", "text/html", Some("no-cache")), // No slash: any directory
+            ("/logo.PNG", "Image/PNG; q=1", Some("max-age=60")),
+            (
+                "/docs/a/b.txt",
+                "text/plain; charset=utf-8",
+                Some("max-age=10"),
+            ),
+            ("/docs/a/b.bin", "application/octet-stream", None), // Both patterns must match
+        ];
+        for (path, content_type, expected) in cases {
+            assert_eq!(
+                value(&policies, path, content_type).as_deref(),
+                expected,
+                "{}",
+                path
+            );
+        }
+        assert_eq!(policies.for_listing(), None);
+    }
+
+    #[test]
+    fn reads_max_age() {
+        assert_eq!(
+            CacheControl::new("public, Max-Age=\"600\"").max_age,
+            Some(600)
+        );
+        assert_eq!(CacheControl::new(" no-store ").max_age, None);
+        assert_eq!(CacheControl::new(" no-store ").value, "no-store");
+        assert_eq!(CacheControl::new("s-maxage=5, max-age=x").max_age, None);
+    }
+
+    #[test]
+    fn loads_from_the_config_section() {
+        let policies: CachePolicies = toml::from_str(
+            r#"
+            listings = "no-cache"
+
+            [[rules]]
+            type = "text/css"
+            cache_control = "max-age=3600"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(policies.for_listing().map(|c| c.max_age), Some(None));
+        assert_eq!(
+            policies
+                .for_file("/a.css", "text/css")
+                .and_then(|c| c.max_age),
+            Some(3600)
+        );
+        assert!(
+            toml::from_str::<CachePolicies>("[[rules]]\npath = \"a[\"\ncache_control = \"x\"")
+                .is_err()
+        );
+        assert!(toml::from_str::<CachePolicies>("[[rules]]\ncache = \"x\"").is_err());
+    }
+}
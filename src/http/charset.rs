@@ -0,0 +1,239 @@
+use super::request::HttpRequest;
+use chardetng::EncodingDetector;
+use encoding_rs::{Decoder, Encoding, UTF_8};
+use std::io::{self, Read};
+
+// Character encodings of text files
+//
+// The encoding is detected from the start of a file: a byte order mark names it outright,
+// text that is valid UTF-8 is taken to be UTF-8, and anything else is guessed by chardetng
+// (which tells GBK, Shift_JIS, EUC-KR, windows-1252 etc. apart). Clients that can't handle
+// the detected encoding may ask for UTF-8 instead, with `?charset=utf-8` or Accept-Charset,
+// and get the file decoded while it is sent.
+
+const BLOCK_SIZE: usize = 8 * 1024; // How much encoded text is decoded at a time
+
+// Detects the encoding of a text file from its first bytes
+pub fn detect(sniffed: &[u8]) -> &'static Encoding {
+    if let Some((encoding, _)) = Encoding::for_bom(sniffed) {
+        return encoding;
+    }
+    if utf8_prefix(sniffed).is_some() {
+        return UTF_8; // Includes plain ASCII
+    }
+    let mut detector = EncodingDetector::new();
+    detector.feed(sniffed, false); // Usually only the start of the file
+    detector.guess(None, true)
+}
+
+// The valid UTF-8 text at the start of `bytes`, allowing a character cut off at the end
+// Returns None if the bytes aren't UTF-8
+pub fn utf8_prefix(bytes: &[u8]) -> Option<&str> {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => Some(text),
+        Err(err) if err.error_len().is_none() => {
+            std::str::from_utf8(&bytes[..err.valid_up_to()]).ok()
+        }
+        Err(_) => None,
+    }
+}
+
+// The encoding named by the charset parameter of a Content-Type, if any
+pub fn declared(content_type: &str) -> Option<&'static Encoding> {
+    content_type
+        .split(';')
+        .skip(1)
+        .filter_map(|param| param.split_once('='))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("charset"))
+        .and_then(|(_, value)| Encoding::for_label(value.trim().trim_matches('"').as_bytes()))
+}
+
+// The name of an encoding for a charset parameter
+pub fn label(encoding: &'static Encoding) -> String {
+    encoding.name().to_ascii_lowercase()
+}
+
+// Whether the client asked for UTF-8 instead of `encoding`: with `?charset=utf-8`, or with
+// an Accept-Charset that accepts UTF-8 but not `encoding`
+pub fn wants_utf8(request: &HttpRequest, encoding: &'static Encoding) -> bool {
+    if let Some(charset) = request.query_param("charset") {
+        return Encoding::for_label(charset.trim().as_bytes()) == Some(UTF_8);
+    }
+    match request.weighted_values("Accept-Charset") {
+        Some(accepted) => quality(&accepted, encoding) == 0.0 && quality(&accepted, UTF_8) > 0.0,
+        None => false, // Any charset is acceptable
+    }
+}
+
+// The weight an Accept-Charset list gives an encoding; `*` covers the ones not listed
+fn quality(accepted: &[(String, f32)], encoding: &'static Encoding) -> f32 {
+    let mut wildcard = 0.0;
+    for (label, q) in accepted {
+        if label == "*" {
+            wildcard = *q;
+        } else if Encoding::for_label(label.as_bytes()) == Some(encoding) {
+            return *q;
+        }
+    }
+    wildcard
+}
+
+// Decodes text in another encoding to UTF-8 while it is read
+// Malformed sequences become U+FFFD; a byte order mark is dropped
+pub struct Utf8Reader<R> {
+    inner: R,
+    decoder: Decoder,
+    input: Vec<u8>,  // Encoded bytes read from the inner reader
+    output: Vec<u8>, // Decoded text not handed out yet
+    position: usize, // How much of the output has been read
+    finished: bool,  // Whether the inner reader has ended
+}
+
+impl<R: Read> Utf8Reader<R> {
+    pub fn new(inner: R, encoding: &'static Encoding) -> Utf8Reader<R> {
+        Utf8Reader {
+            inner,
+            decoder: encoding.new_decoder_with_bom_removal(),
+            input: vec![0; BLOCK_SIZE],
+            output: Vec::new(),
+            position: 0,
+            finished: false,
+        }
+    }
+
+    // Decodes the next block of input into the output buffer
+    fn decode_next(&mut self) -> io::Result<()> {
+        let read = loop {
+            match self.inner.read(&mut self.input) {
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                result => break result?,
+            }
+        };
+        self.finished = read == 0;
+        let capacity = self
+            .decoder
+            .max_utf8_buffer_length(read)
+            .ok_or_else(|| io::Error::other("text block too large to decode"))?;
+        self.output.clear();
+        self.output.resize(capacity, 0);
+        // The output has room for everything, so the whole input is decoded at once
+        let (_, _, written, _) =
+            self.decoder
+                .decode_to_utf8(&self.input[..read], &mut self.output, self.finished);
+        self.output.truncate(written);
+        self.position = 0;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Utf8Reader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // A block may decode to nothing, e.g. when it ends inside a character
+        while self.position == self.output.len() {
+            if self.finished {
+                return Ok(0);
+            }
+            self.decode_next()?;
+        }
+        let pending = &self.output[self.position..];
+        let read = pending.len().min(buf.len());
+        buf[..read].copy_from_slice(&pending[..read]);
+        self.position += read;
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use encoding_rs::{SHIFT_JIS, UTF_16LE, WINDOWS_1252};
+
+    // Hands out one byte per read, so characters are split across blocks
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let read = self.0.len().min(buf.len()).min(1);
+            buf[..read].copy_from_slice(&self.0[..read]);
+            self.0 = &self.0[read..];
+            Ok(read)
+        }
+    }
+
+    fn request(target: &str, headers: &str) -> HttpRequest {
+        HttpRequest::new(&format!(
+            "GET {} HTTP/1.1\r\nHost: a\r\n{}\r\n",
+            target, headers
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn detects_encodings() {
+        let (japanese, _, _) =
+            SHIFT_JIS.encode("日本語のテキストファイルです。文字コードを判定します。");
+        assert_eq!(detect(b"\xff\xfeh\0i\0"), UTF_16LE);
+        assert_eq!(detect("plain ascii and ünïcödé".as_bytes()), UTF_8);
+        assert_eq!(detect(&"€".as_bytes()[..2]), UTF_8); // Cut off by the sniffing limit
+        assert_eq!(detect(&japanese), SHIFT_JIS);
+        assert_eq!(detect(b"caf\xe9 cr\xe8me br\xfbl\xe9e"), WINDOWS_1252);
+        assert_eq!(utf8_prefix(b"ab\xe2\x82"), Some("ab"));
+        assert_eq!(utf8_prefix(b"ab\xff"), None);
+    }
+
+    #[test]
+    fn reads_declared_charsets() {
+        assert_eq!(
+            declared("text/html; Charset=\"Shift_JIS\""),
+            Some(SHIFT_JIS)
+        );
+        assert_eq!(declared("text/html; level=1"), None);
+        assert_eq!(declared("text/html; charset=nonsense"), None);
+        assert_eq!(label(SHIFT_JIS), "shift_jis");
+    }
+
+    #[test]
+    fn asks_for_utf8_by_query_or_accept_charset() {
+        let cases = [
+            ("/a.txt?charset=utf-8", "", true),
+            ("/a.txt?charset=UTF8", "", true),
+            ("/a.txt?charset=latin1", "Accept-Charset: utf-8\r\n", false), // The query wins
+            ("/a.txt", "", false),
+            (
+                "/a.txt",
+                "Accept-Charset: utf-8, iso-8859-1;q=0.5\r\n",
+                true,
+            ),
+            ("/a.txt", "Accept-Charset: shift_jis, utf-8\r\n", false),
+            ("/a.txt", "Accept-Charset: *;q=0.1\r\n", false),
+            ("/a.txt", "Accept-Charset: utf-8, *;q=0\r\n", true),
+        ];
+        for (target, headers, expected) in cases {
+            let request = request(target, headers);
+            assert_eq!(
+                wants_utf8(&request, SHIFT_JIS),
+                expected,
+                "{} {:?}",
+                target,
+                headers
+            );
+        }
+    }
+
+    #[test]
+    fn decodes_to_utf8_while_reading() {
+        let text = "日本語のテキスト";
+        let (encoded, _, _) = SHIFT_JIS.encode(text);
+        let mut decoded = String::new();
+        Utf8Reader::new(Trickle(&encoded), SHIFT_JIS)
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, text);
+
+        let mut decoded = String::new();
+        Utf8Reader::new(&b"\xef\xbb\xbfok \xff"[..], UTF_8)
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, "ok \u{fffd}"); // BOM dropped, malformed byte replaced
+    }
+}
@@ -0,0 +1,494 @@
+use super::request::is_tchar;
+use std::io::{self, BufRead, Read, Write};
+use std::mem;
+
+// Chunked transfer coding (RFC 9112 section 7.1) for request and response bodies
+
+const MAX_CHUNK_LINE: usize = 4096; // Longest chunk-size line (including extensions) we accept
+const MAX_TRAILER_SIZE: usize = 8 * 1024; // Largest trailer section we accept
+
+// Decoding state of a ChunkedReader or ChunkedDecoder
+#[derive(Debug, PartialEq)]
+enum State {
+    Size,     // Expecting a chunk-size line
+    Data,     // Inside chunk data
+    DataEnd,  // Expecting the CRLF that ends chunk data
+    Trailers, // Expecting trailer fields or the empty line after the last chunk
+    Done,     // Last chunk and trailers have been read
+}
+
+// Decodes a chunked body from an underlying reader, enforcing a limit on the decoded size
+pub struct ChunkedReader<R> {
+    inner: R,
+    state: State,
+    remaining: u64,                  // Bytes left in the current chunk
+    total: u64,                      // Decoded bytes so far
+    limit: u64,                      // Maximum decoded body size
+    trailers: Vec<(String, String)>, // Trailer fields received after the last chunk
+}
+
+impl<R: BufRead> ChunkedReader<R> {
+    // Creates a decoder that fails once the body grows past `limit` bytes
+    pub fn new(inner: R, limit: u64) -> ChunkedReader<R> {
+        ChunkedReader {
+            inner,
+            state: State::Size,
+            remaining: 0,
+            total: 0,
+            limit,
+            trailers: Vec::new(),
+        }
+    }
+
+    // Trailer fields, available once the body has been read to the end
+    pub fn trailers(&self) -> &[(String, String)] {
+        &self.trailers
+    }
+
+    // Returns the underlying reader, positioned after whatever has been decoded
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    // Reads a CRLF terminated line, rejecting bare LF and lines longer than `max`
+    fn read_line(&mut self, max: usize) -> io::Result<String> {
+        let mut line = Vec::new();
+        loop {
+            let available = self.inner.fill_buf()?;
+            if available.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "truncated chunked body",
+                ));
+            }
+            let newline = available.iter().position(|&b| b == b'\n');
+            let take = newline.map_or(available.len(), |pos| pos + 1);
+            line.extend_from_slice(&available[..take]);
+            self.inner.consume(take);
+            if line.len() > max {
+                return Err(invalid("chunk line too long"));
+            }
+            if newline.is_some() {
+                break;
+            }
+        }
+        finish_line(line)
+    }
+
+    // Reads a chunk-size line
+    fn read_chunk_size(&mut self) -> io::Result<u64> {
+        let line = self.read_line(MAX_CHUNK_LINE)?;
+        parse_chunk_size(&line)
+    }
+
+    // Reads the trailer section that follows the last chunk
+    fn read_trailers(&mut self) -> io::Result<()> {
+        let mut size = 0;
+        loop {
+            let line = self.read_line(MAX_TRAILER_SIZE)?;
+            if line.is_empty() {
+                return Ok(());
+            }
+            size += line.len() + 2;
+            if size > MAX_TRAILER_SIZE {
+                return Err(invalid("trailer section too large"));
+            }
+            self.trailers.push(parse_trailer(&line)?);
+        }
+    }
+}
+
+impl<R: BufRead> Read for ChunkedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.state {
+                State::Size => {
+                    let size = self.read_chunk_size()?;
+                    if size == 0 {
+                        self.state = State::Trailers;
+                        continue;
+                    }
+                    self.total = self.total.saturating_add(size);
+                    if self.total > self.limit {
+                        return Err(io::Error::new(
+                            io::ErrorKind::FileTooLarge,
+                            "chunked body too large",
+                        ));
+                    }
+                    self.remaining = size;
+                    self.state = State::Data;
+                }
+                State::Data => {
+                    if buf.is_empty() {
+                        return Ok(0);
+                    }
+                    let max = buf.len().min(self.remaining as usize);
+                    let read = self.inner.read(&mut buf[..max])?;
+                    if read == 0 {
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "truncated chunk data",
+                        ));
+                    }
+                    self.remaining -= read as u64;
+                    if self.remaining == 0 {
+                        self.state = State::DataEnd;
+                    }
+                    return Ok(read);
+                }
+                State::DataEnd => {
+                    if !self.read_line(2)?.is_empty() {
+                        return Err(invalid("chunk data longer than its size"));
+                    }
+                    self.state = State::Size;
+                }
+                State::Trailers => {
+                    self.read_trailers()?;
+                    self.state = State::Done;
+                }
+                State::Done => return Ok(0),
+            }
+        }
+    }
+}
+
+// Decodes a chunked body from bytes handed to it as they arrive, for event loops that
+// can't block on a reader; partial lines are kept, so every byte is looked at once
+pub struct ChunkedDecoder {
+    state: State,
+    remaining: u64,
+    total: u64,
+    limit: u64,
+    line: Vec<u8>,       // The line being received (chunk size, data end or trailer)
+    trailer_size: usize, // Bytes of trailer fields so far
+    body: Vec<u8>,
+    trailers: Vec<(String, String)>,
+}
+
+impl ChunkedDecoder {
+    // Creates a decoder that fails once the body grows past `limit` bytes
+    pub fn new(limit: u64) -> ChunkedDecoder {
+        ChunkedDecoder {
+            state: State::Size,
+            remaining: 0,
+            total: 0,
+            limit,
+            line: Vec::new(),
+            trailer_size: 0,
+            body: Vec::new(),
+            trailers: Vec::new(),
+        }
+    }
+
+    // Decodes the next bytes of the body, returning how many of them it used
+    // Once the body is done, the bytes left over belong to the next request
+    pub fn feed(&mut self, input: &[u8]) -> io::Result<usize> {
+        let mut used = 0;
+        while used < input.len() && self.state != State::Done {
+            let rest = &input[used..];
+            if self.state == State::Data {
+                let take = (rest.len() as u64).min(self.remaining) as usize;
+                self.body.extend_from_slice(&rest[..take]);
+                self.remaining -= take as u64;
+                used += take;
+                if self.remaining == 0 {
+                    self.state = State::DataEnd;
+                }
+                continue;
+            }
+            let newline = rest.iter().position(|&b| b == b'\n');
+            let take = newline.map_or(rest.len(), |pos| pos + 1);
+            self.line.extend_from_slice(&rest[..take]);
+            used += take;
+            let max = match self.state {
+                State::Size => MAX_CHUNK_LINE,
+                State::DataEnd => 2,
+                _ => MAX_TRAILER_SIZE,
+            };
+            if self.line.len() > max {
+                return Err(invalid("chunk line too long"));
+            }
+            if newline.is_some() {
+                let line = finish_line(mem::take(&mut self.line))?;
+                self.end_line(&line)?;
+            }
+        }
+        Ok(used)
+    }
+
+    // Acts on a complete line
+    fn end_line(&mut self, line: &str) -> io::Result<()> {
+        match self.state {
+            State::Size => {
+                let size = parse_chunk_size(line)?;
+                if size == 0 {
+                    self.state = State::Trailers;
+                    return Ok(());
+                }
+                self.total = self.total.saturating_add(size);
+                if self.total > self.limit {
+                    return Err(io::Error::new(
+                        io::ErrorKind::FileTooLarge,
+                        "chunked body too large",
+                    ));
+                }
+                self.remaining = size;
+                self.state = State::Data;
+            }
+            State::DataEnd if !line.is_empty() => {
+                return Err(invalid("chunk data longer than its size"))
+            }
+            State::DataEnd => self.state = State::Size,
+            _ if line.is_empty() => self.state = State::Done,
+            _ => {
+                self.trailer_size += line.len() + 2;
+                if self.trailer_size > MAX_TRAILER_SIZE {
+                    return Err(invalid("trailer section too large"));
+                }
+                self.trailers.push(parse_trailer(line)?);
+            }
+        }
+        Ok(())
+    }
+
+    // Whether the last chunk and the trailers have been received
+    pub fn is_done(&self) -> bool {
+        self.state == State::Done
+    }
+
+    // Takes the body decoded so far, for passing it on while the rest arrives
+    pub fn take_body(&mut self) -> Vec<u8> {
+        mem::take(&mut self.body)
+    }
+
+    // The decoded body and its trailer fields
+    pub fn into_parts(self) -> (Vec<u8>, Vec<(String, String)>) {
+        (self.body, self.trailers)
+    }
+}
+
+// Checks that a received line ends with CRLF and returns it without
+fn finish_line(mut line: Vec<u8>) -> io::Result<String> {
+    if !line.ends_with(b"\r\n") {
+        return Err(invalid("chunk line not terminated by CRLF"));
+    }
+    line.truncate(line.len() - 2);
+    String::from_utf8(line).map_err(|_| invalid("chunk line is not valid text"))
+}
+
+// Parses a chunk-size line, ignoring any chunk extensions
+fn parse_chunk_size(line: &str) -> io::Result<u64> {
+    let size = line
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim_end_matches([' ', '\t']);
+    if size.is_empty() || size.len() > 16 || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(invalid("invalid chunk size"));
+    }
+    u64::from_str_radix(size, 16).map_err(|_| invalid("invalid chunk size"))
+}
+
+// Parses a trailer field line into its name and value
+fn parse_trailer(line: &str) -> io::Result<(String, String)> {
+    let (name, value) = line
+        .split_once(':')
+        .ok_or_else(|| invalid("malformed trailer field"))?;
+    if name.is_empty() || !name.bytes().all(is_tchar) {
+        return Err(invalid("malformed trailer field")); // Also rejects whitespace and obs-fold
+    }
+    Ok((name.to_string(), value.trim().to_string()))
+}
+
+// Encodes everything written to it as chunks; call `finish` to send the last chunk
+// Each `write` becomes one chunk handed to the writer in a single call; nothing is held back,
+// so a streamed body (e.g. server-sent events) reaches the client as soon as it is produced
+pub struct ChunkedWriter<W: Write> {
+    inner: W,
+    chunk: Vec<u8>, // Size line, data and CRLF of the chunk being written
+}
+
+impl<W: Write> ChunkedWriter<W> {
+    pub fn new(inner: W) -> ChunkedWriter<W> {
+        ChunkedWriter {
+            inner,
+            chunk: Vec::new(),
+        }
+    }
+
+    // Writes the last (empty) chunk and returns the underlying writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.inner.write_all(b"0\r\n\r\n")?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0); // An empty chunk would terminate the body
+        }
+        self.chunk.clear();
+        write!(self.chunk, "{:X}\r\n", buf.len())?;
+        self.chunk.extend_from_slice(buf);
+        self.chunk.extend_from_slice(b"\r\n");
+        self.inner.write_all(&self.chunk)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Shorthand for a malformed body error
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Decoded = (Vec<u8>, Vec<(String, String)>); // Body and trailers
+
+    // Decodes a whole body with ChunkedReader
+    fn read_all(input: &[u8], limit: u64) -> io::Result<Decoded> {
+        let mut reader = ChunkedReader::new(input, limit);
+        let mut body = Vec::new();
+        reader.read_to_end(&mut body)?;
+        Ok((body, reader.trailers().to_vec()))
+    }
+
+    // Decodes a whole body with ChunkedDecoder, one byte at a time
+    fn feed_bytewise(input: &[u8], limit: u64) -> io::Result<Decoded> {
+        let mut decoder = ChunkedDecoder::new(limit);
+        for byte in input.chunks(1) {
+            if decoder.is_done() {
+                break;
+            }
+            assert_eq!(decoder.feed(byte)?, 1);
+        }
+        assert!(decoder.is_done(), "body ended early");
+        Ok(decoder.into_parts())
+    }
+
+    #[test]
+    fn decodes_chunks_and_trailers() {
+        let input = b"4\r\nWiki\r\n5;ext=1\r\npedia\r\n0\r\nExpires: never\r\n\r\n";
+        for decode in [read_all, feed_bytewise] {
+            let (body, trailers) = decode(input, 1024).unwrap();
+            assert_eq!(body, b"Wikipedia");
+            assert_eq!(trailers, vec![("Expires".to_string(), "never".to_string())]);
+        }
+    }
+
+    #[test]
+    fn decoder_stops_at_end_of_body() {
+        let mut decoder = ChunkedDecoder::new(1024);
+        let used = decoder
+            .feed(b"3\r\nabc\r\n0\r\n\r\nGET / HTTP/1.1\r\n")
+            .unwrap();
+        assert_eq!(used, 13);
+        assert!(decoder.is_done());
+        assert_eq!(decoder.into_parts().0, b"abc");
+    }
+
+    #[test]
+    fn rejects_chunk_size_overflow() {
+        // 17 hex digits don't fit in a u64, and 16 of them are over any limit
+        for input in [&b"10000000000000000\r\n"[..], b"ffffffffffffffff\r\n"] {
+            assert!(read_all(input, u64::MAX - 1).is_err());
+            assert!(ChunkedDecoder::new(u64::MAX - 1).feed(input).is_err());
+        }
+        let err = ChunkedDecoder::new(1024)
+            .feed(b"ffffffffffffffff\r\n")
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::FileTooLarge);
+    }
+
+    #[test]
+    fn rejects_bodies_over_the_limit() {
+        let input = b"8\r\n12345678\r\n8\r\n12345678\r\n0\r\n\r\n";
+        for decode in [read_all, feed_bytewise] {
+            assert_eq!(
+                decode(input, 10).unwrap_err().kind(),
+                io::ErrorKind::FileTooLarge
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_chunks() {
+        let cases: [&[u8]; 5] = [
+            b"\r\n",                    // Empty size
+            b"-1\r\n",                  // Not hex
+            b"0x3\r\nabc\r\n0\r\n\r\n", // Prefixed
+            b"3\nabc\r\n0\r\n\r\n",     // Bare LF
+            b"3\r\nabcd\r\n0\r\n\r\n",  // Data longer than its size
+        ];
+        for input in cases {
+            assert!(read_all(input, 1024).is_err(), "{:?}", input);
+            let mut decoder = ChunkedDecoder::new(1024);
+            assert!(
+                input.chunks(1).any(|byte| decoder.feed(byte).is_err()),
+                "{:?}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn writer_output_decodes() {
+        let mut writer = ChunkedWriter::new(Vec::new());
+        writer.write_all(b"hello ").unwrap();
+        writer.write_all(b"world").unwrap();
+        let encoded = writer.finish().unwrap();
+        assert_eq!(read_all(&encoded, 1024).unwrap().0, b"hello world");
+    }
+
+    #[test]
+    fn writer_sends_each_chunk_in_one_write() {
+        // Records the buffers it is given
+        struct Writes(Vec<Vec<u8>>);
+        impl Write for Writes {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.push(buf.to_vec());
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut writer = ChunkedWriter::new(Writes(Vec::new()));
+        writer.write_all(b"hello ").unwrap();
+        writer.write_all(b"world").unwrap();
+        let writes = writer.finish().unwrap().0;
+        assert_eq!(
+            writes,
+            [&b"6\r\nhello \r\n"[..], b"5\r\nworld\r\n", b"0\r\n\r\n"]
+        );
+    }
+
+    #[test]
+    fn rejects_trailer_names_that_are_not_tokens() {
+        for trailer in [
+            "Bad Name: 1",
+            "Bad\tName: 1",
+            "(x): 1",
+            " Folded: 1",
+            "Name : 1",
+            ": 1",
+        ] {
+            let input = format!("1\r\na\r\n0\r\n{}\r\n\r\n", trailer);
+            assert!(read_all(input.as_bytes(), 1024).is_err(), "{}", trailer);
+            assert!(
+                feed_bytewise(input.as_bytes(), 1024).is_err(),
+                "{}",
+                trailer
+            );
+        }
+    }
+}
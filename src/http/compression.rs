@@ -0,0 +1,469 @@
+use super::handler::Handler;
+use super::middleware::Middleware;
+use super::mime;
+use super::request::HttpRequest;
+use super::response::{AcceptRanges, HttpResponse, ResponseBody, ResponseStatus};
+use serde::Deserialize;
+use std::fs::{self, Metadata};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+// Compression of responses while they are sent (Content-Encoding, RFC 9110 section 8.4)
+//
+// The coding is negotiated from Accept-Encoding: the one the client weighs highest wins,
+// ties go to the first in `encodings`, and clients that send no Accept-Encoding get the
+// body as it is. Only types that shrink (text, JSON, SVG, WebAssembly, ...) are compressed,
+// and only files of at least `min_size` bytes; listings always are, as their size is unknown.
+//
+// As a middleware, `Compression` compresses the responses of any handler (routes, custom
+// endpoints) the same way; responses that already have a Content-Encoding are left alone.
+//
+// Files compressed ahead of time are served instead when they sit next to the requested
+// file (`app.js.br`, `app.js.zst`, `app.js.gz`) and are at least as new as it; the best one
+// the client accepts wins over compressing on the fly.
+//
+//     [compression]
+//     min_size = 1024
+//     encodings = ["br", "zstd", "gzip"]
+//     precompressed = true
+
+// Compression levels, chosen for speed since every response is compressed anew
+const GZIP_LEVEL: u32 = 6;
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW: u32 = 22; // log2 of the window size
+const ZSTD_LEVEL: i32 = 3;
+const BUFFER_SIZE: usize = 8 * 1024;
+
+// A content coding the server can produce
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum ContentCoding {
+    #[serde(rename = "br")]
+    Brotli,
+    #[serde(rename = "zstd")]
+    Zstd,
+    #[serde(rename = "gzip")]
+    Gzip,
+}
+
+impl ContentCoding {
+    // The token used in Accept-Encoding and Content-Encoding
+    pub fn token(self) -> &'static str {
+        match self {
+            ContentCoding::Brotli => "br",
+            ContentCoding::Zstd => "zstd",
+            ContentCoding::Gzip => "gzip",
+        }
+    }
+
+    // The extension of files compressed ahead of time with this coding
+    pub fn extension(self) -> &'static str {
+        match self {
+            ContentCoding::Brotli => "br",
+            ContentCoding::Zstd => "zst",
+            ContentCoding::Gzip => "gz",
+        }
+    }
+
+    // Wraps a reader so that it yields the compressed bytes
+    pub fn encode(self, reader: Box<dyn Read + Send>) -> io::Result<Box<dyn Read + Send>> {
+        Ok(match self {
+            ContentCoding::Brotli => Box::new(brotli::CompressorReader::new(
+                reader,
+                BUFFER_SIZE,
+                BROTLI_QUALITY,
+                BROTLI_WINDOW,
+            )),
+            ContentCoding::Zstd => Box::new(zstd::stream::read::Encoder::new(reader, ZSTD_LEVEL)?),
+            ContentCoding::Gzip => Box::new(flate2::read::GzEncoder::new(
+                reader,
+                flate2::Compression::new(GZIP_LEVEL),
+            )),
+        })
+    }
+}
+
+// When and how responses are compressed
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Compression {
+    pub enabled: bool,
+    pub min_size: u64,                 // Smaller files aren't worth compressing
+    pub encodings: Vec<ContentCoding>, // Offered codings, preferred first
+    pub precompressed: bool,           // Whether to look for files compressed ahead of time
+}
+
+impl Default for Compression {
+    fn default() -> Compression {
+        Compression {
+            enabled: true,
+            min_size: 1024,
+            encodings: vec![
+                ContentCoding::Brotli,
+                ContentCoding::Zstd,
+                ContentCoding::Gzip,
+            ],
+            precompressed: true,
+        }
+    }
+}
+
+impl Compression {
+    // Compression turned off
+    pub fn disabled() -> Compression {
+        Compression {
+            enabled: false,
+            ..Compression::default()
+        }
+    }
+
+    // Whether a body of this type and size (None if unknown) may be compressed, so that
+    // the response varies with Accept-Encoding
+    pub fn applies(&self, content_type: &str, length: Option<u64>) -> bool {
+        self.enabled
+            && !self.encodings.is_empty()
+            && compressible(content_type)
+            && length.is_none_or(|length| length >= self.min_size)
+    }
+
+    // The coding to send a response in, or None to send it as it is
+    pub fn negotiate(&self, request: &HttpRequest) -> Option<ContentCoding> {
+        best_coding(request, &self.encodings)
+    }
+
+    // The files next to `path` compressed ahead of time with one of the offered codings
+    // Files older than the original are left out, as they may hold an old version, and so are
+    // those that resolve outside the canonical `root` (through a symlink)
+    pub fn sidecars(&self, path: &Path, original: &Metadata, root: &Path) -> Vec<Sidecar> {
+        if !self.enabled || !self.precompressed {
+            return Vec::new();
+        }
+        let modified = original.modified().ok();
+        self.encodings
+            .iter()
+            .filter_map(|&coding| {
+                let mut name = path.file_name()?.to_os_string();
+                name.push(".");
+                name.push(coding.extension());
+                let path = path
+                    .with_file_name(name)
+                    .canonicalize()
+                    .ok()
+                    .filter(|path| path.starts_with(root))?;
+                let metadata = fs::metadata(&path)
+                    .ok()
+                    .filter(|metadata| metadata.is_file())?;
+                let fresh = modified
+                    .zip(metadata.modified().ok())
+                    .is_some_and(|(original, sidecar)| sidecar >= original);
+                fresh.then_some(Sidecar { coding, path })
+            })
+            .collect()
+    }
+}
+
+impl Compression {
+    // Compresses a finished response while it is sent, if it applies and the client accepts
+    // one of the codings; the body then has no length and no ranges, and its ETag a suffix
+    pub fn compress(&self, request: &HttpRequest, response: &mut HttpResponse) -> io::Result<()> {
+        let encoded = response
+            .headers
+            .iter()
+            .any(|(name, _)| name.eq_ignore_ascii_case("Content-Encoding"));
+        // Partial content would have to be compressed before it was cut
+        if encoded || response.status != ResponseStatus::OK || !response.allows_body() {
+            return Ok(());
+        }
+        let length = (!response.is_chunked()).then_some(response.content_length as u64);
+        if !self.applies(&response.content_type, length) {
+            return Ok(());
+        }
+        match response
+            .headers
+            .iter_mut()
+            .find(|(name, _)| name.eq_ignore_ascii_case("Vary"))
+        {
+            Some((_, vary)) if vary.to_ascii_lowercase().contains("accept-encoding") => {}
+            Some((_, vary)) => vary.push_str(", Accept-Encoding"),
+            None => response.add_header("Vary", "Accept-Encoding"),
+        }
+        let coding = match self.negotiate(request) {
+            Some(coding) => coding,
+            None => return Ok(()),
+        };
+        let body = std::mem::replace(&mut response.response_body, ResponseBody::Bytes(Vec::new()));
+        let reader = match body {
+            // Never more than the advertised length, as when sent as it is
+            ResponseBody::Reader(reader) => Box::new(reader.take(response.content_length as u64)),
+            body => body.into_reader(),
+        };
+        response.response_body = ResponseBody::Stream(coding.encode(reader)?);
+        response.content_length = 0;
+        response.accept_ranges = AcceptRanges::None;
+        for (name, value) in response.headers.iter_mut() {
+            // Not the same bytes, so not the same strong ETag (as for files, see Validators)
+            if name.eq_ignore_ascii_case("ETag") {
+                if let Some(unquoted) = value.strip_suffix('"') {
+                    *value = format!("{}-{}\"", unquoted, coding.token());
+                }
+            }
+        }
+        response.add_header("Content-Encoding", coding.token());
+        Ok(())
+    }
+}
+
+impl Middleware for Compression {
+    fn handle(&self, request: &mut HttpRequest, next: &dyn Handler) -> io::Result<HttpResponse> {
+        let mut response = next.handle(request)?;
+        self.compress(request, &mut response)?;
+        Ok(response)
+    }
+}
+
+// A file compressed ahead of time
+#[derive(Debug, Clone)]
+pub struct Sidecar {
+    pub coding: ContentCoding,
+    pub path: PathBuf,
+}
+
+// The offered coding the client weighs highest, ties going to the first offered
+pub fn best_coding(request: &HttpRequest, offered: &[ContentCoding]) -> Option<ContentCoding> {
+    let accepted = request.weighted_values("Accept-Encoding")?;
+    let mut best: Option<(ContentCoding, f32)> = None;
+    for &coding in offered {
+        let q = quality(&accepted, coding);
+        if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+            best = Some((coding, q));
+        }
+    }
+    best.map(|(coding, _)| coding)
+}
+
+// The weight an Accept-Encoding list gives a coding; `*` covers the ones not listed
+fn quality(accepted: &[(String, f32)], coding: ContentCoding) -> f32 {
+    let mut wildcard = 0.0;
+    for (token, q) in accepted {
+        if token == coding.token() || (coding == ContentCoding::Gzip && token == "x-gzip") {
+            return *q;
+        } else if token == "*" {
+            wildcard = *q;
+        }
+    }
+    wildcard
+}
+
+// Whether a type is worth compressing; images, audio, video and archives already are
+pub fn compressible(content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or("").trim();
+    mime::is_text(essence)
+        || matches!(
+            essence,
+            "application/wasm"
+                | "application/x-ndjson"
+                | "application/toml"
+                | "application/yaml"
+                | "image/bmp"
+                | "image/x-icon"
+                | "image/vnd.microsoft.icon"
+                | "font/ttf"
+                | "font/otf"
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use std::fs::File;
+    use std::time::{Duration, SystemTime};
+
+    fn request(accept_encoding: &str) -> HttpRequest {
+        HttpRequest::new(&format!(
+            "GET / HTTP/1.1\r\nHost: a\r\nAccept-Encoding: {}\r\n\r\n",
+            accept_encoding
+        ))
+        .unwrap()
+    }
+
+    // A 200 response of `length` bytes of text with an ETag
+    fn text(length: usize) -> HttpResponse {
+        HttpResponse::builder()
+            .text("compress me ".repeat(length / 12 + 1)[..length].to_string())
+            .header("ETag", "\"abc\"")
+            .build()
+    }
+
+    fn decode(token: &str, compressed: &[u8]) -> Vec<u8> {
+        let mut decoder: Box<dyn Read + '_> = match token {
+            "br" => Box::new(brotli::Decompressor::new(compressed, 4096)),
+            "zstd" => Box::new(zstd::stream::read::Decoder::new(compressed).unwrap()),
+            _ => Box::new(flate2::read::GzDecoder::new(compressed)),
+        };
+        let mut decoded = Vec::new();
+        decoder.read_to_end(&mut decoded).unwrap();
+        decoded
+    }
+
+    fn header<'a>(response: &'a HttpResponse, name: &str) -> Option<&'a str> {
+        response
+            .headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    // The codings of the sidecars found for `name` in `root`
+    fn sidecar_codings(root: &Path, name: &str) -> Vec<ContentCoding> {
+        let path = root.join(name);
+        let metadata = fs::metadata(&path).unwrap();
+        Compression::default()
+            .sidecars(&path, &metadata, root)
+            .into_iter()
+            .map(|sidecar| sidecar.coding)
+            .collect()
+    }
+
+    #[test]
+    fn finds_sidecars_at_least_as_new_as_the_file() {
+        let dir = TempDir::new("sidecars");
+        let root = dir.mkdir("root").canonicalize().unwrap();
+        for name in ["app.js", "app.js.br", "app.js.gz"] {
+            fs::write(root.join(name), name).unwrap();
+        }
+        let stale = SystemTime::now() - Duration::from_secs(3600);
+        let gz = File::options()
+            .write(true)
+            .open(root.join("app.js.gz"))
+            .unwrap();
+        gz.set_modified(stale).unwrap();
+        assert_eq!(sidecar_codings(&root, "app.js"), [ContentCoding::Brotli]);
+
+        let off = Compression {
+            precompressed: false,
+            ..Compression::default()
+        };
+        let path = root.join("app.js");
+        assert!(off
+            .sidecars(&path, &fs::metadata(&path).unwrap(), &root)
+            .is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn skips_sidecars_outside_the_root() {
+        use std::os::unix::fs::symlink;
+
+        let dir = TempDir::new("sidecar-links");
+        let root = dir.mkdir("root").canonicalize().unwrap();
+        let outside = dir.mkdir("outside");
+        fs::write(root.join("app.js"), "app").unwrap();
+        fs::write(root.join("stored.br"), "compressed").unwrap();
+        fs::write(outside.join("secret"), "secret").unwrap();
+        symlink(outside.join("secret"), root.join("app.js.br")).unwrap();
+        symlink(root.join("stored.br"), root.join("app.js.gz")).unwrap();
+        assert_eq!(sidecar_codings(&root, "app.js"), [ContentCoding::Gzip]);
+    }
+
+    #[test]
+    fn negotiates_by_weight_then_by_preference() {
+        let offered = Compression::default().encodings;
+        let cases = [
+            ("gzip, br", Some(ContentCoding::Brotli)), // Equal weights: the server's order
+            ("gzip;q=1, br;q=0.5", Some(ContentCoding::Gzip)),
+            ("x-gzip", Some(ContentCoding::Gzip)),
+            ("*;q=0.2, br;q=0", Some(ContentCoding::Zstd)),
+            ("ZSTD;Q=0.9, deflate", Some(ContentCoding::Zstd)),
+            ("identity", None),
+            ("br;q=0, gzip;q=0", None),
+        ];
+        for (accept_encoding, expected) in cases {
+            let negotiated = best_coding(&request(accept_encoding), &offered);
+            assert_eq!(negotiated, expected, "{}", accept_encoding);
+        }
+        let none = HttpRequest::new("GET / HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+        assert_eq!(best_coding(&none, &offered), None);
+        assert_eq!(
+            best_coding(&request("gzip"), &[ContentCoding::Brotli]),
+            None
+        );
+    }
+
+    #[test]
+    fn compresses_only_types_that_shrink() {
+        assert!(compressible("text/css; charset=utf-8"));
+        assert!(compressible("application/json"));
+        assert!(compressible("image/svg+xml"));
+        assert!(compressible("application/wasm"));
+        assert!(!compressible("image/png"));
+        assert!(!compressible("application/zip"));
+
+        let compression = Compression::default();
+        assert!(compression.applies("text/html", None)); // Listings, of unknown size
+        assert!(compression.applies("text/html", Some(1024)));
+        assert!(!compression.applies("text/html", Some(1023)));
+        assert!(!Compression::disabled().applies("text/html", None));
+    }
+
+    #[test]
+    fn compressed_bodies_decode_to_the_original() {
+        let original = text(4096);
+        let expected = match &original.response_body {
+            ResponseBody::Bytes(bytes) => bytes.clone(),
+            body => panic!("unexpected body {:?}", body),
+        };
+        for token in ["br", "zstd", "gzip"] {
+            let mut response = text(4096);
+            Compression::default()
+                .compress(&request(token), &mut response)
+                .unwrap();
+            assert_eq!(header(&response, "Content-Encoding"), Some(token));
+            assert_eq!(header(&response, "Vary"), Some("Accept-Encoding"));
+            let etag = format!("\"abc-{}\"", token);
+            assert_eq!(header(&response, "ETag"), Some(etag.as_str()));
+            assert!(response.is_chunked());
+            assert!(matches!(response.accept_ranges, AcceptRanges::None));
+
+            let mut compressed = Vec::new();
+            let body =
+                std::mem::replace(&mut response.response_body, ResponseBody::Bytes(Vec::new()));
+            body.into_reader().read_to_end(&mut compressed).unwrap();
+            assert!(compressed.len() < expected.len(), "{}", token);
+            assert_eq!(decode(token, &compressed), expected, "{}", token);
+        }
+    }
+
+    #[test]
+    fn leaves_responses_it_cannot_compress() {
+        let compression = Compression::default();
+
+        // The client accepts no coding, but a cache must still tell the variants apart
+        let mut plain = text(2048);
+        plain.add_header("Vary", "Origin");
+        compression
+            .compress(&request("identity"), &mut plain)
+            .unwrap();
+        assert_eq!(header(&plain, "Vary"), Some("Origin, Accept-Encoding"));
+        assert_eq!(header(&plain, "Content-Encoding"), None);
+        assert_eq!(plain.content_length, 2048);
+
+        let mut small = text(100);
+        compression.compress(&request("gzip"), &mut small).unwrap();
+        assert_eq!(header(&small, "Vary"), None);
+
+        let mut encoded = text(2048);
+        encoded.add_header("Content-Encoding", "br");
+        compression
+            .compress(&request("gzip"), &mut encoded)
+            .unwrap();
+        assert_eq!(header(&encoded, "Content-Encoding"), Some("br"));
+        assert_eq!(encoded.content_length, 2048);
+
+        let mut created = text(2048);
+        created.status = ResponseStatus::Created;
+        compression
+            .compress(&request("gzip"), &mut created)
+            .unwrap();
+        assert_eq!(header(&created, "Content-Encoding"), None);
+    }
+}
@@ -0,0 +1,255 @@
+use super::request::{HttpRequest, Method};
+use serde::Deserialize;
+use std::fs::{File, Metadata};
+use std::io::{self, Read};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use xxhash_rust::xxh3::Xxh3;
+
+// Validators (ETag and Last-Modified) and conditional requests (RFC 9110 section 13)
+
+// How ETags of files are made
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ETagKind {
+    #[default]
+    Strong, // From the inode, modification time (in nanoseconds) and size
+    Weak,    // The same, marked weak (W/), for files rewritten with equivalent content
+    Content, // A hash of the content; stays the same when a file is touched or copied
+}
+
+// The validators of a file
+#[derive(Debug, Clone)]
+pub struct Validators {
+    pub etag: String,
+    pub last_modified: Option<SystemTime>,
+}
+
+impl Validators {
+    // Makes the validators of an open file; with ETagKind::Content the whole file is read
+    // and the caller has to rewind it
+    pub fn for_file(
+        file: &mut File,
+        metadata: &Metadata,
+        kind: ETagKind,
+    ) -> io::Result<Validators> {
+        let last_modified = metadata.modified().ok();
+        let etag = match kind {
+            ETagKind::Content => format!("\"{:016x}\"", content_hash(file)?),
+            ETagKind::Strong => format!("\"{}\"", metadata_tag(metadata, last_modified)),
+            ETagKind::Weak => format!("W/\"{}\"", metadata_tag(metadata, last_modified)),
+        };
+        Ok(Validators {
+            etag,
+            last_modified,
+        })
+    }
+
+    // Marks the ETag as belonging to a variant of the file (e.g. another encoding), so it
+    // differs from the ETag of the file as stored
+    pub fn variant(&mut self, name: &str) {
+        if let Some(unquoted) = self.etag.strip_suffix('"') {
+            self.etag = format!("{}-{}\"", unquoted, name);
+        }
+    }
+
+    // The headers that describe the validators
+    pub fn headers(&self) -> Vec<(String, String)> {
+        let mut headers = vec![("ETag".to_string(), self.etag.clone())];
+        if let Some(modified) = self.last_modified {
+            headers.push((
+                "Last-Modified".to_string(),
+                httpdate::fmt_http_date(modified),
+            ));
+        }
+        headers
+    }
+
+    // Evaluates the request's preconditions in the order of RFC 9110 section 13.2.2
+    pub fn evaluate(&self, request: &HttpRequest) -> Precondition {
+        let safe = matches!(request.method(), Method::Get | Method::Head);
+        // 1. and 2.: If-Match, or If-Unmodified-Since without it
+        // Repeated list fields count as one list, so every tag sent is compared
+        if let Some(if_match) = request.combined_header("If-Match") {
+            if !matches_any(&if_match, &self.etag, strong_match) {
+                return Precondition::Failed;
+            }
+        } else if let Some(since) = request.header("If-Unmodified-Since").and_then(parse_date) {
+            if self.modified_after(since) {
+                return Precondition::Failed;
+            }
+        }
+        // 3. and 4.: If-None-Match, or If-Modified-Since without it (only for GET and HEAD)
+        if let Some(if_none_match) = request.combined_header("If-None-Match") {
+            if matches_any(&if_none_match, &self.etag, weak_match) {
+                return if safe {
+                    Precondition::NotModified
+                } else {
+                    Precondition::Failed
+                };
+            }
+        } else if safe {
+            if let Some(since) = request.header("If-Modified-Since").and_then(parse_date) {
+                if !self.modified_after(since) {
+                    return Precondition::NotModified;
+                }
+            }
+        }
+        Precondition::Passed
+    }
+
+    // Whether the file changed after `date`, at the one second precision of HTTP dates
+    // A file without a modification time counts as changed
+    fn modified_after(&self, date: SystemTime) -> bool {
+        self.last_modified
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .zip(date.duration_since(UNIX_EPOCH).ok())
+            .is_none_or(|(modified, date)| modified.as_secs() > date.as_secs())
+    }
+}
+
+// What a conditional request comes to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Precondition {
+    Passed,      // Serve the request normally
+    NotModified, // 304: the client's copy is current
+    Failed,      // 412: a precondition didn't hold
+}
+
+// Opaque part of a metadata based ETag: inode (on Unix), modification time and size
+fn metadata_tag(metadata: &Metadata, modified: Option<SystemTime>) -> String {
+    let nanos = modified
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .unwrap_or(Duration::ZERO)
+        .as_nanos();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        format!("{:x}-{:x}-{:x}", metadata.ino(), nanos, metadata.len())
+    }
+    #[cfg(not(unix))]
+    {
+        format!("{:x}-{:x}", nanos, metadata.len())
+    }
+}
+
+// Hashes everything left in a file
+fn content_hash(file: &mut File) -> io::Result<u64> {
+    let mut hasher = Xxh3::new();
+    let mut block = vec![0; 64 * 1024];
+    loop {
+        match file.read(&mut block) {
+            Ok(0) => return Ok(hasher.digest()),
+            Ok(read) => hasher.update(&block[..read]),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+// Whether a list of entity tags (or `*`) matches an ETag with the given comparison
+fn matches_any(list: &str, etag: &str, matches: fn(&str, &str) -> bool) -> bool {
+    list.trim() == "*" || list.split(',').any(|tag| matches(tag.trim(), etag))
+}
+
+// Strong comparison: both tags are strong and identical (RFC 9110 section 8.8.3.2)
+fn strong_match(a: &str, b: &str) -> bool {
+    !a.starts_with("W/") && !b.starts_with("W/") && a == b
+}
+
+// Weak comparison: the tags are identical once any W/ prefix is dropped
+fn weak_match(a: &str, b: &str) -> bool {
+    a.trim_start_matches("W/") == b.trim_start_matches("W/")
+}
+
+// Parses an HTTP date, ignoring invalid ones as RFC 9110 asks
+fn parse_date(value: &str) -> Option<SystemTime> {
+    httpdate::parse_http_date(value.trim()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A file last modified at 1000 seconds past the epoch, with an ETag of "abc"
+    fn validators() -> Validators {
+        Validators {
+            etag: "\"abc\"".to_string(),
+            last_modified: Some(UNIX_EPOCH + Duration::from_secs(1000)),
+        }
+    }
+
+    fn evaluate(method: &str, headers: &[&str]) -> Precondition {
+        let headers: String = headers
+            .iter()
+            .map(|header| format!("{}\r\n", header))
+            .collect();
+        let request = HttpRequest::new(&format!(
+            "{} / HTTP/1.1\r\nHost: a\r\n{}\r\n",
+            method, headers
+        ))
+        .unwrap();
+        validators().evaluate(&request)
+    }
+
+    #[test]
+    fn compares_every_listed_tag() {
+        use Precondition::*;
+        let cases: [(&str, &[&str], Precondition); 9] = [
+            ("GET", &[], Passed),
+            ("GET", &["If-None-Match: \"x\", W/\"abc\""], NotModified), // Weak comparison
+            // Repeated lines form one list
+            (
+                "GET",
+                &["If-None-Match: \"x\"", "If-None-Match: \"abc\""],
+                NotModified,
+            ),
+            ("HEAD", &["If-None-Match: *"], NotModified),
+            ("PUT", &["If-None-Match: \"abc\""], Failed),
+            ("PUT", &["If-Match: \"x\"", "If-Match: \"abc\""], Passed),
+            ("PUT", &["If-Match: W/\"abc\""], Failed), // Strong comparison
+            ("PUT", &["If-Match: \"x\"", "If-Match: \"y\""], Failed),
+            ("GET", &["If-Match: *", "If-None-Match: \"x\""], Passed),
+        ];
+        for (method, headers, expected) in cases {
+            assert_eq!(
+                evaluate(method, headers),
+                expected,
+                "{} {:?}",
+                method,
+                headers
+            );
+        }
+    }
+
+    #[test]
+    fn compares_dates_to_the_second() {
+        use Precondition::*;
+        let at = httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(1000));
+        let before = httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(999));
+        let since = |date: &str| format!("If-Modified-Since: {}", date);
+        let unmodified = |date: &str| format!("If-Unmodified-Since: {}", date);
+        assert_eq!(evaluate("GET", &[&since(&at)]), NotModified);
+        assert_eq!(evaluate("GET", &[&since(&before)]), Passed);
+        assert_eq!(evaluate("PUT", &[&since(&at)]), Passed); // Only GET and HEAD
+        assert_eq!(
+            evaluate("GET", &[&since(&at), "If-None-Match: \"x\""]),
+            Passed
+        ); // The ETag takes precedence
+        assert_eq!(evaluate("GET", &[&since("yesterday")]), Passed); // Invalid dates are ignored
+        assert_eq!(evaluate("PUT", &[&unmodified(&before)]), Failed);
+        assert_eq!(evaluate("PUT", &[&unmodified(&at)]), Passed);
+        assert_eq!(
+            evaluate("PUT", &[&unmodified(&before), "If-Match: \"abc\""]),
+            Passed
+        );
+    }
+
+    #[test]
+    fn marks_variants_and_weak_tags() {
+        let mut validators = validators();
+        validators.variant("br");
+        assert_eq!(validators.etag, "\"abc-br\"");
+        assert!(weak_match("W/\"abc\"", "\"abc\""));
+        assert!(!strong_match("W/\"abc\"", "W/\"abc\""));
+    }
+}
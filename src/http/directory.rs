@@ -0,0 +1,188 @@
+use globset::{GlobBuilder, GlobMatcher};
+use serde::Deserialize;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+// How directories are served: by an index file, by a generated listing, or not at all
+//
+// A directory holding one of the `index` files (the first found wins) is served by it, with
+// requests missing the trailing slash redirected so relative links in the page work. Other
+// directories get a listing unless listings are turned off, in which case they answer
+// 403 Forbidden. Rules turn listings on or off for single directories: a rule's path glob
+// is matched against the directory and the directories above it, relative to the root, so
+// a rule also covers everything below its directory; the first rule that matches decides.
+//
+//     [directories]
+//     index = ["index.html", "index.htm"]
+//     listings = false
+//
+//     [[directories.rules]]
+//     path = "downloads"
+//     listings = true
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "DirectoriesConfig")]
+pub struct Directories {
+    index: Vec<String>,
+    listings: bool,
+    rules: Vec<ListingRule>,
+}
+
+// Listings on or off for the directories matching a pattern
+#[derive(Debug, Clone)]
+struct ListingRule {
+    path: GlobMatcher,
+    listings: bool,
+}
+
+impl Default for Directories {
+    fn default() -> Directories {
+        Directories {
+            index: vec!["index.html".to_string(), "index.htm".to_string()],
+            listings: true,
+            rules: Vec::new(),
+        }
+    }
+}
+
+impl Directories {
+    pub fn new() -> Directories {
+        Directories::default()
+    }
+
+    // Sets the file names served for a directory, preferred first; none always lists
+    pub fn index(mut self, names: &[&str]) -> io::Result<Directories> {
+        for name in names {
+            let mut components = Path::new(name).components();
+            if !matches!(
+                (components.next(), components.next()),
+                (Some(Component::Normal(_)), None)
+            ) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("index file {:?} is not a file name", name),
+                ));
+            }
+        }
+        self.index = names.iter().map(|name| name.to_string()).collect();
+        Ok(self)
+    }
+
+    // Turns listings of directories without an index file on or off
+    pub fn listings(mut self, enabled: bool) -> Directories {
+        self.listings = enabled;
+        self
+    }
+
+    // Turns listings on or off for the directories matching a path pattern and those below
+    pub fn rule(mut self, path: &str, listings: bool) -> io::Result<Directories> {
+        let pattern = path.trim_matches('/');
+        let glob = GlobBuilder::new(pattern)
+            .literal_separator(true)
+            .build()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        self.rules.push(ListingRule {
+            path: glob.compile_matcher(),
+            listings,
+        });
+        Ok(self)
+    }
+
+    // The index file to serve for a directory, if it has one
+    pub fn index_file(&self, dir: &Path) -> Option<PathBuf> {
+        self.index
+            .iter()
+            .map(|name| dir.join(name))
+            .find(|path| path.is_file())
+    }
+
+    // Whether a directory may be listed, given its canonical path relative to the canonical
+    // root (so `./`, `//` and symlinks can't dodge a rule)
+    pub fn lists(&self, dir: &Path) -> bool {
+        let names: Vec<_> = dir
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect();
+        let path = names.join("/");
+        let path = path.as_str();
+        // The directory itself, then each directory above it up to the root
+        let mut dirs = vec![path];
+        dirs.extend(path.rmatch_indices('/').map(|(slash, _)| &path[..slash]));
+        if !path.is_empty() {
+            dirs.push("");
+        }
+        self.rules
+            .iter()
+            .find(|rule| dirs.iter().any(|dir| rule.path.is_match(dir)))
+            .map_or(self.listings, |rule| rule.listings)
+    }
+}
+
+// The `[directories]` section of the config file
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct DirectoriesConfig {
+    index: Vec<String>,
+    listings: bool,
+    rules: Vec<ListingRuleConfig>,
+}
+
+impl Default for DirectoriesConfig {
+    fn default() -> DirectoriesConfig {
+        let defaults = Directories::default();
+        DirectoriesConfig {
+            index: defaults.index,
+            listings: defaults.listings,
+            rules: Vec::new(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ListingRuleConfig {
+    path: String,
+    listings: bool,
+}
+
+impl TryFrom<DirectoriesConfig> for Directories {
+    type Error = io::Error;
+
+    fn try_from(config: DirectoriesConfig) -> io::Result<Directories> {
+        let index: Vec<&str> = config.index.iter().map(String::as_str).collect();
+        let mut directories = Directories::new().index(&index)?.listings(config.listings);
+        for rule in config.rules {
+            directories = directories.rule(&rule.path, rule.listings)?;
+        }
+        Ok(directories)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_matching_rule_decides() {
+        let directories = Directories::new()
+            .listings(false)
+            .rule("downloads/private", false)
+            .unwrap()
+            .rule("/downloads/", true)
+            .unwrap();
+        assert!(directories.lists(Path::new("downloads")));
+        assert!(directories.lists(Path::new("downloads/a/b")));
+        assert!(!directories.lists(Path::new("downloads/private/a")));
+        assert!(!directories.lists(Path::new("")));
+        assert!(!directories.lists(Path::new("other")));
+        // Names only match whole directories
+        assert!(!directories.lists(Path::new("downloads2")));
+    }
+
+    #[test]
+    fn index_names_are_file_names() {
+        for name in ["a/index.html", "../index.html", "/index.html", "", "."] {
+            assert!(Directories::new().index(&[name]).is_err(), "{:?}", name);
+        }
+        assert!(Directories::new().index(&["default.htm"]).is_ok());
+    }
+}
@@ -0,0 +1,363 @@
+use super::conditional::Validators;
+use lru::LruCache;
+use serde::Deserialize;
+use std::fmt::{self, Display};
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+// In-memory cache of small files, so hot files aren't opened and read on every request
+//
+// Entries hold the content, Content-Type and validators of a file, keyed by its path. A
+// lookup compares the file's current size, modification time and inode with the cached
+// ones and drops the entry when they differ, so a changed file is read again on the next
+// request. When the root is watched (see `server::watch`), files are also dropped as soon
+// as they change. The least recently used files are evicted to stay within `max_size` bytes.
+//
+//     [file_cache]
+//     max_size = 33554432     # Bytes of content kept in memory
+//     max_file_size = 262144  # Larger files are always read from disk
+
+// Settings of the `[file_cache]` config section
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileCacheConfig {
+    pub enabled: bool,
+    pub max_size: u64,
+    pub max_file_size: u64,
+}
+
+impl Default for FileCacheConfig {
+    fn default() -> FileCacheConfig {
+        FileCacheConfig {
+            enabled: true,
+            max_size: 32 * 1024 * 1024,
+            max_file_size: 256 * 1024,
+        }
+    }
+}
+
+impl FileCacheConfig {
+    // Creates the cache, or None if it is disabled
+    pub fn build(&self) -> Option<Arc<FileCache>> {
+        self.enabled
+            .then(|| Arc::new(FileCache::new(self.max_size, self.max_file_size)))
+    }
+}
+
+// A cached file
+#[derive(Debug, Clone)]
+pub struct CachedFile {
+    pub content: Arc<[u8]>,
+    pub content_type: String,
+    pub validators: Validators,
+    stamp: Stamp,
+}
+
+// What identifies a version of a file
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Stamp {
+    len: u64,
+    modified: Option<SystemTime>,
+    inode: u64, // Changes when a file is replaced by renaming another over it
+}
+
+impl Stamp {
+    fn of(metadata: &Metadata) -> Stamp {
+        #[cfg(unix)]
+        let inode = std::os::unix::fs::MetadataExt::ino(metadata);
+        #[cfg(not(unix))]
+        let inode = 0;
+        Stamp {
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+            inode,
+        }
+    }
+}
+
+pub struct FileCache {
+    entries: Mutex<Entries>,
+    max_size: u64,
+    max_file_size: u64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,     // Entries dropped to make room
+    invalidations: AtomicU64, // Entries dropped because their file changed
+}
+
+struct Entries {
+    files: LruCache<PathBuf, CachedFile>,
+    size: u64, // Total length of the cached contents
+}
+
+impl FileCache {
+    pub fn new(max_size: u64, max_file_size: u64) -> FileCache {
+        FileCache {
+            entries: Mutex::new(Entries {
+                files: LruCache::unbounded(), // Bounded by size below
+                size: 0,
+            }),
+            max_size,
+            max_file_size: max_file_size.min(max_size),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+            invalidations: AtomicU64::new(0),
+        }
+    }
+
+    // Whether a file of this length is small enough to be cached
+    pub fn admits(&self, len: u64) -> bool {
+        len <= self.max_file_size
+    }
+
+    // Returns the cached file if it is still the version on disk, described by `metadata`
+    pub fn get(&self, path: &Path, metadata: &Metadata) -> Option<CachedFile> {
+        if !self.admits(metadata.len()) {
+            return None; // Never cached, so not a miss either
+        }
+        let mut entries = self.lock();
+        let fresh = match entries.files.get(path) {
+            Some(cached) => cached.stamp == Stamp::of(metadata),
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                return None;
+            }
+        };
+        if !fresh {
+            entries.remove(path);
+            self.invalidations.fetch_add(1, Ordering::Relaxed);
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        entries.files.get(path).cloned()
+    }
+
+    // Caches a file that was just read, evicting the least recently used ones to make room
+    pub fn insert(
+        &self,
+        path: &Path,
+        metadata: &Metadata,
+        content: Vec<u8>,
+        content_type: String,
+        validators: Validators,
+    ) -> CachedFile {
+        let cached = CachedFile {
+            content: content.into(),
+            content_type,
+            validators,
+            stamp: Stamp::of(metadata),
+        };
+        let len = cached.content.len() as u64;
+        if !self.admits(len) {
+            return cached;
+        }
+        let mut entries = self.lock();
+        entries.remove(path);
+        while entries.size + len > self.max_size {
+            match entries.files.pop_lru() {
+                Some((_, evicted)) => {
+                    entries.size -= evicted.content.len() as u64;
+                    self.evictions.fetch_add(1, Ordering::Relaxed);
+                }
+                None => break,
+            }
+        }
+        entries.size += len;
+        entries.files.put(path.to_path_buf(), cached.clone());
+        cached
+    }
+
+    // Drops a file from the cache
+    pub fn remove(&self, path: &Path) {
+        if self.lock().remove(path) {
+            self.invalidations.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // Drops a file, or a directory's files, from the cache
+    pub fn remove_below(&self, path: &Path) {
+        let mut entries = self.lock();
+        let below: Vec<PathBuf> = entries
+            .files
+            .iter()
+            .map(|(cached, _)| cached)
+            .filter(|cached| cached.starts_with(path))
+            .cloned()
+            .collect();
+        for cached in below {
+            entries.remove(&cached);
+            self.invalidations.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // Drops every file
+    pub fn clear(&self) {
+        let mut entries = self.lock();
+        entries.files.clear();
+        entries.size = 0;
+    }
+
+    // Counters and current usage
+    pub fn stats(&self) -> CacheStats {
+        let entries = self.lock();
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            invalidations: self.invalidations.load(Ordering::Relaxed),
+            files: entries.files.len(),
+            size: entries.size,
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Entries> {
+        // The entries stay consistent even if a thread panicked while holding the lock
+        self.entries.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+impl Entries {
+    // Returns whether the file was cached
+    fn remove(&mut self, path: &Path) -> bool {
+        match self.files.pop(path) {
+            Some(removed) => {
+                self.size -= removed.content.len() as u64;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+// A snapshot of the cache's counters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub invalidations: u64,
+    pub files: usize, // Files cached now
+    pub size: u64,    // Bytes cached now
+}
+
+impl Display for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} hits, {} misses, {} evictions, {} invalidations, {} files ({} bytes) cached",
+            self.hits, self.misses, self.evictions, self.invalidations, self.files, self.size
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use std::fs;
+
+    // Writes a file and caches what was written
+    fn cache_file(cache: &FileCache, path: &Path, content: &[u8]) -> Metadata {
+        fs::write(path, content).unwrap();
+        let metadata = fs::metadata(path).unwrap();
+        let validators = Validators {
+            etag: "\"tag\"".to_string(),
+            last_modified: None,
+        };
+        cache.insert(
+            path,
+            &metadata,
+            content.to_vec(),
+            "text/plain".to_string(),
+            validators,
+        );
+        metadata
+    }
+
+    #[test]
+    fn serves_files_until_they_change() {
+        let dir = TempDir::new("file-cache");
+        let cache = FileCache::new(1024, 100);
+        let path = dir.join("a.txt");
+        let metadata = cache_file(&cache, &path, b"first");
+        let cached = cache.get(&path, &metadata).unwrap();
+        assert_eq!(&*cached.content, b"first");
+        assert_eq!(cached.content_type, "text/plain");
+
+        fs::write(&path, b"second version").unwrap();
+        let changed = fs::metadata(&path).unwrap();
+        assert!(cache.get(&path, &changed).is_none());
+        assert!(cache.get(&dir.join("b.txt"), &changed).is_none());
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.invalidations), (1, 2, 1));
+        assert_eq!((stats.files, stats.size), (0, 0));
+    }
+
+    #[test]
+    fn keeps_large_files_out() {
+        let dir = TempDir::new("file-cache-large");
+        let cache = FileCache::new(1024, 4);
+        assert!(cache.admits(4) && !cache.admits(5));
+        assert!(!FileCache::new(3, 100).admits(4)); // No file larger than the whole cache
+        let path = dir.join("large.txt");
+        let metadata = cache_file(&cache, &path, b"too large");
+        assert!(cache.get(&path, &metadata).is_none());
+        assert_eq!(cache.stats().misses, 0); // Not counted, it could never hit
+        assert_eq!(cache.stats().files, 0);
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used_files() {
+        let dir = TempDir::new("file-cache-lru");
+        let cache = FileCache::new(10, 10);
+        let a = cache_file(&cache, &dir.join("a"), b"aaaa");
+        cache_file(&cache, &dir.join("b"), b"bbbb");
+        assert!(cache.get(&dir.join("a"), &a).is_some()); // Now b is the oldest
+        let c = cache_file(&cache, &dir.join("c"), b"cccc");
+        assert!(cache.get(&dir.join("a"), &a).is_some());
+        assert!(cache.get(&dir.join("c"), &c).is_some());
+        let stats = cache.stats();
+        assert_eq!((stats.files, stats.size, stats.evictions), (2, 8, 1));
+        assert!(stats
+            .to_string()
+            .contains("1 evictions, 0 invalidations, 2 files (8 bytes)"));
+    }
+
+    #[test]
+    fn drops_files_below_a_path() {
+        let dir = TempDir::new("file-cache-remove");
+        let cache = FileCache::new(1024, 100);
+        dir.mkdir("sub");
+        cache_file(&cache, &dir.join("sub/a"), b"a");
+        cache_file(&cache, &dir.join("sub/b"), b"b");
+        let kept = cache_file(&cache, &dir.join("subway"), b"c"); // Not below `sub`
+        cache.remove_below(&dir.join("sub"));
+        assert_eq!(cache.stats().files, 1);
+        assert!(cache.get(&dir.join("subway"), &kept).is_some());
+        cache.remove(&dir.join("subway"));
+        cache.remove(&dir.join("subway"));
+        assert_eq!(cache.stats().invalidations, 3);
+
+        cache_file(&cache, &dir.join("sub/a"), b"a");
+        cache.clear();
+        assert_eq!((cache.stats().files, cache.stats().size), (0, 0));
+    }
+
+    #[test]
+    fn reads_the_config_section() {
+        let config: FileCacheConfig = toml::from_str("max_file_size = 10").unwrap();
+        assert_eq!(
+            (config.max_size, config.max_file_size),
+            (32 * 1024 * 1024, 10)
+        );
+        assert!(config.build().is_some());
+        let disabled: FileCacheConfig = toml::from_str("enabled = false").unwrap();
+        assert!(disabled.build().is_none());
+        assert!(toml::from_str::<FileCacheConfig>("size = 1").is_err());
+    }
+}
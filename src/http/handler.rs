@@ -0,0 +1,205 @@
+use super::cache_control::CachePolicies;
+use super::compression::Compression;
+use super::conditional::ETagKind;
+use super::directory::Directories;
+use super::file_cache::FileCache;
+use super::mime::MimeTypes;
+use super::request::{HttpRequest, Method, StreamedBody};
+use super::response::{HttpResponse, ResponseStatus};
+use crate::config::Config;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+
+// Turns a request into a response
+// Implemented by the static file server, the tus endpoint, the router, and any
+// closure taking `&mut HttpRequest`, so custom endpoints can live next to static files
+pub trait Handler: Send + Sync {
+    // Produces the response for a request whose body has been read
+    fn handle(&self, request: &mut HttpRequest) -> io::Result<HttpResponse>;
+
+    // Inspects a request before its body is read (e.g. for `Expect: 100-continue`)
+    // Returns an early final response to reject the request without reading the body
+    fn precheck(&self, _request: &HttpRequest) -> Option<HttpResponse> {
+        None
+    }
+
+    // Chooses where the body of a request that passed precheck goes (see BodySink)
+    // Streaming it lets large uploads go to disk as they arrive, past the server's body limit
+    fn body_sink(&self, _request: &HttpRequest) -> BodySink {
+        BodySink::Buffer
+    }
+}
+
+// Where a request body goes as it is received
+pub enum BodySink {
+    Buffer,               // Into request_body, up to the server's body size limit
+    Stream(StreamedBody), // Into this writer as it arrives; handed back in HttpRequest::streamed_body
+    Reject(HttpResponse), // Nowhere: send this response instead and close the connection
+}
+
+impl<F> Handler for F
+where
+    F: Fn(&mut HttpRequest) -> io::Result<HttpResponse> + Send + Sync,
+{
+    fn handle(&self, request: &mut HttpRequest) -> io::Result<HttpResponse> {
+        self(request)
+    }
+}
+
+// Boxed handlers are handlers too, so they can be passed around and nested
+impl Handler for Box<dyn Handler> {
+    fn handle(&self, request: &mut HttpRequest) -> io::Result<HttpResponse> {
+        (**self).handle(request)
+    }
+
+    fn precheck(&self, request: &HttpRequest) -> Option<HttpResponse> {
+        (**self).precheck(request)
+    }
+
+    fn body_sink(&self, request: &HttpRequest) -> BodySink {
+        (**self).body_sink(request)
+    }
+}
+
+// Serves files and directory listings below a root directory
+pub struct FileServer {
+    root: PathBuf,
+    canonical_root: OnceLock<PathBuf>, // Resolved on first use, as the root may not exist yet
+    mime_types: MimeTypes,
+    etag: ETagKind,
+    cache: CachePolicies,
+    compression: Compression,
+    directories: Directories,
+    file_cache: Option<Arc<FileCache>>,
+}
+
+impl FileServer {
+    pub fn new(root: impl Into<PathBuf>) -> FileServer {
+        FileServer {
+            root: root.into(),
+            canonical_root: OnceLock::new(),
+            mime_types: MimeTypes::new(),
+            etag: ETagKind::default(),
+            cache: CachePolicies::new(),
+            compression: Compression::default(),
+            directories: Directories::default(),
+            file_cache: None,
+        }
+    }
+
+    // Creates a file server with the options from a config file
+    pub fn with_config(root: impl Into<PathBuf>, config: &Config) -> FileServer {
+        let server = FileServer::new(root)
+            .mime_types(MimeTypes::from(&config.mime_types))
+            .etag(config.etag)
+            .cache(config.cache.clone())
+            .compression(config.compression.clone())
+            .directories(config.directories.clone());
+        match config.file_cache.build() {
+            Some(file_cache) => server.file_cache(file_cache),
+            None => server,
+        }
+    }
+
+    // Sets how the Content-Type of files is chosen
+    pub fn mime_types(mut self, mime_types: MimeTypes) -> FileServer {
+        self.mime_types = mime_types;
+        self
+    }
+
+    // Sets how the ETags of files are made
+    pub fn etag(mut self, kind: ETagKind) -> FileServer {
+        self.etag = kind;
+        self
+    }
+
+    // Returns how the ETags of files are made
+    pub fn etag_kind(&self) -> ETagKind {
+        self.etag
+    }
+
+    // Sets the Cache-Control policies of files and listings
+    pub fn cache(mut self, policies: CachePolicies) -> FileServer {
+        self.cache = policies;
+        self
+    }
+
+    // Returns the Cache-Control policies of files and listings
+    pub fn cache_policies(&self) -> &CachePolicies {
+        &self.cache
+    }
+
+    // Sets when and how files and listings are compressed
+    pub fn compression(mut self, compression: Compression) -> FileServer {
+        self.compression = compression;
+        self
+    }
+
+    // Returns when and how files and listings are compressed
+    pub fn compression_options(&self) -> &Compression {
+        &self.compression
+    }
+
+    // Sets the index files of directories and where listings are allowed
+    pub fn directories(mut self, directories: Directories) -> FileServer {
+        self.directories = directories;
+        self
+    }
+
+    // Returns the index files of directories and where listings are allowed
+    pub fn directory_options(&self) -> &Directories {
+        &self.directories
+    }
+
+    // Keeps small files in memory; the cache may be shared with other file servers
+    pub fn file_cache(mut self, file_cache: Arc<FileCache>) -> FileServer {
+        self.file_cache = Some(file_cache);
+        self
+    }
+
+    // Returns the in-memory cache of small files, if there is one
+    pub fn cached_files(&self) -> Option<&Arc<FileCache>> {
+        self.file_cache.as_ref()
+    }
+
+    // Returns the directory files are served from
+    pub fn root(&self) -> &PathBuf {
+        &self.root
+    }
+
+    // Returns the root with symlinks resolved, which served files must be below
+    pub fn canonical_root(&self) -> io::Result<&Path> {
+        if let Some(root) = self.canonical_root.get() {
+            return Ok(root);
+        }
+        let root = self.root.canonicalize()?;
+        Ok(self.canonical_root.get_or_init(|| root))
+    }
+
+    // Returns the table Content-Types are looked up in
+    pub fn content_types(&self) -> &MimeTypes {
+        &self.mime_types
+    }
+}
+
+impl Handler for FileServer {
+    fn handle(&self, request: &mut HttpRequest) -> io::Result<HttpResponse> {
+        match self.precheck(request) {
+            Some(response) => Ok(response),
+            None => HttpResponse::from_file_server(request, self),
+        }
+    }
+
+    // Files are only read, so anything but GET and HEAD is refused before the body is read
+    fn precheck(&self, request: &HttpRequest) -> Option<HttpResponse> {
+        match request.method() {
+            Method::Get | Method::Head => None,
+            _ => {
+                let mut response = HttpResponse::with_status(ResponseStatus::MethodNotAllowed);
+                response.add_header("Allow", "GET, HEAD");
+                Some(response)
+            }
+        }
+    }
+}
@@ -0,0 +1,302 @@
+use super::cache_control::CacheControl;
+use super::handler::Handler;
+use super::middleware::Middleware;
+use super::request::{HttpRequest, Method};
+use super::response::{HttpResponse, ResponseBody};
+use std::io::{self, Read};
+use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// Live reload for front-end development (`--dev`)
+//
+// HTML pages get a small script that listens to server-sent events from /_live-reload, and
+// `reload` tells every listening page to reload, e.g. whenever the watcher sees a change
+// below the root. The script goes before `</body>` (or at the end of the page), with the
+// Content-Length updated; other responses, and compressed ones, are left alone. Pages with
+// the script differ from the files, so they are sent without validators and not cached.
+// The event streams stay open until `close`, which has to come before the server shuts
+// down, as shutdown waits for them.
+
+pub const LIVE_RELOAD_ENDPOINT: &str = "_live-reload"; // Event stream, relative to the server root
+
+const HEARTBEAT: Duration = Duration::from_secs(15); // Comment lines that keep idle streams open
+const SETTLE: Duration = Duration::from_millis(100); // Changes this close together cause one reload
+const MAX_PAGE_SIZE: usize = 8 * 1024 * 1024; // Larger pages are sent without the script
+
+// Reloads on a message; after losing the server (e.g. a restart), reloads once it is back
+const SCRIPT: &str = r#"<script>
+(function () {
+  var source = new EventSource("/_live-reload"), lost = false;
+  source.onmessage = function () { location.reload(); };
+  source.onerror = function () { lost = true; };
+  source.onopen = function () { if (lost) location.reload(); };
+})();
+</script>
+"#;
+
+// Clones share the connected pages, so one can be added as middleware and another kept
+// to call `reload` and `close`
+#[derive(Clone, Default)]
+pub struct LiveReload {
+    pages: Arc<Mutex<Vec<Sender<()>>>>, // One per open event stream
+    closed: Arc<AtomicBool>,
+}
+
+impl LiveReload {
+    pub fn new() -> LiveReload {
+        LiveReload::default()
+    }
+
+    // Tells every connected page to reload
+    pub fn reload(&self) {
+        lock(&self.pages).retain(|page| page.send(()).is_ok());
+    }
+
+    // Ends the event streams, now and for pages that connect later
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        lock(&self.pages).clear();
+    }
+
+    // Opens an event stream for a page
+    fn events(&self) -> HttpResponse {
+        let (sender, receiver) = mpsc::channel();
+        if !self.closed.load(Ordering::Relaxed) {
+            lock(&self.pages).push(sender);
+        }
+        let stream = EventStream {
+            receiver,
+            pending: b"retry: 1000\n\n".to_vec(), // Reconnect quickly after a restart
+            position: 0,
+        };
+        HttpResponse::builder()
+            .content_type("text/event-stream")
+            .header("Cache-Control", "no-store")
+            .reader(stream, None)
+            .build()
+    }
+}
+
+impl Middleware for LiveReload {
+    fn handle(&self, request: &mut HttpRequest, next: &dyn Handler) -> io::Result<HttpResponse> {
+        if *request.method() == Method::Get && request.resource.path == LIVE_RELOAD_ENDPOINT {
+            return Ok(self.events());
+        }
+        let mut response = next.handle(request)?;
+        inject(&mut response)?;
+        Ok(response)
+    }
+}
+
+// Adds the script to an HTML response
+fn inject(response: &mut HttpResponse) -> io::Result<()> {
+    let essence = response.content_type.split(';').next().unwrap_or("").trim();
+    let html = essence.eq_ignore_ascii_case("text/html")
+        || essence.eq_ignore_ascii_case("application/xhtml+xml");
+    let encoded = response
+        .headers
+        .iter()
+        .any(|(name, _)| name.eq_ignore_ascii_case("Content-Encoding"));
+    if !html || encoded || !response.allows_body() {
+        return Ok(());
+    }
+    let body = mem::replace(&mut response.response_body, ResponseBody::Bytes(Vec::new()));
+    let page = match body {
+        ResponseBody::Bytes(page) => page,
+        ResponseBody::Reader(reader) if response.content_length <= MAX_PAGE_SIZE => {
+            let mut page = Vec::with_capacity(response.content_length);
+            reader
+                .take(response.content_length as u64)
+                .read_to_end(&mut page)?;
+            if page.len() < response.content_length {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "response body shorter than its length",
+                ));
+            }
+            page
+        }
+        // Its length is unknown anyway, so the script simply follows the page
+        ResponseBody::Stream(reader) => {
+            let script = io::Cursor::new(SCRIPT.as_bytes());
+            response.response_body = ResponseBody::Stream(Box::new(reader.chain(script)));
+            uncacheable(response);
+            return Ok(());
+        }
+        body => {
+            response.response_body = body; // Too large to hold in memory
+            return Ok(());
+        }
+    };
+    response.content_length = page.len() + SCRIPT.len();
+    response.response_body = ResponseBody::Bytes(with_script(page));
+    uncacheable(response);
+    Ok(())
+}
+
+// Inserts the script before the last `</body>`, or appends it
+fn with_script(mut page: Vec<u8>) -> Vec<u8> {
+    let end = page
+        .windows(b"</body".len())
+        .rposition(|tag| tag.eq_ignore_ascii_case(b"</body"))
+        .unwrap_or(page.len());
+    page.splice(end..end, SCRIPT.bytes());
+    page
+}
+
+// Drops the file's validators and keeps caches from storing the page
+fn uncacheable(response: &mut HttpResponse) {
+    response.headers.retain(|(name, _)| {
+        !name.eq_ignore_ascii_case("ETag") && !name.eq_ignore_ascii_case("Last-Modified")
+    });
+    response.cache_control = Some(CacheControl::new("no-store"));
+}
+
+// The event stream of one page: a `reload` message per batch of changes, and heartbeats
+struct EventStream {
+    receiver: Receiver<()>,
+    pending: Vec<u8>, // Event text not handed out yet
+    position: usize,  // How much of it has been read
+}
+
+impl Read for EventStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position == self.pending.len() {
+            self.pending = match self.receiver.recv_timeout(HEARTBEAT) {
+                Ok(()) => {
+                    // Saving a file often changes it several times in a row
+                    thread::sleep(SETTLE);
+                    while self.receiver.try_recv().is_ok() {}
+                    b"data: reload\n\n".to_vec()
+                }
+                Err(RecvTimeoutError::Timeout) => b": keep-alive\n\n".to_vec(),
+                Err(RecvTimeoutError::Disconnected) => return Ok(0), // Closed
+            };
+            self.position = 0;
+        }
+        let pending = &self.pending[self.position..];
+        let read = pending.len().min(buf.len());
+        buf[..read].copy_from_slice(&pending[..read]);
+        self.position += read;
+        Ok(read)
+    }
+}
+
+// The page list stays usable even if a thread panicked while holding the lock
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    fn request(target: &str) -> HttpRequest {
+        HttpRequest::new(&format!("GET {} HTTP/1.1\r\nHost: a\r\n\r\n", target)).unwrap()
+    }
+
+    // A cacheable page
+    fn page(_request: &mut HttpRequest) -> io::Result<HttpResponse> {
+        Ok(HttpResponse::builder()
+            .html("<html><BODY>hi</BODY></html>")
+            .header("ETag", "\"abc\"")
+            .header("Last-Modified", "Tue, 01 Jan 2030 00:00:00 GMT")
+            .build())
+    }
+
+    fn body(response: HttpResponse) -> String {
+        let mut body = String::new();
+        response
+            .response_body
+            .into_reader()
+            .read_to_string(&mut body)
+            .unwrap();
+        body
+    }
+
+    #[test]
+    fn adds_the_script_to_html_pages() {
+        let response = LiveReload::new().handle(&mut request("/"), &page).unwrap();
+        assert!(response.headers.is_empty()); // Without validators
+        assert_eq!(response.cache_control.as_ref().unwrap().value, "no-store");
+        let length = response.content_length;
+        let page = body(response);
+        assert_eq!(page.len(), length);
+        assert_eq!(page, format!("<html><BODY>hi{}</BODY></html>", SCRIPT));
+
+        assert_eq!(
+            with_script(b"<p>".to_vec()),
+            format!("<p>{}", SCRIPT).into_bytes()
+        );
+
+        // Streamed pages get it at the end, read bodies in place
+        let mut streamed = HttpResponse::builder()
+            .content_type("text/html")
+            .reader(&b"<p>"[..], None)
+            .build();
+        inject(&mut streamed).unwrap();
+        assert_eq!(body(streamed), format!("<p>{}", SCRIPT));
+        let mut read = HttpResponse::builder()
+            .content_type("application/xhtml+xml")
+            .reader(&b"<body></body>extra"[..], Some(13))
+            .build();
+        inject(&mut read).unwrap();
+        assert_eq!(body(read), format!("<body>{}</body>", SCRIPT));
+        let mut short = HttpResponse::builder()
+            .content_type("text/html")
+            .reader(&b"<p>"[..], Some(10))
+            .build();
+        assert!(inject(&mut short).is_err());
+    }
+
+    #[test]
+    fn leaves_other_responses_alone() {
+        let mut text = HttpResponse::builder().text("</body>").build();
+        inject(&mut text).unwrap();
+        assert_eq!(body(text), "</body>");
+
+        let mut encoded = HttpResponse::builder()
+            .html("<body></body>")
+            .header("Content-Encoding", "gzip")
+            .build();
+        inject(&mut encoded).unwrap();
+        assert_eq!(body(encoded), "<body></body>");
+    }
+
+    #[test]
+    fn streams_a_reload_per_batch_of_changes() {
+        let live_reload = LiveReload::new();
+        let endpoint = format!("/{}", LIVE_RELOAD_ENDPOINT);
+        let response = live_reload.handle(&mut request(&endpoint), &page).unwrap();
+        assert_eq!(response.content_type, "text/event-stream");
+        assert!(response.is_chunked());
+        let mut events = response.response_body.into_reader();
+        let mut buf = [0; 64];
+        let read = events.read(&mut buf).unwrap();
+        assert_eq!(&buf[..read], b"retry: 1000\n\n");
+
+        live_reload.reload();
+        live_reload.reload(); // Within the settle time, so in the same message
+        let read = events.read(&mut buf).unwrap();
+        assert_eq!(&buf[..read], b"data: reload\n\n");
+
+        let reloader = live_reload.clone();
+        let started = Instant::now();
+        let closer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            reloader.close();
+        });
+        assert_eq!(events.read(&mut buf).unwrap(), 0); // Ends once closed
+        assert!(started.elapsed() < HEARTBEAT);
+        closer.join().unwrap();
+
+        // Pages connecting after `close` get an ended stream right away
+        let late = live_reload.handle(&mut request(&endpoint), &page).unwrap();
+        assert_eq!(body(late), "retry: 1000\n\n");
+    }
+}
@@ -0,0 +1,652 @@
+use super::handler::{BodySink, Handler};
+use super::request::{HttpRequest, Method};
+use super::response::{HttpResponse, ResponseStatus};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Wraps a handler to add behaviour around it (logging, auth, headers, ...)
+// A middleware decides whether to call `next`, and may change the request before
+// and the response after it. User-defined middlewares implement this trait, or
+// are closures taking `(&mut HttpRequest, &dyn Handler)`.
+pub trait Middleware: Send + Sync {
+    fn handle(&self, request: &mut HttpRequest, next: &dyn Handler) -> io::Result<HttpResponse>;
+
+    // Runs before the request body is read; return a response to reject the request early
+    fn precheck(&self, request: &HttpRequest, next: &dyn Handler) -> Option<HttpResponse> {
+        next.precheck(request)
+    }
+
+    // Runs after precheck accepted the request; chooses where its body goes (see BodySink)
+    fn body_sink(&self, request: &HttpRequest, next: &dyn Handler) -> BodySink {
+        next.body_sink(request)
+    }
+}
+
+impl<F> Middleware for F
+where
+    F: Fn(&mut HttpRequest, &dyn Handler) -> io::Result<HttpResponse> + Send + Sync,
+{
+    fn handle(&self, request: &mut HttpRequest, next: &dyn Handler) -> io::Result<HttpResponse> {
+        self(request, next)
+    }
+}
+
+// Boxed middlewares are middlewares too, so they can be collected before building a chain
+impl Middleware for Box<dyn Middleware> {
+    fn handle(&self, request: &mut HttpRequest, next: &dyn Handler) -> io::Result<HttpResponse> {
+        (**self).handle(request, next)
+    }
+
+    fn precheck(&self, request: &HttpRequest, next: &dyn Handler) -> Option<HttpResponse> {
+        (**self).precheck(request, next)
+    }
+
+    fn body_sink(&self, request: &HttpRequest, next: &dyn Handler) -> BodySink {
+        (**self).body_sink(request, next)
+    }
+}
+
+// A handler wrapped in a stack of middlewares
+// The first middleware added is the outermost one: it sees the request first and the response last
+pub struct Chain {
+    middlewares: Vec<Box<dyn Middleware>>,
+    handler: Box<dyn Handler>,
+}
+
+impl Chain {
+    pub fn new(handler: impl Handler + 'static) -> Chain {
+        Chain {
+            middlewares: Vec::new(),
+            handler: Box::new(handler),
+        }
+    }
+
+    // Adds a middleware inside the ones already added
+    pub fn with(mut self, middleware: impl Middleware + 'static) -> Chain {
+        self.middlewares.push(Box::new(middleware));
+        self
+    }
+}
+
+impl Handler for Chain {
+    fn handle(&self, request: &mut HttpRequest) -> io::Result<HttpResponse> {
+        Next {
+            middlewares: &self.middlewares,
+            handler: self.handler.as_ref(),
+        }
+        .handle(request)
+    }
+
+    fn precheck(&self, request: &HttpRequest) -> Option<HttpResponse> {
+        Next {
+            middlewares: &self.middlewares,
+            handler: self.handler.as_ref(),
+        }
+        .precheck(request)
+    }
+
+    fn body_sink(&self, request: &HttpRequest) -> BodySink {
+        Next {
+            middlewares: &self.middlewares,
+            handler: self.handler.as_ref(),
+        }
+        .body_sink(request)
+    }
+}
+
+// The rest of a chain, as seen by a middleware
+struct Next<'a> {
+    middlewares: &'a [Box<dyn Middleware>],
+    handler: &'a dyn Handler,
+}
+
+impl Handler for Next<'_> {
+    fn handle(&self, request: &mut HttpRequest) -> io::Result<HttpResponse> {
+        match self.middlewares.split_first() {
+            Some((middleware, rest)) => middleware.handle(
+                request,
+                &Next {
+                    middlewares: rest,
+                    handler: self.handler,
+                },
+            ),
+            None => self.handler.handle(request),
+        }
+    }
+
+    fn precheck(&self, request: &HttpRequest) -> Option<HttpResponse> {
+        match self.middlewares.split_first() {
+            Some((middleware, rest)) => middleware.precheck(
+                request,
+                &Next {
+                    middlewares: rest,
+                    handler: self.handler,
+                },
+            ),
+            None => self.handler.precheck(request),
+        }
+    }
+
+    fn body_sink(&self, request: &HttpRequest) -> BodySink {
+        match self.middlewares.split_first() {
+            Some((middleware, rest)) => middleware.body_sink(
+                request,
+                &Next {
+                    middlewares: rest,
+                    handler: self.handler,
+                },
+            ),
+            None => self.handler.body_sink(request),
+        }
+    }
+}
+
+// Logs one line per request: client, method, target, status and time taken
+pub struct Logger;
+
+impl Middleware for Logger {
+    fn handle(&self, request: &mut HttpRequest, next: &dyn Handler) -> io::Result<HttpResponse> {
+        let started = Instant::now();
+        let client = request
+            .peer_addr()
+            .map_or("-".to_string(), |addr| addr.ip().to_string());
+        let line = format!("{} {} {}", client, request.method(), request.target());
+        let response = next.handle(request);
+        match &response {
+            Ok(response) => println!("{} -> {} ({:?})", line, response.status, started.elapsed()),
+            Err(err) => println!("{} -> error: {} ({:?})", line, err, started.elapsed()),
+        }
+        response
+    }
+
+    // Requests rejected before their body is read are logged too
+    fn precheck(&self, request: &HttpRequest, next: &dyn Handler) -> Option<HttpResponse> {
+        let rejection = next.precheck(request)?;
+        let client = request
+            .peer_addr()
+            .map_or("-".to_string(), |addr| addr.ip().to_string());
+        println!(
+            "{} {} {} -> {} (rejected early)",
+            client,
+            request.method(),
+            request.target(),
+            rejection.status
+        );
+        Some(rejection)
+    }
+}
+
+// Adds headers that harden browsers against sniffing, framing and referrer leaks
+// Headers a handler already set are left alone
+pub struct SecurityHeaders {
+    headers: Vec<(String, String)>,
+}
+
+impl SecurityHeaders {
+    pub fn new() -> SecurityHeaders {
+        SecurityHeaders {
+            headers: vec![
+                ("X-Content-Type-Options".to_string(), "nosniff".to_string()),
+                ("X-Frame-Options".to_string(), "SAMEORIGIN".to_string()),
+                ("Referrer-Policy".to_string(), "no-referrer".to_string()),
+            ],
+        }
+    }
+
+    // Adds (or replaces) a header in the set, e.g. a Content-Security-Policy
+    pub fn header(mut self, name: &str, value: &str) -> SecurityHeaders {
+        self.headers
+            .retain(|(existing, _)| !existing.eq_ignore_ascii_case(name));
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+}
+
+impl Default for SecurityHeaders {
+    fn default() -> SecurityHeaders {
+        SecurityHeaders::new()
+    }
+}
+
+impl Middleware for SecurityHeaders {
+    fn handle(&self, request: &mut HttpRequest, next: &dyn Handler) -> io::Result<HttpResponse> {
+        let mut response = next.handle(request)?;
+        for (name, value) in &self.headers {
+            if !has_header(&response, name) {
+                response.add_header(name, value);
+            }
+        }
+        Ok(response)
+    }
+}
+
+// Cross-origin resource sharing: answers preflight requests and tags responses for allowed origins
+pub struct Cors {
+    origins: Vec<String>, // Allowed origins; empty means any origin
+    methods: String,
+    headers: String,
+    expose: String,
+    max_age: u64,
+}
+
+impl Cors {
+    // Allows any origin, the methods this server handles, and the tus headers
+    pub fn new() -> Cors {
+        Cors {
+            origins: Vec::new(),
+            methods: "GET, HEAD, POST, PATCH, DELETE, OPTIONS".to_string(),
+            headers: "Content-Type, Authorization, Tus-Resumable, Upload-Length, Upload-Metadata, Upload-Offset"
+                .to_string(),
+            expose: "Location, Tus-Resumable, Tus-Version, Tus-Extension, Tus-Max-Size, Upload-Offset, Upload-Length, Upload-Expires"
+                .to_string(),
+            max_age: 86400,
+        }
+    }
+
+    // Restricts CORS to the given origin; may be called several times
+    pub fn allow_origin(mut self, origin: &str) -> Cors {
+        self.origins.push(origin.to_string());
+        self
+    }
+
+    pub fn allow_methods(mut self, methods: &str) -> Cors {
+        self.methods = methods.to_string();
+        self
+    }
+
+    pub fn allow_headers(mut self, headers: &str) -> Cors {
+        self.headers = headers.to_string();
+        self
+    }
+
+    pub fn expose_headers(mut self, headers: &str) -> Cors {
+        self.expose = headers.to_string();
+        self
+    }
+
+    // Returns the value for Access-Control-Allow-Origin, or None if the origin isn't allowed
+    fn allowed_origin(&self, origin: &str) -> Option<String> {
+        if self.origins.is_empty() {
+            Some("*".to_string())
+        } else if self.origins.iter().any(|allowed| allowed == origin) {
+            Some(origin.to_string())
+        } else {
+            None
+        }
+    }
+
+    // Builds the answer to a preflight request
+    fn preflight(&self, allow_origin: String) -> HttpResponse {
+        let mut response = HttpResponse::with_status(ResponseStatus::NoContent);
+        response.add_header("Access-Control-Allow-Origin", allow_origin);
+        response.add_header("Access-Control-Allow-Methods", &self.methods);
+        response.add_header("Access-Control-Allow-Headers", &self.headers);
+        response.add_header("Access-Control-Max-Age", self.max_age);
+        response.add_header("Vary", "Origin");
+        response
+    }
+}
+
+impl Default for Cors {
+    fn default() -> Cors {
+        Cors::new()
+    }
+}
+
+// Whether a request is a CORS preflight rather than an actual OPTIONS request
+fn is_preflight(request: &HttpRequest) -> bool {
+    *request.method() == Method::Options
+        && request.header("Origin").is_some()
+        && request.header("Access-Control-Request-Method").is_some()
+}
+
+impl Middleware for Cors {
+    fn handle(&self, request: &mut HttpRequest, next: &dyn Handler) -> io::Result<HttpResponse> {
+        // No Origin means a same-origin or non-browser request
+        let allow_origin = request
+            .header("Origin")
+            .and_then(|origin| self.allowed_origin(origin));
+        if let Some(allow_origin) = &allow_origin {
+            if is_preflight(request) {
+                return Ok(self.preflight(allow_origin.clone()));
+            }
+        }
+
+        let mut response = next.handle(request)?;
+        // With an origin list, even a missing or refused Origin changes the response
+        let varies = allow_origin.is_some() || !self.origins.is_empty();
+        if let Some(allow_origin) = allow_origin {
+            response.add_header("Access-Control-Allow-Origin", allow_origin);
+            response.add_header("Access-Control-Expose-Headers", &self.expose);
+        }
+        if varies {
+            response.add_header("Vary", "Origin");
+        }
+        Ok(response)
+    }
+
+    fn precheck(&self, request: &HttpRequest, next: &dyn Handler) -> Option<HttpResponse> {
+        if is_preflight(request) {
+            return None; // Answered in handle
+        }
+        next.precheck(request)
+    }
+}
+
+// HTTP Basic authentication (RFC 7617) with a fixed set of credentials
+pub struct BasicAuth {
+    realm: String,
+    credentials: Vec<String>, // Expected `user:password` pairs
+}
+
+impl BasicAuth {
+    pub fn new(realm: &str) -> BasicAuth {
+        BasicAuth {
+            realm: realm.to_string(),
+            credentials: Vec::new(),
+        }
+    }
+
+    // Adds an accepted user name and password
+    pub fn user(mut self, user: &str, password: &str) -> BasicAuth {
+        self.credentials.push(format!("{}:{}", user, password));
+        self
+    }
+
+    // Checks the Authorization header against the configured credentials
+    fn is_authorized(&self, request: &HttpRequest) -> bool {
+        let encoded = match request
+            .header("Authorization")
+            .and_then(|value| value.split_once(' '))
+        {
+            Some((scheme, encoded)) if scheme.eq_ignore_ascii_case("Basic") => encoded.trim(),
+            _ => return false,
+        };
+        let decoded = match STANDARD.decode(encoded) {
+            Ok(decoded) => decoded,
+            Err(_) => return false,
+        };
+        // Compare every pair in full so timing doesn't reveal how much matched
+        self.credentials.iter().fold(false, |found, expected| {
+            found | constant_time_eq(expected.as_bytes(), &decoded)
+        })
+    }
+
+    fn unauthorized(&self) -> HttpResponse {
+        let mut response = HttpResponse::with_status(ResponseStatus::Unauthorized);
+        response.add_header(
+            "WWW-Authenticate",
+            format!(
+                "Basic realm=\"{}\", charset=\"UTF-8\"",
+                self.realm.replace('"', "")
+            ),
+        );
+        response
+    }
+}
+
+// Compares two byte strings without stopping at the first difference
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+impl Middleware for BasicAuth {
+    fn handle(&self, request: &mut HttpRequest, next: &dyn Handler) -> io::Result<HttpResponse> {
+        if self.is_authorized(request) {
+            next.handle(request)
+        } else {
+            Ok(self.unauthorized())
+        }
+    }
+
+    // Reject before reading the body, so unauthenticated uploads don't transfer anything
+    fn precheck(&self, request: &HttpRequest, next: &dyn Handler) -> Option<HttpResponse> {
+        if self.is_authorized(request) {
+            next.precheck(request)
+        } else {
+            Some(self.unauthorized())
+        }
+    }
+}
+
+// Limits each client IP to a burst of requests, refilled at a steady rate (token bucket)
+pub struct RateLimit {
+    burst: f64,      // Bucket capacity
+    per_second: f64, // Refill rate
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+// Tokens left for one client, as of `updated`
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimit {
+    // Allows `burst` requests at once and `per_second` requests per second after that
+    pub fn new(burst: u32, per_second: f64) -> RateLimit {
+        RateLimit {
+            burst: f64::from(burst.max(1)),
+            per_second,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Refills the client's bucket and optionally takes a token
+    // Returns how long to wait before retrying if the bucket is empty
+    fn check(&self, ip: IpAddr, take: bool) -> Option<Duration> {
+        let now = Instant::now();
+        let mut buckets = self
+            .buckets
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        // Forget clients whose buckets are full again, so the map doesn't grow forever
+        if buckets.len() > 10_000 {
+            let (burst, rate) = (self.burst, self.per_second);
+            buckets.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate < burst
+            });
+        }
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.per_second).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens < 1.0 {
+            let wait = if self.per_second > 0.0 {
+                (1.0 - bucket.tokens) / self.per_second
+            } else {
+                60.0
+            };
+            return Some(Duration::from_secs_f64(wait));
+        }
+        if take {
+            bucket.tokens -= 1.0;
+        }
+        None
+    }
+
+    fn too_many_requests(wait: Duration) -> HttpResponse {
+        let mut response = HttpResponse::with_status(ResponseStatus::TooManyRequests);
+        response.add_header("Retry-After", (wait.as_secs_f64().ceil() as u64).max(1));
+        response
+    }
+}
+
+impl Middleware for RateLimit {
+    fn handle(&self, request: &mut HttpRequest, next: &dyn Handler) -> io::Result<HttpResponse> {
+        if let Some(ip) = request.peer_addr().map(|addr| addr.ip()) {
+            if let Some(wait) = self.check(ip, true) {
+                return Ok(RateLimit::too_many_requests(wait));
+            }
+        }
+        next.handle(request)
+    }
+
+    // Checks without taking a token; the token is taken once the request is handled
+    fn precheck(&self, request: &HttpRequest, next: &dyn Handler) -> Option<HttpResponse> {
+        if let Some(ip) = request.peer_addr().map(|addr| addr.ip()) {
+            if let Some(wait) = self.check(ip, false) {
+                return Some(RateLimit::too_many_requests(wait));
+            }
+        }
+        next.precheck(request)
+    }
+}
+
+// Case-insensitive check for a header already set on a response
+fn has_header(response: &HttpResponse, name: &str) -> bool {
+    (name.eq_ignore_ascii_case("Content-Type") && !response.content_type.is_empty())
+        || response
+            .headers
+            .iter()
+            .any(|(existing, _)| existing.eq_ignore_ascii_case(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Answers 200 with an empty body
+    fn ok(_request: &mut HttpRequest) -> io::Result<HttpResponse> {
+        Ok(HttpResponse::with_status(ResponseStatus::OK))
+    }
+
+    fn request(method: &str, headers: &str) -> HttpRequest {
+        let mut request = HttpRequest::new(&format!(
+            "{} / HTTP/1.1\r\nHost: a\r\n{}\r\n",
+            method, headers
+        ))
+        .unwrap();
+        request.set_peer_addr("192.0.2.1:1234".parse().unwrap());
+        request
+    }
+
+    // The values of a response header, in order
+    fn header<'a>(response: &'a HttpResponse, name: &str) -> Vec<&'a str> {
+        response
+            .headers
+            .iter()
+            .filter(|(existing, _)| existing.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn runs_middlewares_outermost_first() {
+        let order = |tag: &'static str| {
+            move |request: &mut HttpRequest, next: &dyn Handler| {
+                let mut response = next.handle(request)?;
+                response.add_header("X-Order", tag);
+                Ok(response)
+            }
+        };
+        let chain = Chain::new(ok).with(order("outer")).with(order("inner"));
+        let response = chain.handle(&mut request("GET", "")).unwrap();
+        assert_eq!(header(&response, "X-Order"), ["inner", "outer"]);
+    }
+
+    #[test]
+    fn cors_varies_on_origin_when_origins_are_restricted() {
+        let chain = Chain::new(ok).with(Cors::new().allow_origin("https://a.example"));
+        for headers in [
+            "",
+            "Origin: https://b.example\r\n",
+            "Origin: https://a.example\r\n",
+        ] {
+            let response = chain.handle(&mut request("GET", headers)).unwrap();
+            assert_eq!(header(&response, "Vary"), ["Origin"], "{:?}", headers);
+        }
+        let response = chain
+            .handle(&mut request("GET", "Origin: https://a.example\r\n"))
+            .unwrap();
+        assert_eq!(
+            header(&response, "Access-Control-Allow-Origin"),
+            ["https://a.example"]
+        );
+        let response = chain
+            .handle(&mut request("GET", "Origin: https://b.example\r\n"))
+            .unwrap();
+        assert!(header(&response, "Access-Control-Allow-Origin").is_empty());
+
+        let any = Chain::new(ok).with(Cors::new());
+        let response = any.handle(&mut request("GET", "")).unwrap();
+        assert!(response.headers.is_empty());
+    }
+
+    #[test]
+    fn cors_answers_preflights_of_allowed_origins() {
+        let chain = Chain::new(ok).with(Cors::new().allow_origin("https://a.example"));
+        let preflight = "Origin: https://a.example\r\nAccess-Control-Request-Method: PATCH\r\n";
+        assert!(chain.precheck(&request("OPTIONS", preflight)).is_none());
+        let response = chain.handle(&mut request("OPTIONS", preflight)).unwrap();
+        assert_eq!(response.status, ResponseStatus::NoContent);
+        assert_eq!(header(&response, "Access-Control-Max-Age"), ["86400"]);
+        let refused = "Origin: https://b.example\r\nAccess-Control-Request-Method: PATCH\r\n";
+        let response = chain.handle(&mut request("OPTIONS", refused)).unwrap();
+        assert_eq!(response.status, ResponseStatus::OK); // Passed on to the handler
+    }
+
+    #[test]
+    fn basic_auth_rejects_before_the_body() {
+        let chain = Chain::new(ok).with(BasicAuth::new("files").user("alice", "secret"));
+        let good = format!(
+            "Authorization: Basic {}\r\n",
+            STANDARD.encode("alice:secret")
+        );
+        let bad = format!(
+            "Authorization: Basic {}\r\n",
+            STANDARD.encode("alice:wrong")
+        );
+        assert!(chain.precheck(&request("PUT", &good)).is_none());
+        assert_eq!(
+            chain.handle(&mut request("GET", &good)).unwrap().status,
+            ResponseStatus::OK
+        );
+        for headers in ["", bad.as_str(), "Authorization: Bearer x\r\n"] {
+            let rejection = chain.precheck(&request("PUT", headers)).unwrap();
+            assert_eq!(rejection.status, ResponseStatus::Unauthorized);
+            assert_eq!(
+                header(&rejection, "WWW-Authenticate"),
+                ["Basic realm=\"files\", charset=\"UTF-8\""]
+            );
+        }
+    }
+
+    #[test]
+    fn rate_limit_refuses_requests_past_the_burst() {
+        let chain = Chain::new(ok).with(RateLimit::new(2, 0.0));
+        for _ in 0..2 {
+            assert!(chain.precheck(&request("GET", "")).is_none()); // Prechecks don't take tokens
+            assert_eq!(
+                chain.handle(&mut request("GET", "")).unwrap().status,
+                ResponseStatus::OK
+            );
+        }
+        let response = chain.handle(&mut request("GET", "")).unwrap();
+        assert_eq!(response.status, ResponseStatus::TooManyRequests);
+        assert_eq!(header(&response, "Retry-After"), ["60"]);
+        assert!(chain.precheck(&request("GET", "")).is_some());
+    }
+
+    #[test]
+    fn security_headers_keep_the_handlers_own() {
+        let framed = |_request: &mut HttpRequest| {
+            let mut response = HttpResponse::with_status(ResponseStatus::OK);
+            response.add_header("x-frame-options", "DENY");
+            Ok(response)
+        };
+        let chain = Chain::new(framed)
+            .with(SecurityHeaders::new().header("Referrer-Policy", "same-origin"));
+        let response = chain.handle(&mut request("GET", "")).unwrap();
+        assert_eq!(header(&response, "X-Frame-Options"), ["DENY"]);
+        assert_eq!(header(&response, "Referrer-Policy"), ["same-origin"]);
+        assert_eq!(header(&response, "X-Content-Type-Options"), ["nosniff"]);
+    }
+}
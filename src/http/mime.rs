@@ -0,0 +1,171 @@
+use super::charset;
+use encoding_rs::Encoding;
+use std::collections::HashMap;
+use std::path::Path;
+
+// Picks the Content-Type of served files
+//
+// In order of precedence:
+//   1. user overrides by extension (from the config file)
+//   2. the extension, looked up in mime_guess's table
+//   3. the content, for files without a known extension: magic bytes (infer), then
+//      text/plain if the start of the file looks like text
+//   4. application/octet-stream
+// The extension comes before the content because sniffing can't tell CSS, JavaScript or
+// JSON from any other text. Text types get the charset detected from the content (see
+// `charset`) unless they name one.
+#[derive(Debug, Default, Clone)]
+pub struct MimeTypes {
+    overrides: HashMap<String, String>, // Lowercase extension -> Content-Type
+}
+
+impl MimeTypes {
+    pub fn new() -> MimeTypes {
+        MimeTypes::default()
+    }
+
+    // Uses `content_type` for files with the extension `extension` (without the dot)
+    pub fn insert(&mut self, extension: &str, content_type: &str) {
+        self.overrides.insert(
+            extension.trim_start_matches('.').to_ascii_lowercase(),
+            content_type.to_string(),
+        );
+    }
+
+    // Returns the Content-Type for a file, given the first bytes of its content
+    pub fn content_type(&self, path: &Path, sniffed: &[u8]) -> String {
+        let extension = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_ascii_lowercase());
+        if let Some(content_type) = extension.as_ref().and_then(|ext| self.overrides.get(ext)) {
+            return with_charset(content_type, sniffed);
+        }
+        let mime = extension
+            .and_then(|ext| mime_guess::from_ext(&ext).first_raw())
+            .or_else(|| infer::get(sniffed).map(|file_type| file_type.mime_type()))
+            .unwrap_or(if looks_like_text(sniffed) {
+                "text/plain"
+            } else {
+                "application/octet-stream"
+            });
+        with_charset(mime, sniffed)
+    }
+}
+
+impl From<&HashMap<String, String>> for MimeTypes {
+    fn from(overrides: &HashMap<String, String>) -> MimeTypes {
+        let mut mime_types = MimeTypes::new();
+        for (extension, content_type) in overrides {
+            mime_types.insert(extension, content_type);
+        }
+        mime_types
+    }
+}
+
+// Adds the charset detected from the content to text types that don't declare one
+fn with_charset(mime: &str, sniffed: &[u8]) -> String {
+    if is_text(mime) && charset::declared(mime).is_none() {
+        set_charset(mime, charset::detect(sniffed))
+    } else {
+        mime.to_string()
+    }
+}
+
+// Replaces the charset parameter of a Content-Type, adding it if missing
+pub fn set_charset(content_type: &str, encoding: &'static Encoding) -> String {
+    let mut params = content_type.split(';');
+    let mut result = params.next().unwrap_or("").trim().to_string();
+    for param in params {
+        let is_charset = param
+            .split_once('=')
+            .is_some_and(|(name, _)| name.trim().eq_ignore_ascii_case("charset"));
+        if !is_charset {
+            result.push(';');
+            result.push_str(param);
+        }
+    }
+    result.push_str("; charset=");
+    result.push_str(&charset::label(encoding));
+    result
+}
+
+// Whether a MIME type is text that browsers decode with a charset
+pub fn is_text(mime: &str) -> bool {
+    let essence = mime.split(';').next().unwrap_or("").trim();
+    essence.starts_with("text/")
+        || essence.ends_with("+xml")
+        || essence.ends_with("+json")
+        || matches!(
+            essence,
+            "application/javascript" | "application/json" | "application/xml"
+        )
+}
+
+// Whether sniffed bytes look like text: UTF-8 with no control characters other than whitespace
+fn looks_like_text(sniffed: &[u8]) -> bool {
+    charset::utf8_prefix(sniffed).is_some_and(|text| {
+        !text
+            .chars()
+            .any(|c| c.is_control() && !matches!(c, '\n' | '\r' | '\t' | '\x0c'))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use encoding_rs::UTF_8;
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+
+    #[test]
+    fn prefers_overrides_then_extensions_then_content() {
+        let mut mime_types = MimeTypes::new();
+        mime_types.insert(".MD", "text/markdown");
+        mime_types.insert("dat", "application/x-data");
+        let cases = [
+            ("notes.md", &b"# Notes"[..], "text/markdown; charset=utf-8"),
+            ("table.dat", b"1,2", "application/x-data"),
+            ("page.HTML", b"<p>", "text/html; charset=utf-8"),
+            ("image.png", b"not really", "image/png"),
+            ("data.json", b"GIF89a", "application/json; charset=utf-8"), // The extension wins over the content
+            ("image", PNG, "image/png"),
+            ("README", b"plain words\n", "text/plain; charset=utf-8"),
+            ("blob", b"\0\x01\x02", "application/octet-stream"),
+        ];
+        for (name, sniffed, expected) in cases {
+            assert_eq!(
+                mime_types.content_type(Path::new(name), sniffed),
+                expected,
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn keeps_declared_charsets_and_detects_others() {
+        let mut mime_types = MimeTypes::new();
+        mime_types.insert("txt", "text/plain; charset=iso-8859-1");
+        let latin1 = b"caf\xe9 cr\xe8me br\xfbl\xe9e, na\xefve fa\xe7ade";
+        assert_eq!(
+            mime_types.content_type(Path::new("menu.txt"), latin1),
+            "text/plain; charset=iso-8859-1"
+        );
+        assert_eq!(
+            MimeTypes::new().content_type(Path::new("menu.txt"), latin1),
+            "text/plain; charset=windows-1252"
+        );
+    }
+
+    #[test]
+    fn replaces_charset_parameters() {
+        assert_eq!(
+            set_charset("text/html;Charset=latin1; level=1", UTF_8),
+            "text/html; level=1; charset=utf-8"
+        );
+        assert!(is_text("application/ld+json"));
+        assert!(is_text("image/svg+xml"));
+        assert!(!is_text("image/png"));
+    }
+}
@@ -1,2 +1,15 @@
+pub mod charset;
+pub mod cache_control;
+pub mod chunked;
+pub mod compression;
+pub mod conditional;
+pub mod directory;
+pub mod file_cache;
+pub mod handler;
+pub mod live_reload;
+pub mod middleware;
+pub mod mime;
 pub mod request;
-pub mod response;
\ No newline at end of file
+pub mod response;
+pub mod router;
+pub mod tus;
\ No newline at end of file
@@ -1,82 +1,500 @@
-use std::{collections::HashMap, fmt::Display, io, str::FromStr}; // Imports needed for handling HashMap, formatting, I/O, and string parsing
-use super::response::HttpResponse; // Import HttpResponse from the response module
+use std::{ffi::OsString, fmt::Display, io::{self, Write}, net::SocketAddr, path::PathBuf, str::FromStr}; // Imports needed for handling formatting, I/O, paths, and string parsing
+use percent_encoding::percent_decode_str; // For decoding %XX escapes in request paths
+use super::response::{HttpResponse, ResponseStatus}; // Import HttpResponse from the response module
 
 // The HttpRequest struct stores information about an HTTP request
+// Fields are read through the accessor methods below, which are the stable API for embedders
 #[derive(Debug)]
 pub struct HttpRequest {
     method: Method,         // HTTP method (GET, POST, etc.)
+    target: String,        // Request target exactly as sent (e.g. /dir/file.txt?download=1)
+    path: String,          // Percent-decoded path, always starting with `/`
     pub resource: Resource, // Requested resource (e.g., file path)
-    version: Version,       // HTTP version (1.1, 2.0)
+    version: Version,       // HTTP version (1.0, 1.1)
     headers: HttpHeader,    // HTTP headers (key-value pairs)
-    pub request_body: String, // Body of the HTTP request (for POST, etc.)
+    query: Vec<(String, String)>, // Decoded query parameters; names may repeat
+    pub request_body: Vec<u8>, // Body of the HTTP request (for POST, PATCH, etc.)
+    pub request_trailers: Vec<(String, String)>, // Trailer fields sent after a chunked body
+    pub streamed_body: Option<StreamedBody>, // Where the body went instead, if the handler chose to stream it
+    peer_addr: Option<SocketAddr>, // Address of the client, if known
+    pub(crate) params: Vec<(String, String)>, // Path parameters captured by the router
+}
+
+// How the length of a request body is determined (RFC 9112 section 6.3)
+#[derive(Debug, PartialEq)]
+pub enum BodyLength {
+    None,         // No body
+    Fixed(usize), // Content-Length bytes follow the head
+    Chunked,      // Body uses the chunked transfer coding
+}
+
+// A request body written out as it arrived instead of into request_body
+// Wraps the writer a handler chose in Handler::body_sink, which it gets back with the request
+pub struct StreamedBody(Box<dyn Write + Send>);
+
+impl StreamedBody {
+    pub fn new(writer: impl Write + Send + 'static) -> StreamedBody {
+        StreamedBody(Box::new(writer))
+    }
+}
+
+impl Write for StreamedBody {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl std::fmt::Debug for StreamedBody {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("StreamedBody")
+    }
 }
 
 impl HttpRequest {
     // Method to generate an HTTP response for the current request
+    // Serves static files from the current directory; see `Handler` for custom routing and
+    // `Router::uploads` for tus uploads
     pub fn response(&self) -> io::Result<HttpResponse> {
+        if let Some(response) = self.server_wide_response() {
+            return Ok(response);
+        }
         HttpResponse::new(self)
     }
 
+    // Answers requests that aren't about a resource at all (`OPTIONS *` and `CONNECT`)
+    pub(crate) fn server_wide_response(&self) -> Option<HttpResponse> {
+        match self.resource.form {
+            // OPTIONS * asks about the server as a whole
+            TargetForm::Asterisk => {
+                let mut response = HttpResponse::with_status(ResponseStatus::NoContent);
+                response.add_header("Allow", "GET, HEAD, OPTIONS, POST, PATCH, DELETE");
+                Some(response)
+            }
+            // CONNECT is for proxies, which this server is not
+            TargetForm::Authority => Some(HttpResponse::with_status(ResponseStatus::NotImplemented)),
+            TargetForm::Origin | TargetForm::Absolute => None,
+        }
+    }
+
+    // Returns a path parameter captured by the router (e.g. "id" for the route /users/:id)
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    // Returns the HTTP method of the request
+    pub fn method(&self) -> &Method {
+        &self.method
+    }
+
+    // Returns the request target exactly as it appeared in the request line
+    pub fn target(&self) -> &str {
+        &self.target
+    }
+
+    // Returns the percent-decoded path, starting with `/` (e.g. "/test-files/你好")
+    // Invalid UTF-8 is replaced; use `resource.decoded_path()` for filesystem lookups
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    // Returns the raw query string (the part after `?`), if any
+    pub fn query(&self) -> Option<&str> {
+        self.resource.query.as_deref()
+    }
+
+    // Returns the HTTP version of the request
+    pub fn version(&self) -> &Version {
+        &self.version
+    }
+
+    // Returns the request body
+    pub fn body(&self) -> &[u8] {
+        &self.request_body
+    }
+
+    // Returns the trailer fields of a chunked request body
+    pub fn trailers(&self) -> &[(String, String)] {
+        &self.request_trailers
+    }
+
+    // Returns the address of the client that sent the request, if known
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    // Records the address of the client; set by the server when a connection is accepted
+    pub fn set_peer_addr(&mut self, peer_addr: SocketAddr) {
+        self.peer_addr = Some(peer_addr);
+    }
+
+    // Iterates over all headers as (name, value) pairs, in the order received
+    // Names keep the case the client used
+    pub fn headers(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.headers
+            .headers
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    // Looks up a header value by name (header names are case-insensitive)
+    // If the field is repeated, the first value is returned
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    // Returns every value of a possibly repeated header, in the order received
+    pub fn header_values<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.headers
+            .headers
+            .iter()
+            .filter(move |(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    // Combines the values of a repeated header into one comma separated list (RFC 9110 section 5.3)
+    pub(crate) fn combined_header(&self, name: &str) -> Option<String> {
+        let values: Vec<&str> = self.header_values(name).collect();
+        if values.is_empty() {
+            None
+        } else {
+            Some(values.join(", "))
+        }
+    }
+
+    // Parses a header listing values with optional weights, like Accept-Encoding or
+    // Accept-Charset, into (lowercase value, q) pairs; a missing or malformed q counts as 1
+    // Returns None if the header is absent, so callers can tell "anything" from "nothing"
+    pub fn weighted_values(&self, name: &str) -> Option<Vec<(String, f32)>> {
+        let combined = self.combined_header(name)?;
+        let values = combined
+            .split(',')
+            .filter_map(|item| {
+                let mut params = item.split(';');
+                let value = params.next()?.trim().to_ascii_lowercase();
+                if value.is_empty() {
+                    return None;
+                }
+                let q = params
+                    .filter_map(|param| param.split_once('='))
+                    .find(|(key, _)| key.trim().eq_ignore_ascii_case("q"))
+                    .and_then(|(_, q)| q.trim().parse::<f32>().ok())
+                    .filter(|q| (0.0..=1.0).contains(q))
+                    .unwrap_or(1.0);
+                Some((value, q))
+            })
+            .collect();
+        Some(values)
+    }
+
+    // Returns the first value of a query parameter (`?download=1` gives Some("1") for "download")
+    pub fn query_param(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    // Returns every value of a repeated query parameter, in order
+    pub fn query_values<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.query
+            .iter()
+            .filter(move |(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    // Returns all decoded query parameters in the order they appeared
+    pub fn query_params(&self) -> &[(String, String)] {
+        &self.query
+    }
+
+    // Returns the declared Content-Length of the request body, if any
+    pub fn content_length(&self) -> Option<usize> {
+        match self.body_length() {
+            Ok(BodyLength::Fixed(length)) => Some(length),
+            _ => None,
+        }
+    }
+
+    // Determines how the body is framed, rejecting ambiguous combinations of
+    // Content-Length and Transfer-Encoding that could be used for request smuggling
+    pub fn body_length(&self) -> io::Result<BodyLength> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+
+        if let Some(transfer_encoding) = self.combined_header("Transfer-Encoding") {
+            // HTTP/1.0 has no transfer codings, so the framing can't be trusted (RFC 9112 section 6.1)
+            if self.version == Version::V1_0 {
+                return Err(invalid("Transfer-Encoding in an HTTP/1.0 request"));
+            }
+            if self.header("Content-Length").is_some() {
+                return Err(invalid("both Content-Length and Transfer-Encoding present"));
+            }
+            let codings: Vec<String> = transfer_encoding
+                .split(',')
+                .map(|coding| coding.trim().to_ascii_lowercase())
+                .collect();
+            // chunked must be the final coding, and it's the only one we can decode
+            if codings.last().map(String::as_str) != Some("chunked") {
+                return Err(invalid("Transfer-Encoding does not end with chunked"));
+            }
+            if codings.len() > 1 {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!("unsupported transfer coding {}", transfer_encoding),
+                ));
+            }
+            return Ok(BodyLength::Chunked);
+        }
+
+        match self.combined_header("Content-Length") {
+            Some(content_length) => {
+                // Repeated Content-Length fields are only acceptable if they all agree
+                let mut lengths = content_length.split(',').map(str::trim);
+                let first = lengths.next().unwrap_or("");
+                if first.is_empty() || !first.bytes().all(|b| b.is_ascii_digit()) {
+                    return Err(invalid("invalid Content-Length"));
+                }
+                if lengths.any(|other| other != first) {
+                    return Err(invalid("conflicting Content-Length values"));
+                }
+                first
+                    .parse()
+                    .map(BodyLength::Fixed)
+                    .map_err(|_| invalid("invalid Content-Length"))
+            }
+            None => Ok(BodyLength::None),
+        }
+    }
+
     // Constructs a new HttpRequest from the raw request string
     pub fn new(request: &str) -> io::Result<HttpRequest> {
-        let method = Method::new(request); // Extract method (GET, POST, etc.)
-        let resource = Resource::new(request).unwrap_or_else(|| Resource {
-            path: "".to_string(),
-        }); // Extract requested resource path
-        let version = Version::new(request)
-            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.msg))?; // Extract version or return an error
-        let headers = HttpHeader::new(request).unwrap_or(HttpHeader {
-            headers: HashMap::new(),
-        }); // Extract headers
-        let request_body = request.split_once("\r\n\r\n").map_or(String::new(), |(_, body)| body.to_string()); // Extract body of the request
+        HttpRequest::parse(request.as_bytes())
+    }
 
+    // Constructs a new HttpRequest from the raw request bytes
+    // The head must be valid HTTP; the body is kept exactly as received
+    pub fn parse(request: &[u8]) -> io::Result<HttpRequest> {
+        // Clients may send stray empty lines before the request line (RFC 9112 section 2.2)
+        let mut request = request;
+        while let Some(rest) = request.strip_prefix(b"\r\n") {
+            request = rest;
+        }
+
+        // Split off the body at the empty line ending the head
+        let (head, request_body) = match find(request, b"\r\n\r\n") {
+            Some(pos) => (&request[..pos + 2], request[pos + 4..].to_vec()),
+            None => (request, Vec::new()),
+        };
+        let line_end = find(head, b"\r\n").ok_or(ParseError::MissingRequestLine)?;
+
+        let (method, target, resource, version) = parse_request_line(&head[..line_end])?;
+        let headers = HttpHeader::parse(&head[line_end + 2..])?; // Extract and validate headers
+
+        // HTTP/1.1 requests must carry exactly one Host header (RFC 9112 section 3.2); HTTP/1.0
+        // ones may leave it out
+        let hosts = headers.headers.iter().filter(|(name, _)| name.eq_ignore_ascii_case("Host")).count();
+        if hosts > 1 || (version == Version::V1_1 && hosts == 0) {
+            return Err(ParseError::InvalidHost.into());
+        }
+
+        let query = resource.query_pairs();
+        let path = format!("/{}", percent_decode_str(&resource.path).decode_utf8_lossy());
         Ok(HttpRequest {
             method,
+            target,
+            path,
             resource,
             version,
             headers,
+            query,
             request_body,
+            request_trailers: Vec::new(),
+            streamed_body: None,
+            peer_addr: None,
+            params: Vec::new(),
         })
     }
 }
 
-// Represents the headers of the HTTP request as a HashMap of key-value pairs
+// Parses and validates `method SP request-target SP HTTP-version` (RFC 9112 section 3)
+fn parse_request_line(line: &[u8]) -> Result<(Method, String, Resource, Version), ParseError> {
+    // The request line is plain ASCII; anything else (including tabs and stray CRs) is rejected
+    if !line.iter().all(|&b| b == b' ' || b.is_ascii_graphic()) {
+        return Err(ParseError::InvalidRequestLine);
+    }
+    let line = std::str::from_utf8(line).map_err(|_| ParseError::InvalidRequestLine)?;
+
+    // Exactly three parts separated by single spaces
+    let mut parts = line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version), None) => (method, target, version),
+        _ => return Err(ParseError::InvalidRequestLine),
+    };
+    if method.is_empty() || !method.bytes().all(is_tchar) {
+        return Err(ParseError::InvalidMethod);
+    }
+
+    let method = Method::new(method); // Extract method (GET, POST, etc.)
+    let resource = Resource::new(target, &method)?; // Extract requested resource
+    let version = Version::from_str(version).map_err(|_| {
+        // Distinguish a well-formed but unsupported version from garbage
+        let well_formed = version.len() == 8
+            && version.starts_with("HTTP/")
+            && version.as_bytes()[5].is_ascii_digit()
+            && version.as_bytes()[6] == b'.'
+            && version.as_bytes()[7].is_ascii_digit();
+        if well_formed {
+            ParseError::UnsupportedVersion(version.to_string())
+        } else {
+            ParseError::InvalidVersion
+        }
+    })?;
+    Ok((method, target.to_string(), resource, version))
+}
+
+// Returns the position of the first occurrence of `needle` in `haystack`
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+// Checks for a token character (RFC 9110 section 5.6.2), the only characters allowed in field names
+pub(crate) fn is_tchar(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+// Checks for a character allowed in a field value: visible ASCII, obs-text, space or tab
+fn is_field_value_char(b: u8) -> bool {
+    b.is_ascii_graphic() || b >= 0x80 || b == b' ' || b == b'\t'
+}
+
+// Reasons a request head can be rejected
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    MissingRequestLine,         // No CRLF terminated request line
+    InvalidRequestLine,         // Not exactly `method SP target SP version`
+    InvalidMethod,              // Method is not a token
+    InvalidTarget,              // Target doesn't match any of the four request-target forms
+    InvalidVersion,             // Version is not `HTTP/x.y`
+    UnsupportedVersion(String), // Well-formed version we don't speak
+    BareLineFeed,               // Line terminated by LF without CR
+    MissingColon,               // Header line without a colon
+    WhitespaceBeforeColon,      // `Name : value`, forbidden by RFC 9112 section 5.1
+    ObsFold,                    // Header value continued on the next line
+    InvalidHeaderName,          // Header name is not a token
+    InvalidHeaderValue,         // Control characters in a header value
+    InvalidHost,                // Missing or repeated Host header
+}
+
+impl ParseError {
+    // The status sent back for a request that failed to parse
+    pub fn status(&self) -> ResponseStatus {
+        match self {
+            ParseError::UnsupportedVersion(_) => ResponseStatus::HttpVersionNotSupported,
+            _ => ResponseStatus::BadRequest,
+        }
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseError::MissingRequestLine => write!(f, "missing request line"),
+            ParseError::InvalidRequestLine => write!(f, "malformed request line"),
+            ParseError::InvalidMethod => write!(f, "invalid method"),
+            ParseError::InvalidTarget => write!(f, "invalid request target"),
+            ParseError::InvalidVersion => write!(f, "invalid protocol version"),
+            ParseError::UnsupportedVersion(version) => write!(f, "unsupported protocol version {}", version),
+            ParseError::BareLineFeed => write!(f, "header line not terminated by CRLF"),
+            ParseError::MissingColon => write!(f, "header line without a colon"),
+            ParseError::WhitespaceBeforeColon => write!(f, "whitespace between header name and colon"),
+            ParseError::ObsFold => write!(f, "obsolete line folding in header"),
+            ParseError::InvalidHeaderName => write!(f, "invalid header name"),
+            ParseError::InvalidHeaderValue => write!(f, "invalid character in header value"),
+            ParseError::InvalidHost => write!(f, "missing or repeated Host header"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+// Parse errors surface as InvalidData I/O errors; the ParseError can be recovered with `get_ref`
+impl From<ParseError> for io::Error {
+    fn from(err: ParseError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+// Represents the headers of the HTTP request as a list of name-value pairs
+// Names keep their original case and repeated fields keep separate entries
 #[derive(Debug)]
 struct HttpHeader {
-    headers: HashMap<String, String>,
+    headers: Vec<(String, String)>,
 }
 
 impl HttpHeader {
-    // Parses headers from the raw request string and returns an HttpHeader struct
-    pub fn new(request: &str) -> Option<HttpHeader> {
+    // Parses the header lines following the request line, rejecting malformed fields
+    fn parse(header_bytes: &[u8]) -> Result<HttpHeader, ParseError> {
         let mut httpheader = HttpHeader {
-            headers: HashMap::new(),
+            headers: Vec::new(),
         };
-        let (_, header_str) = request.split_once("\r\n")?; // Extract headers portion from the request
-        for line in header_str.split_terminator("\r\n") {
+        for line in header_bytes.split(|&b| b == b'\n') {
+            let line = match line.strip_suffix(b"\r") {
+                Some(line) => line,
+                None if line.is_empty() => break, // Nothing after the last CRLF
+                None => return Err(ParseError::BareLineFeed),
+            };
             if line.is_empty() {
                 break;
             }
-            let (header, value) = line.split_once(":")?; // Split header lines into key-value pairs
-            httpheader.headers.insert(header.trim().to_string(), value.trim().to_string());
+            if line.starts_with(b" ") || line.starts_with(b"\t") {
+                return Err(ParseError::ObsFold); // Continuation lines are obsolete and ambiguous
+            }
+            let colon = line
+                .iter()
+                .position(|&b| b == b':')
+                .ok_or(ParseError::MissingColon)?; // Split header lines into key-value pairs
+            let (name, value) = (&line[..colon], &line[colon + 1..]);
+            if name.ends_with(b" ") || name.ends_with(b"\t") {
+                return Err(ParseError::WhitespaceBeforeColon);
+            }
+            if name.is_empty() || !name.iter().all(|&b| is_tchar(b)) {
+                return Err(ParseError::InvalidHeaderName);
+            }
+            if !value.iter().all(|&b| is_field_value_char(b)) {
+                return Err(ParseError::InvalidHeaderValue);
+            }
+            let name = String::from_utf8_lossy(name).to_string(); // Names are plain ASCII tokens
+            let value = String::from_utf8_lossy(value).trim_matches([' ', '\t']).to_string();
+            httpheader.headers.push((name, value));
         }
-        Some(httpheader)
+        Ok(httpheader)
     }
 }
 
-// Enum representing the HTTP version (1.1, 2.0)
-#[derive(Debug)]
+// Enum representing the HTTP version of a request line (1.0, 1.1)
+// HTTP/2 has no text request line, so it never shows up here
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Version {
+    V1_0,
     V1_1,
-    V2_0,
 }
 
 impl Display for Version {
     // Implements the Display trait for formatting the version as a string
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let msg = match self {
+            Version::V1_0 => "HTTP/1.0",
             Version::V1_1 => "HTTP/1.1",
-            Version::V2_0 => "HTTP/2",
         };
         write!(f, "{}", msg)
     }
@@ -94,75 +512,313 @@ impl Display for VersionError {
 }
 
 impl Version {
-    // Creates a new Version object from the raw request string
-    pub fn new(request: &str) -> Result<Self, VersionError> {
-        Version::from_str(request)
+    // Creates a new Version object from the version part of the request line (e.g. HTTP/1.1)
+    pub fn new(version: &str) -> Result<Self, VersionError> {
+        Version::from_str(version)
     }
 }
 
 impl FromStr for Version {
     type Err = VersionError;
 
-    // Parses the version from the request string
-    fn from_str(request: &str) -> Result<Self, Self::Err> {
-        let request_split = request.split_once("\r\n");
-        if let Some((method_line, _rest)) = request_split {
-            let splits = method_line.split_ascii_whitespace(); // Split the method line (e.g., GET / HTTP/1.1)
-            for split in splits {
-                if split == "HTTP/1.1" {
-                    return Ok(Version::V1_1);
-                } else if split == "HTTP/2" || split == "HTTP/2.0" {
-                    return Ok(Version::V2_0);
-                };
-            }
+    // Parses the version token; matching is exact and case-sensitive
+    fn from_str(version: &str) -> Result<Self, Self::Err> {
+        match version {
+            "HTTP/1.0" => Ok(Version::V1_0),
+            "HTTP/1.1" => Ok(Version::V1_1),
+            _ => Err(VersionError {
+                msg: format!("Unknown protocol version {}", version),
+            }),
         }
-        let invalid = format!("Unknown protocol version in {}", request);
-        let version_error = VersionError { msg: invalid };
-        Err(version_error)
     }
 }
 
-// Enum representing the HTTP method (GET, POST, or Uninitialized)
-#[derive(Debug)]
-enum Method {
+// Enum representing the HTTP method (GET, POST, etc.)
+// Methods without a variant of their own are kept verbatim in Other
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Method {
     Get,
+    Head,
     Post,
-    Uninitialized,
+    Put,
+    Patch,
+    Delete,
+    Options,
+    Connect,
+    Trace,
+    Other(String),
 }
 
 impl Method {
-    // Parses the method (GET, POST, etc.) from the raw request string
-    pub fn new(request: &str) -> Method {
-        if let Some((method_line, _)) = request.split_once("\r\n") {
-            if let Some((method, _)) = method_line.split_once(' ') {
-                return match method {
-                    "GET" => Method::Get,
-                    "POST" => Method::Post,
-                    _ => Method::Uninitialized,
-                };
-            }
+    // Parses the method token of the request line (methods are case-sensitive)
+    pub fn new(method: &str) -> Method {
+        match method {
+            "GET" => Method::Get,
+            "HEAD" => Method::Head,
+            "POST" => Method::Post,
+            "PUT" => Method::Put,
+            "PATCH" => Method::Patch,
+            "DELETE" => Method::Delete,
+            "OPTIONS" => Method::Options,
+            "CONNECT" => Method::Connect,
+            "TRACE" => Method::Trace,
+            other => Method::Other(other.to_string()),
         }
-        Method::Uninitialized
+    }
+
+    // Returns the method as it appears on the wire
+    pub fn as_str(&self) -> &str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Patch => "PATCH",
+            Method::Delete => "DELETE",
+            Method::Options => "OPTIONS",
+            Method::Connect => "CONNECT",
+            Method::Trace => "TRACE",
+            Method::Other(method) => method,
+        }
+    }
+}
+
+impl Display for Method {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
     }
 }
 
+// The four forms a request target can take (RFC 9112 section 3.2)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TargetForm {
+    Origin,    // /path?query, the usual form
+    Absolute,  // http://host/path?query, sent to proxies
+    Authority, // host:port, only for CONNECT
+    Asterisk,  // *, only for server-wide OPTIONS
+}
+
 // Struct representing the requested resource (e.g., a file path)
 #[derive(Debug)]
 pub struct Resource {
-    pub path: String,
+    pub path: String,              // Path without the leading slash, still percent-encoded
+    pub query: Option<String>,     // Raw query string after `?`, still percent-encoded
+    pub fragment: Option<String>,  // Fragment after `#`, if a client sent one
+    pub form: TargetForm,          // How the target was written in the request line
+    pub authority: Option<String>, // Host (and port) from absolute- or authority-form targets
 }
 
 impl Resource {
-    // Parses the resource path from the request string
-    pub fn new(request: &str) -> Option<Resource> {
-        request.split_once("\r\n").and_then(|(method_line, _)| {
-            method_line.split_once(' ').and_then(|(_, rest)| {
-                rest.split_once(' ').map(|(resource, _)| {
-                    Resource {
-                        path: resource.trim_start_matches('/').to_string(),
-                    }
-                })
+    // Parses the request target, checking that its form fits the method
+    pub fn new(target: &str, method: &Method) -> Result<Resource, ParseError> {
+        let resource = |target: &str, form, authority: Option<&str>| {
+            // Fragments are never sent by browsers, but drop one if present
+            let (target, fragment) = match target.split_once('#') {
+                Some((target, fragment)) => (target, Some(fragment.to_string())),
+                None => (target, None),
+            };
+            let (path, query) = match target.split_once('?') {
+                Some((path, query)) => (path, Some(query.to_string())),
+                None => (target, None),
+            };
+            Resource {
+                path: path.trim_start_matches('/').to_string(),
+                query,
+                fragment,
+                form,
+                authority: authority.map(str::to_string),
+            }
+        };
+
+        if *method == Method::Connect {
+            // authority-form: host:port with no scheme, path or userinfo
+            let (host, port) = target.rsplit_once(':').ok_or(ParseError::InvalidTarget)?;
+            if host.is_empty() || target.contains(['/', '@', '?', '#']) || port.is_empty() || !port.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(ParseError::InvalidTarget);
+            }
+            return Ok(resource("", TargetForm::Authority, Some(target)));
+        }
+        if target == "*" {
+            if *method != Method::Options {
+                return Err(ParseError::InvalidTarget);
+            }
+            return Ok(resource("", TargetForm::Asterisk, None));
+        }
+        if target.starts_with('/') {
+            return Ok(resource(target, TargetForm::Origin, None));
+        }
+
+        // absolute-form: scheme "://" authority [path]
+        let (scheme, rest) = target.split_once("://").ok_or(ParseError::InvalidTarget)?;
+        if !scheme.eq_ignore_ascii_case("http") && !scheme.eq_ignore_ascii_case("https") {
+            return Err(ParseError::InvalidTarget);
+        }
+        let path_start = rest.find(['/', '?']).unwrap_or(rest.len());
+        let (authority, path) = rest.split_at(path_start);
+        if authority.is_empty() || authority.contains('@') {
+            return Err(ParseError::InvalidTarget);
+        }
+        Ok(resource(path, TargetForm::Absolute, Some(authority)))
+    }
+
+    // Decodes the query string into name-value pairs, in order (`+` stands for a space)
+    pub fn query_pairs(&self) -> Vec<(String, String)> {
+        let decode = |part: &str| percent_decode_str(&part.replace('+', " ")).decode_utf8_lossy().into_owned();
+        self.query
+            .as_deref()
+            .unwrap_or("")
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| match pair.split_once('=') {
+                Some((name, value)) => (decode(name), decode(value)),
+                None => (decode(pair), String::new()),
             })
-        })
+            .collect()
+    }
+
+    // Returns the percent-decoded path as a filesystem path
+    // Decoding works on bytes, so non-UTF-8 file names (e.g. %FF) survive on Unix
+    pub fn decoded_path(&self) -> PathBuf {
+        let bytes: Vec<u8> = percent_decode_str(&self.path).collect();
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStringExt;
+            PathBuf::from(OsString::from_vec(bytes))
+        }
+        #[cfg(not(unix))]
+        {
+            PathBuf::from(String::from_utf8_lossy(&bytes).into_owned())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The ParseError a request head is rejected with
+    fn parse_error(request: &str) -> ParseError {
+        let err = HttpRequest::new(request).expect_err(request);
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        err.get_ref().and_then(|err| err.downcast_ref::<ParseError>()).cloned().expect(request)
+    }
+
+    #[test]
+    fn parses_a_request() {
+        let request = HttpRequest::new("\r\nGET /dir/a%20b.txt?x=1&y=a+b HTTP/1.1\r\nHost: example.com\r\nX-Test:  value \r\n\r\nbody").unwrap();
+        assert_eq!(*request.method(), Method::Get);
+        assert_eq!(request.path(), "/dir/a b.txt");
+        assert_eq!(request.header("x-test"), Some("value"));
+        assert_eq!(request.query_params(), [("x".to_string(), "1".to_string()), ("y".to_string(), "a b".to_string())]);
+        assert_eq!(request.request_body, b"body");
+    }
+
+    #[test]
+    fn accepts_http_1_0() {
+        let request = HttpRequest::new("GET /a HTTP/1.0\r\n\r\n").unwrap();
+        assert_eq!(*request.version(), Version::V1_0);
+        assert_eq!(request.path(), "/a");
+        // HTTP/1.0 has no chunked coding to frame a body with
+        let request = HttpRequest::new("POST /a HTTP/1.0\r\nTransfer-Encoding: chunked\r\n\r\n").unwrap();
+        assert_eq!(request.body_length().unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_malformed_request_lines() {
+        let cases = [
+            ("GET /", ParseError::MissingRequestLine),
+            ("GET  / HTTP/1.1\r\nHost: a\r\n\r\n", ParseError::InvalidRequestLine),
+            ("GET /\tx HTTP/1.1\r\nHost: a\r\n\r\n", ParseError::InvalidRequestLine),
+            ("GET / HTTP/1.1 x\r\nHost: a\r\n\r\n", ParseError::InvalidRequestLine),
+            ("G(T / HTTP/1.1\r\nHost: a\r\n\r\n", ParseError::InvalidMethod),
+            ("GET x HTTP/1.1\r\nHost: a\r\n\r\n", ParseError::InvalidTarget),
+            ("GET * HTTP/1.1\r\nHost: a\r\n\r\n", ParseError::InvalidTarget),
+            ("GET ftp://a/ HTTP/1.1\r\nHost: a\r\n\r\n", ParseError::InvalidTarget),
+            ("GET http://user@a/ HTTP/1.1\r\nHost: a\r\n\r\n", ParseError::InvalidTarget),
+            ("CONNECT a/b:80 HTTP/1.1\r\nHost: a\r\n\r\n", ParseError::InvalidTarget),
+            ("GET / http/1.1\r\nHost: a\r\n\r\n", ParseError::InvalidVersion),
+            ("GET / HTTP/2.0\r\nHost: a\r\n\r\n", ParseError::UnsupportedVersion("HTTP/2.0".to_string())),
+            ("GET / HTTP/2\r\nHost: a\r\n\r\n", ParseError::InvalidVersion),
+            ("GET / HTTP/1.1\r\n\r\n", ParseError::InvalidHost),
+            ("GET / HTTP/1.0\r\nHost: a\r\nHost: b\r\n\r\n", ParseError::InvalidHost),
+        ];
+        for (request, expected) in cases {
+            assert_eq!(parse_error(request), expected, "{:?}", request);
+        }
+        assert_eq!(ParseError::UnsupportedVersion(String::new()).status(), ResponseStatus::HttpVersionNotSupported);
+        assert_eq!(ParseError::InvalidMethod.status(), ResponseStatus::BadRequest);
+    }
+
+    #[test]
+    fn rejects_malformed_headers() {
+        let cases = [
+            ("GET / HTTP/1.1\r\nHost: a\nX: b\r\n\r\n", ParseError::BareLineFeed),
+            ("GET / HTTP/1.1\r\nHost: a\r\nX b\r\n\r\n", ParseError::MissingColon),
+            ("GET / HTTP/1.1\r\nHost : a\r\n\r\n", ParseError::WhitespaceBeforeColon),
+            ("GET / HTTP/1.1\r\nHost: a\r\nX: b\r\n c\r\n\r\n", ParseError::ObsFold),
+            ("GET / HTTP/1.1\r\nHost: a\r\nX(y): b\r\n\r\n", ParseError::InvalidHeaderName),
+            ("GET / HTTP/1.1\r\nHost: a\r\n: b\r\n\r\n", ParseError::InvalidHeaderName),
+            ("GET / HTTP/1.1\r\nHost: a\r\nX: b\rc\r\n\r\n", ParseError::InvalidHeaderValue),
+            ("GET / HTTP/1.1\r\nX: b\r\n\r\n", ParseError::InvalidHost),
+            ("GET / HTTP/1.1\r\nHost: a\r\nHost: b\r\n\r\n", ParseError::InvalidHost),
+        ];
+        for (request, expected) in cases {
+            assert_eq!(parse_error(request), expected, "{:?}", request);
+        }
+    }
+
+    // How the body of a request with the given headers is framed
+    fn body_length(headers: &str) -> io::Result<BodyLength> {
+        HttpRequest::new(&format!("POST / HTTP/1.1\r\nHost: a\r\n{}\r\n", headers))?.body_length()
+    }
+
+    #[test]
+    fn frames_bodies() {
+        assert_eq!(body_length("").unwrap(), BodyLength::None);
+        assert_eq!(body_length("Content-Length: 5\r\n").unwrap(), BodyLength::Fixed(5));
+        assert_eq!(body_length("Content-Length: 5\r\nContent-Length: 5\r\n").unwrap(), BodyLength::Fixed(5));
+        assert_eq!(body_length("Transfer-Encoding: Chunked\r\n").unwrap(), BodyLength::Chunked);
+    }
+
+    #[test]
+    fn rejects_ambiguous_body_lengths() {
+        let cases = [
+            "Content-Length: 5\r\nTransfer-Encoding: chunked\r\n",
+            "Transfer-Encoding: chunked\r\nContent-Length: 0\r\n",
+            "Content-Length: 5\r\nContent-Length: 6\r\n",
+            "Content-Length: 5, 6\r\n",
+            "Content-Length: +5\r\n",
+            "Content-Length: -1\r\n",
+            "Content-Length: 0x5\r\n",
+            "Content-Length: 99999999999999999999999\r\n",
+            "Transfer-Encoding: chunked, identity\r\n",
+            "Transfer-Encoding: identity\r\n",
+        ];
+        for headers in cases {
+            assert!(body_length(headers).is_err(), "{:?}", headers);
+        }
+        assert_eq!(
+            body_length("Transfer-Encoding: gzip, chunked\r\n").unwrap_err().kind(),
+            io::ErrorKind::Unsupported
+        );
+    }
+
+    #[test]
+    fn decodes_paths_without_dropping_traversal() {
+        // The file server rejects these components; decoding must not hide them
+        let request = HttpRequest::new("GET /%2Fetc%2Fpasswd HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+        assert!(request.resource.decoded_path().has_root());
+        let request = HttpRequest::new("GET /%2E%2E/etc HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+        assert!(request.resource.decoded_path().components().any(|c| c == std::path::Component::ParentDir));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn parses_bytes_that_are_not_utf8() {
+        use std::os::unix::ffi::OsStrExt;
+        let request = HttpRequest::parse(b"GET /a%FF.txt HTTP/1.1\r\nHost: a\r\nX-Name: caf\xe9\r\n\r\n\xff\x00").unwrap();
+        assert_eq!(request.resource.decoded_path().as_os_str().as_bytes(), b"a\xff.txt");
+        assert_eq!(request.header("X-Name"), Some("caf\u{fffd}"));
+        assert_eq!(request.request_body, b"\xff\x00"); // Bodies are kept byte for byte
     }
 }
@@ -1,13 +1,26 @@
-use super::request::HttpRequest;
+use super::request::{is_tchar, HttpRequest};
+use super::charset::{self, Utf8Reader};
+use super::cache_control::CacheControl;
+use super::chunked::ChunkedWriter;
+use super::compression;
+use super::conditional::{Precondition, Validators};
+use super::handler::FileServer;
+use super::mime;
+use encoding_rs::UTF_8;
 use super::request::Version;
-use infer;
-use percent_encoding::percent_decode_str; // Corrected import for URL decoding
-use std::fmt::Display;
-use std::fs::File;
-use std::io::{self, Read};
+use percent_encoding::{percent_decode_str, percent_encode, AsciiSet, NON_ALPHANUMERIC}; // Corrected import for URL decoding
+use std::borrow::Cow;
+use std::ffi::OsStr;
+use std::fmt::{Debug, Display};
+use std::fs::{self, File, Metadata};
+use std::path::{Component, Path};
+use std::io::{self, Read, Seek, Write};
+use std::time::{Duration, SystemTime};
 use url_escape::encode_component;
 use walkdir::WalkDir;
 
+const SNIFF_LENGTH: u64 = 8192; // Bytes read up front to detect a file's type
+
 // Represents an HTTP response
 #[derive(Debug)]
 pub struct HttpResponse {
@@ -15,73 +28,328 @@ pub struct HttpResponse {
     pub status: ResponseStatus,
     pub content_length: usize,
     pub accept_ranges: AcceptRanges,
-    pub response_body: Vec<u8>,
+    pub response_body: ResponseBody,
     pub current_path: String,
     pub content_type: String,
+    pub headers: Vec<(String, String)>, // Additional response headers (name, value)
+    pub cache_control: Option<CacheControl>, // Sent as Cache-Control, with Expires for a max-age
+    pub close_delimited: bool, // A body of unknown length is sent as is and ends with the connection, instead of chunked
 }
 
 impl HttpResponse {
+    // Creates an empty response with the given status and no body
+    pub fn with_status(status: ResponseStatus) -> HttpResponse {
+        HttpResponse {
+            version: Version::V1_1,
+            status,
+            content_length: 0,
+            accept_ranges: AcceptRanges::None,
+            response_body: ResponseBody::Bytes(Vec::new()),
+            current_path: String::new(),
+            content_type: String::new(),
+            headers: Vec::new(),
+            cache_control: None,
+            close_delimited: false,
+        }
+    }
+
+    // Adds an extra header to the response (sanitized when written, see write_head_to)
+    pub fn add_header(&mut self, name: &str, value: impl Display) {
+        self.headers.push((name.to_string(), value.to_string()));
+    }
+
+    // Replaces the response body, updating the content length and type
+    pub fn set_body(&mut self, content_type: &str, body: Vec<u8>) {
+        self.content_type = content_type.to_string();
+        self.content_length = body.len();
+        self.response_body = ResponseBody::Bytes(body);
+    }
+
+    // Whether the body length is unknown up front and must be sent chunked
+    pub fn is_chunked(&self) -> bool {
+        matches!(self.response_body, ResponseBody::Stream(_)) && !self.close_delimited
+    }
+
+    // Starts building a custom response (200 OK with an empty body by default)
+    pub fn builder() -> ResponseBuilder {
+        ResponseBuilder {
+            response: HttpResponse::with_status(ResponseStatus::OK),
+        }
+    }
+
+    // Whether the status allows a body; 1xx, 204 and 304 responses never carry one
+    pub(crate) fn allows_body(&self) -> bool {
+        !matches!(self.status, ResponseStatus::NoContent | ResponseStatus::NotModified)
+    }
+
+    // Serializes the status line and headers, including the framing headers for the body
+    // Fields can't break out of their line: names that aren't tokens are left out, and
+    // control characters such as CR and LF are removed from values
+    pub fn write_head_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        let mut head = format!("{} {}\r\n", self.version, self.status);
+        if self.allows_body() {
+            if self.is_chunked() {
+                head.push_str("Transfer-Encoding: chunked\r\n"); // Length unknown, send the body in chunks
+            } else if !matches!(self.response_body, ResponseBody::Stream(_)) {
+                head.push_str(&format!("Content-Length: {}\r\n", self.content_length));
+            }
+        }
+        if !self.content_type.is_empty() {
+            head.push_str(&format!("Content-Type: {}\r\n", field_value(&self.content_type)));
+        }
+        if let Some(cache_control) = &self.cache_control {
+            head.push_str(&format!("Cache-Control: {}\r\n", field_value(&cache_control.value)));
+            // For HTTP/1.0 caches; relative to when the response is sent
+            if let Some(max_age) = cache_control.max_age {
+                let expires = SystemTime::now() + Duration::from_secs(max_age);
+                head.push_str(&format!("Expires: {}\r\n", httpdate::fmt_http_date(expires)));
+            }
+        }
+        for (name, value) in &self.headers {
+            if name.is_empty() || !name.bytes().all(is_tchar) {
+                continue;
+            }
+            head.push_str(&format!("{}: {}\r\n", name, field_value(value)));
+        }
+        head.push_str("\r\n");
+        writer.write_all(head.as_bytes())
+    }
+
+    // Serializes the whole response (head and body) to the writer
+    pub fn write_to<W: Write>(self, mut writer: W) -> io::Result<()> {
+        self.write_head_to(&mut writer)?;
+        if self.allows_body() {
+            match self.response_body {
+                ResponseBody::Bytes(bytes) => writer.write_all(&bytes)?,
+                ResponseBody::Reader(reader) => {
+                    // Never send more than the advertised length
+                    let copied = io::copy(&mut reader.take(self.content_length as u64), &mut writer)?;
+                    if copied < self.content_length as u64 {
+                        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "response body shorter than its length"));
+                    }
+                }
+                ResponseBody::Stream(mut reader) if self.close_delimited => {
+                    io::copy(&mut reader, &mut writer)?;
+                }
+                ResponseBody::Stream(mut reader) => {
+                    let mut chunked = ChunkedWriter::new(&mut writer);
+                    io::copy(&mut reader, &mut chunked)?;
+                    chunked.finish()?;
+                }
+            }
+        }
+        writer.flush() // Ensure all data is written to the client
+    }
+
     // Creates a new HTTP response based on the provided HTTP request
+    // Files are looked up relative to the current directory
     pub fn new(request: &HttpRequest) -> io::Result<HttpResponse> {
+        HttpResponse::from_root(request, &std::env::current_dir()?)
+    }
+
+    // Creates a new HTTP response serving the requested file or directory below `root`
+    pub fn from_root(request: &HttpRequest, root: &Path) -> io::Result<HttpResponse> {
+        HttpResponse::from_file_server(request, &FileServer::new(root))
+    }
+
+    // Creates a new HTTP response serving the requested file or directory with the file
+    // server's root and options
+    pub fn from_file_server(request: &HttpRequest, server: &FileServer) -> io::Result<HttpResponse> {
+        let root = server.root();
         let version = Version::V1_1; // HTTP version 1.1
         let mut status = ResponseStatus::NotFound; // Default status
         let mut content_length = 0; // Default content length
         let mut accept_ranges = AcceptRanges::None; // Default Accept-Ranges header
         let mut content_type = String::new(); // Default content type
-        let mut response_body = Vec::new(); // Default response body
-        let current_path = request.resource.path.clone(); // Current request path
+        let mut response_body = ResponseBody::Bytes(Vec::new()); // Default response body
+        let current_path = request.resource.path.clone(); // Current request path (without the query)
+        let mut headers = Vec::new(); // Extra headers
+        let mut cache_control = None; // Cache-Control policy
+        let mut vary = Vec::new(); // Request headers the response depends on
 
-        let server_root_path = std::env::current_dir()?; // Root directory of the server
+        let server_root_path = root.to_path_buf(); // Root directory of the server
         // Decode the resource path from URL encoding
-        let resource = percent_decode_str(&request.resource.path).decode_utf8_lossy();
-        let new_path = server_root_path.join(&*resource); // Construct the full path to the resource
+        let resource = percent_decode_str(&request.resource.path).decode_utf8_lossy(); // Lossy, for display only
+        let decoded_path = request.resource.decoded_path();
+        // An absolute path (e.g. from `%2F`) would replace the root when joined, and `..` climbs out of it
+        let escapes = decoded_path.components().any(|component| {
+            matches!(component, Component::RootDir | Component::Prefix(_) | Component::ParentDir)
+        });
+        let mut new_path = server_root_path.join(&decoded_path); // Construct the full path to the resource
 
-        // Check if the requested path is within the server's root directory
-        let rootcwd_len = server_root_path.canonicalize()?.components().count();
-        let resource_len = new_path.canonicalize()?.components().count();
+        // Check if the requested path is within the server's root directory, after following symlinks
+        let canonical_root = server.canonical_root()?;
+        // A path that can't be canonicalized doesn't exist; it falls through to the 404 below
+        let canonical_path = new_path.canonicalize().ok();
+        let outside = canonical_path
+            .as_ref()
+            .is_some_and(|path| !path.starts_with(canonical_root));
 
-        if rootcwd_len > resource_len {
+        if escapes || outside {
             status = ResponseStatus::NotFound; // Path is outside the root directory
             return Ok(HttpResponse {
                 version,
                 status,
                 content_length: 0,
                 accept_ranges,
-                response_body: Vec::new(),
+                response_body: ResponseBody::Bytes(Vec::new()),
                 current_path,
-                content_type: "text/plain".to_string(),
+                content_type: "text/plain; charset=utf-8".to_string(),
+                headers: Vec::new(),
+                cache_control: None,
+                close_delimited: false,
             });
         }
 
-        let base_url = "http://localhost:5500"; // Base URL for directory listing
+        // Directories with an index file are served by it instead of a listing
+        let mut index_name = None; // Name of the index file served, if any
+        if new_path.is_dir() {
+            // An index file linking out of the root is ignored like a missing one
+            let index = server
+                .directory_options()
+                .index_file(&new_path)
+                .filter(|index| index.canonicalize().is_ok_and(|path| path.starts_with(canonical_root)));
+            if let Some(index) = index {
+                // Relative links in the page only resolve below the directory with a trailing slash
+                if !request.resource.path.is_empty() && !request.resource.path.ends_with('/') {
+                    // Rebuilt from the path's components, so a leading `\` or `//` can't turn it into another host
+                    let segments: Vec<String> = decoded_path
+                        .components()
+                        .filter_map(|component| match component {
+                            Component::Normal(name) => Some(encode_file_name(name)),
+                            _ => None,
+                        })
+                        .collect();
+                    let location = match &request.resource.query {
+                        Some(query) => format!("/{}/?{}", segments.join("/"), query),
+                        None => format!("/{}/", segments.join("/")),
+                    };
+                    return Ok(HttpResponse::builder()
+                        .status(ResponseStatus::MovedPermanently)
+                        .redirect(&location)
+                        .build());
+                }
+                index_name = index.file_name().map(|name| name.to_string_lossy().into_owned());
+                new_path = index;
+            }
+        }
 
         if new_path.exists() {
             if new_path.is_file() {
-                // Handle file response
-                let mut file = File::open(&new_path)?;
-                let mut content = Vec::new();
-                file.read_to_end(&mut content)?;
+                // Handle file response, from the cache or the file itself
+                let file = open_file(server, &new_path)?;
+                let (metadata, mut validators, reader) = (file.metadata, file.validators, file.reader);
 
-                content_length = content.len();
+                content_length = metadata.len() as usize;
                 status = ResponseStatus::OK; // File found
                 accept_ranges = AcceptRanges::Bytes;
 
-                if let Some(file_type) = infer::get(&content) {
-                    content_type = file_type.mime_type().to_string(); // Detect MIME type
-                } else if matches!(
-                    new_path.extension().and_then(|ext| ext.to_str()),
-                    Some("txt" | "rs" | "lock" | "png" | "json" | "TAG" | "toml" | "md")
-                ) {
-                    content_type = "text/plain".to_string(); // Default to plain text for known extensions
-                } else {
-                    content_type = "application/octet-stream".to_string(); // Fallback for unknown file types
+                content_type = file.content_type; // By extension, then content
+
+                // `?download` asks the browser to save the file instead of displaying it
+                if request.query_param("download").is_some() {
+                    let file_name = new_path.file_name().map(encode_file_name).unwrap_or_default();
+                    headers.push((
+                        "Content-Disposition".to_string(),
+                        format!("attachment; filename*=UTF-8''{}", file_name),
+                    ));
+                }
+
+                let legacy = charset::declared(&content_type).filter(|&encoding| encoding != UTF_8);
+                if legacy.is_some() {
+                    vary.push("Accept-Charset"); // The body may be decoded, see below
+                }
+                let mut transcoded = false;
+                match legacy {
+                    // Decoded to UTF-8 while sent, so the length is unknown and ranges don't apply
+                    Some(encoding) if charset::wants_utf8(request, encoding) => {
+                        content_type = mime::set_charset(&content_type, UTF_8);
+                        accept_ranges = AcceptRanges::None;
+                        content_length = 0;
+                        response_body = ResponseBody::Stream(Box::new(Utf8Reader::new(reader, encoding)));
+                        validators.variant("utf-8"); // Not the same bytes as the file
+                        transcoded = true;
+                    }
+                    _ => response_body = ResponseBody::Reader(reader),
+                }
+
+                // Files compressed ahead of time hold the file as stored, not decoded text
+                let compression = server.compression_options();
+                let sidecars = if transcoded { Vec::new() } else { compression.sidecars(&new_path, &metadata, canonical_root) };
+                let compress = compression.applies(&content_type, Some(metadata.len()));
+                if compress || !sidecars.is_empty() {
+                    vary.push("Accept-Encoding");
+                }
+                let mut coding = None; // Content-Encoding, sent with the body only
+                let available: Vec<_> = sidecars.iter().map(|sidecar| sidecar.coding).collect();
+                let sidecar = compression::best_coding(request, &available)
+                    .and_then(|best| sidecars.into_iter().find(|sidecar| sidecar.coding == best));
+                if let Some(sidecar) = sidecar {
+                    // Sent as stored, with its own length and validators
+                    let mut compressed = File::open(&sidecar.path)?;
+                    let compressed_metadata = compressed.metadata()?;
+                    validators = Validators::for_file(&mut compressed, &compressed_metadata, server.etag_kind())?;
+                    validators.variant(sidecar.coding.token()); // Like the ETag when compressed on the fly
+                    compressed.rewind()?;
+                    content_length = compressed_metadata.len() as usize;
+                    response_body = ResponseBody::Reader(Box::new(compressed));
+                    coding = Some(sidecar.coding);
+                } else if compress {
+                    // Like the Compression middleware, but the ETag variant has to be known
+                    // before the preconditions are evaluated
+                    coding = compression.negotiate(request);
+                    if let Some(coding) = coding {
+                        // Compressed while sent, so like decoded text it has no length or ranges
+                        response_body = ResponseBody::Stream(coding.encode(response_body.into_reader())?);
+                        accept_ranges = AcceptRanges::None;
+                        content_length = 0;
+                        validators.variant(coding.token());
+                    }
+                }
+                if !vary.is_empty() {
+                    headers.push(("Vary".to_string(), vary.join(", ")));
+                }
+
+                let mut decoded_path = percent_decode_str(&request.resource.path).decode_utf8_lossy().into_owned();
+                if let Some(index_name) = &index_name {
+                    decoded_path.push_str(index_name); // Policies match the index file's own path
                 }
+                cache_control = server.cache_policies().for_file(&decoded_path, &content_type);
 
-                response_body = content;
+                // Conditional requests are answered from the validators, without the body
+                headers.extend(validators.headers());
+                match validators.evaluate(request) {
+                    Precondition::Passed => {}
+                    Precondition::NotModified => {
+                        let mut not_modified = HttpResponse::with_status(ResponseStatus::NotModified);
+                        not_modified.headers = headers; // Same validators, caching and Vary as a 200
+                        not_modified.cache_control = cache_control;
+                        return Ok(not_modified);
+                    }
+                    Precondition::Failed => return Ok(HttpResponse::with_status(ResponseStatus::PreconditionFailed)),
+                }
+                if let Some(coding) = coding {
+                    headers.push(("Content-Encoding".to_string(), coding.token().to_string()));
+                }
             } else if new_path.is_dir() {
+                // Directories without an index file may not be listed (e.g. on production sites)
+                // Rules match where the directory really is, however the request spelled it
+                let relative = canonical_path
+                    .as_deref()
+                    .and_then(|path| path.strip_prefix(canonical_root).ok())
+                    .unwrap_or(Path::new(""));
+                if !server.directory_options().lists(relative) {
+                    let forbidden_body = "<html><body><h1>403 Forbidden</h1><p>Directory listings are disabled on this server.</p></body></html>";
+                    return Ok(HttpResponse::builder()
+                        .status(ResponseStatus::Forbidden)
+                        .html(forbidden_body)
+                        .build());
+                }
+
                 // Handle directory response
                 status = ResponseStatus::OK;
-                content_type = "text/html".to_string();
+                content_type = "text/html; charset=utf-8".to_string();
+                cache_control = server.cache_policies().for_listing();
 
                 let mut begin_html = r#"
                 <!DOCTYPE html> 
@@ -96,8 +364,8 @@ impl HttpResponse {
 
                 // Generate link to go up one directory
                 let one_step_back_path = {
-                    let components: Vec<&str> = resource.split('/').collect();
-                    if components.len() >= 1 {
+                    let components: Vec<&str> = resource.trim_end_matches('/').split('/').collect();
+                    if !components.is_empty() {
                         components[..components.len() - 1].join("/")
                     } else {
                         String::from("/")
@@ -105,60 +373,52 @@ impl HttpResponse {
                 };
 
                 let go_back_link = format!(
-                    "<a href=\"{}/{}\">Go back up a directory</a>",
-                    base_url,
+                    "<a href=\"/{}\">Go back up a directory</a>", // Root-relative, so it works on any address
                     encode_component(&one_step_back_path)
                 );
 
                 // Add current directory path and go back link to HTML
                 let header = format!(
                     "<h1>Currently in {}</h1>{}<br><hr>",
-                    decoded_path, go_back_link
+                    escape_html(&decoded_path), go_back_link
                 );
                 begin_html.push_str(&header);
 
-                // List files and directories within the current directory
-                for entry in WalkDir::new(new_path).max_depth(1).min_depth(1) {
-                    let entry = entry.unwrap();
-                    let file_name = entry.file_name().to_string_lossy().to_string();
-                    let file_url = encode_component(&file_name);
-
-                    if entry.path().is_dir() {
-                        begin_html.push_str(&format!(
-                            "<div><a href=\"{}/{}\">{}/</a></div>",
-                            base_url,
-                            resource.to_string() + "/" + &file_url,
-                            file_name
-                        ));
-                    } else {
-                        begin_html.push_str(&format!(
-                            "<div><a href=\"{}/{}\">{}</a></div>",
-                            base_url,
-                            resource.to_string() + "/" + &file_url,
-                            file_name
-                        ));
-                    }
-                }
-
-                let end_html = r#"
-                </body>
-                </html>"#
-                    .to_string();
-
-                let full_html = begin_html + &end_html;
-                response_body = full_html.into_bytes();
-                content_length = response_body.len();
+                // Entries are rendered while the body is sent, so the listing length is unknown
+                // and the response goes out with chunked transfer encoding
+                let mut listing = HttpResponse {
+                    version,
+                    status,
+                    content_length: 0,
+                    accept_ranges,
+                    response_body: ResponseBody::Stream(Box::new(DirectoryListing {
+                        entries: WalkDir::new(new_path).max_depth(1).min_depth(1).into_iter(),
+                        // Still percent-encoded, so it can go straight into links
+                        resource: request.resource.path.trim_end_matches('/').to_string(),
+                        buffer: begin_html.into_bytes(),
+                        position: 0,
+                        finished: false,
+                    })),
+                    current_path,
+                    content_type,
+                    headers,
+                    cache_control,
+                    close_delimited: false,
+                };
+                // Compressed like any other response (see the Compression middleware)
+                server.compression_options().compress(request, &mut listing)?;
+                return Ok(listing);
             }
         } else {
             // Return 404 Not Found if the file or directory doesn't exist
             status = ResponseStatus::NotFound;
-            content_type = "text/html".to_string();
+            content_type = "text/html; charset=utf-8".to_string();
             let not_found_body = format!(
                 "<html><body><h1>404 Not Found</h1><p>The requested resource <strong>{}</strong> was not found on this server.</p></body></html>",
-                request.resource.path
+                escape_html(&request.resource.path)
             );
-            response_body = not_found_body.into_bytes();
-            content_length = response_body.len();
+            content_length = not_found_body.len();
+            response_body = ResponseBody::Bytes(not_found_body.into_bytes());
         }
 
         Ok(HttpResponse {
@@ -169,22 +429,381 @@ impl HttpResponse {
             response_body,
             current_path,
             content_type,
+            headers,
+            cache_control,
+            close_delimited: false,
         })
     }
 }
 
+// Removes control characters (except tab) from a header value, so it can't end its line
+fn field_value(value: &str) -> Cow<'_, str> {
+    let allowed = |c: char| !c.is_ascii_control() || c == '\t';
+    if value.chars().all(allowed) {
+        Cow::Borrowed(value)
+    } else {
+        Cow::Owned(value.chars().filter(|&c| allowed(c)).collect())
+    }
+}
+
+// A file to send, with what is known about it up front
+struct FileBody {
+    metadata: Metadata,
+    validators: Validators,
+    content_type: String,
+    reader: Box<dyn Read + Send>,
+}
+
+// Opens a file to send: small files come from the file cache while they are unchanged,
+// others are opened, and their first bytes sniffed for the type before streaming the rest
+fn open_file(server: &FileServer, path: &Path) -> io::Result<FileBody> {
+    let file_cache = server.cached_files();
+    if let Some(file_cache) = file_cache {
+        let metadata = fs::metadata(path)?;
+        if let Some(cached) = file_cache.get(path, &metadata) {
+            return Ok(FileBody {
+                metadata,
+                validators: cached.validators,
+                content_type: cached.content_type,
+                reader: Box::new(io::Cursor::new(cached.content)),
+            });
+        }
+    }
+
+    let mut file = File::open(path)?;
+    let metadata = file.metadata()?;
+    let validators = Validators::for_file(&mut file, &metadata, server.etag_kind())?;
+    file.rewind()?; // Content hashes read the whole file
+
+    if let Some(file_cache) = file_cache.filter(|file_cache| file_cache.admits(metadata.len())) {
+        let mut content = Vec::with_capacity(metadata.len() as usize);
+        (&mut file).take(metadata.len()).read_to_end(&mut content)?;
+        let sniffed = &content[..content.len().min(SNIFF_LENGTH as usize)];
+        let content_type = server.content_types().content_type(path, sniffed); // By extension, then content
+        if content.len() as u64 != metadata.len() {
+            // Changed while read; send what there is (it falls short of the length) and don't keep it
+            return Ok(FileBody {
+                metadata,
+                validators,
+                content_type,
+                reader: Box::new(io::Cursor::new(content)),
+            });
+        }
+        let cached = file_cache.insert(path, &metadata, content, content_type, validators);
+        return Ok(FileBody {
+            metadata,
+            validators: cached.validators,
+            content_type: cached.content_type,
+            reader: Box::new(io::Cursor::new(cached.content)),
+        });
+    }
+
+    let mut sniffed = Vec::new();
+    (&mut file).take(SNIFF_LENGTH).read_to_end(&mut sniffed)?;
+    let content_type = server.content_types().content_type(path, &sniffed); // By extension, then content
+    Ok(FileBody {
+        metadata,
+        validators,
+        content_type,
+        reader: Box::new(io::Cursor::new(sniffed).chain(file)),
+    })
+}
+
+// The body of a response: either fully in memory or produced while it is sent
+pub enum ResponseBody {
+    Bytes(Vec<u8>),                 // Known length, sent with Content-Length
+    Reader(Box<dyn Read + Send>),   // Known length (the response's content_length), streamed
+    Stream(Box<dyn Read + Send>),   // Unknown length, sent with chunked transfer encoding
+}
+
+impl ResponseBody {
+    // Turns the body into a reader of its bytes, whatever its kind
+    pub fn into_reader(self) -> Box<dyn Read + Send> {
+        match self {
+            ResponseBody::Bytes(bytes) => Box::new(io::Cursor::new(bytes)),
+            ResponseBody::Reader(reader) | ResponseBody::Stream(reader) => reader,
+        }
+    }
+}
+
+impl Debug for ResponseBody {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResponseBody::Bytes(bytes) => write!(f, "Bytes({} bytes)", bytes.len()),
+            ResponseBody::Reader(_) => write!(f, "Reader"),
+            ResponseBody::Stream(_) => write!(f, "Stream"),
+        }
+    }
+}
+
+// Builds custom responses, e.g.
+// HttpResponse::builder().status(ResponseStatus::Created).json(r#"{"id":1}"#).build()
+pub struct ResponseBuilder {
+    response: HttpResponse,
+}
+
+impl ResponseBuilder {
+    // Sets the status code
+    pub fn status(mut self, status: ResponseStatus) -> ResponseBuilder {
+        self.response.status = status;
+        self
+    }
+
+    // Adds a header; Content-Type is kept separately so it is never sent twice
+    pub fn header(mut self, name: &str, value: impl Display) -> ResponseBuilder {
+        if name.eq_ignore_ascii_case("Content-Type") {
+            self.response.content_type = value.to_string();
+        } else {
+            self.response.add_header(name, value);
+        }
+        self
+    }
+
+    // Sets the Content-Type
+    pub fn content_type(mut self, content_type: &str) -> ResponseBuilder {
+        self.response.content_type = content_type.to_string();
+        self
+    }
+
+    // Sets an in-memory body
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> ResponseBuilder {
+        let body = body.into();
+        self.response.content_length = body.len();
+        self.response.response_body = ResponseBody::Bytes(body);
+        self
+    }
+
+    // Streams the body from a reader; without a length it is sent chunked
+    pub fn reader(mut self, reader: impl Read + Send + 'static, length: Option<usize>) -> ResponseBuilder {
+        match length {
+            Some(length) => {
+                self.response.content_length = length;
+                self.response.response_body = ResponseBody::Reader(Box::new(reader));
+            }
+            None => {
+                self.response.content_length = 0;
+                self.response.response_body = ResponseBody::Stream(Box::new(reader));
+            }
+        }
+        self
+    }
+
+    // Streams the body from an open file, using its size as the length
+    pub fn file(self, file: File) -> io::Result<ResponseBuilder> {
+        let length = file.metadata()?.len() as usize;
+        Ok(self.reader(file, Some(length)))
+    }
+
+    // Sets a JSON body from an already serialized document
+    pub fn json(self, json: impl Into<String>) -> ResponseBuilder {
+        self.content_type("application/json").body(json.into())
+    }
+
+    // Sets an HTML body
+    pub fn html(self, html: impl Into<String>) -> ResponseBuilder {
+        self.content_type("text/html; charset=utf-8").body(html.into())
+    }
+
+    // Sets a plain text body
+    pub fn text(self, text: impl Into<String>) -> ResponseBuilder {
+        self.content_type("text/plain; charset=utf-8").body(text.into())
+    }
+
+    // Redirects to another location (302 Found unless a redirect status was already set)
+    pub fn redirect(mut self, location: &str) -> ResponseBuilder {
+        if !self.response.status.is_redirect() {
+            self.response.status = ResponseStatus::Found;
+        }
+        self.header("Location", location)
+    }
+
+    // Finishes the response
+    pub fn build(self) -> HttpResponse {
+        self.response
+    }
+}
+
+// Renders a directory listing entry by entry as it is read
+struct DirectoryListing {
+    entries: walkdir::IntoIter,
+    resource: String, // Percent-encoded path of the directory, without a leading slash
+    buffer: Vec<u8>, // Rendered HTML not yet handed out
+    position: usize, // How much of the buffer has been read
+    finished: bool,  // Whether the closing tags have been rendered
+}
+
+impl DirectoryListing {
+    // Renders the next entry (or the closing tags) into the buffer
+    // Returns false once everything has been rendered
+    fn render_next(&mut self) -> bool {
+        self.buffer.clear();
+        self.position = 0;
+        for entry in self.entries.by_ref() {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(_) => continue, // Skip entries we can't read
+            };
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            let file_url = encode_file_name(entry.file_name());
+            let suffix = if entry.path().is_dir() { "/" } else { "" }; // Directories get a trailing slash
+            // Root-relative link; an empty resource would otherwise give a protocol-relative `//name`
+            let href = if self.resource.is_empty() {
+                format!("/{}", file_url)
+            } else {
+                format!("/{}/{}", self.resource, file_url)
+            };
+
+            self.buffer.extend_from_slice(
+                format!(
+                    "<div><a href=\"{}\">{}{}</a></div>",
+                    escape_html(&href),
+                    escape_html(&file_name),
+                    suffix
+                )
+                .as_bytes(),
+            );
+            return true;
+        }
+        if self.finished {
+            return false;
+        }
+        self.finished = true;
+        let end_html = r#"
+                </body>
+                </html>"#;
+        self.buffer.extend_from_slice(end_html.as_bytes());
+        true
+    }
+}
+
+// Escapes text for HTML content and quoted attribute values, so names and paths can't add markup
+fn escape_html(text: &str) -> Cow<'_, str> {
+    if !text.contains(['&', '<', '>', '"', '\'']) {
+        return Cow::Borrowed(text);
+    }
+    let mut escaped = String::with_capacity(text.len() + 16);
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+// Characters left as-is in links to listed files (the unreserved set of RFC 3986)
+const FILE_NAME_ESCAPE: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'_').remove(b'.').remove(b'~');
+
+// Percent-encodes a file name for a link, byte by byte so non-UTF-8 names on Unix stay reachable
+fn encode_file_name(name: &OsStr) -> String {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        percent_encode(name.as_bytes(), FILE_NAME_ESCAPE).to_string()
+    }
+    #[cfg(not(unix))]
+    {
+        percent_encode(name.to_string_lossy().as_bytes(), FILE_NAME_ESCAPE).to_string()
+    }
+}
+
+impl Read for DirectoryListing {
+    // Fills as much of `buf` as there are entries for, so a chunked listing isn't sent one entry per chunk
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut read = 0;
+        while read < buf.len() {
+            if self.position == self.buffer.len() && !self.render_next() {
+                break;
+            }
+            let pending = &self.buffer[self.position..];
+            let len = pending.len().min(buf.len() - read);
+            buf[read..read + len].copy_from_slice(&pending[..len]);
+            self.position += len;
+            read += len;
+        }
+        Ok(read)
+    }
+}
+
 // Represents HTTP response status codes
-#[derive(Debug)]
-enum ResponseStatus {
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResponseStatus {
     OK = 200,
+    Created = 201,
+    NoContent = 204,
+    MovedPermanently = 301,
+    Found = 302,
+    SeeOther = 303,
+    NotModified = 304,
+    TemporaryRedirect = 307,
+    PermanentRedirect = 308,
+    BadRequest = 400,
+    Unauthorized = 401,
+    Forbidden = 403,
     NotFound = 404,
+    MethodNotAllowed = 405,
+    Conflict = 409,
+    Gone = 410,
+    PreconditionFailed = 412,
+    PayloadTooLarge = 413,
+    UnsupportedMediaType = 415,
+    ExpectationFailed = 417,
+    TooManyRequests = 429,
+    InternalServerError = 500,
+    NotImplemented = 501,
+    HttpVersionNotSupported = 505,
+}
+
+impl ResponseStatus {
+    // Returns the numeric status code
+    pub fn code(&self) -> u16 {
+        *self as u16
+    }
+
+    // Whether this is a 3xx redirect that carries a Location
+    pub fn is_redirect(&self) -> bool {
+        matches!(
+            self,
+            ResponseStatus::MovedPermanently
+                | ResponseStatus::Found
+                | ResponseStatus::SeeOther
+                | ResponseStatus::TemporaryRedirect
+                | ResponseStatus::PermanentRedirect
+        )
+    }
 }
 
 impl Display for ResponseStatus {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let msg = match self {
             ResponseStatus::OK => "200 OK",
+            ResponseStatus::Created => "201 Created",
+            ResponseStatus::NoContent => "204 No Content",
+            ResponseStatus::MovedPermanently => "301 Moved Permanently",
+            ResponseStatus::Found => "302 Found",
+            ResponseStatus::SeeOther => "303 See Other",
+            ResponseStatus::NotModified => "304 Not Modified",
+            ResponseStatus::TemporaryRedirect => "307 Temporary Redirect",
+            ResponseStatus::PermanentRedirect => "308 Permanent Redirect",
+            ResponseStatus::BadRequest => "400 Bad Request",
+            ResponseStatus::Unauthorized => "401 Unauthorized",
+            ResponseStatus::Forbidden => "403 Forbidden",
             ResponseStatus::NotFound => "404 Not Found",
+            ResponseStatus::MethodNotAllowed => "405 Method Not Allowed",
+            ResponseStatus::Conflict => "409 Conflict",
+            ResponseStatus::Gone => "410 Gone",
+            ResponseStatus::PreconditionFailed => "412 Precondition Failed",
+            ResponseStatus::PayloadTooLarge => "413 Payload Too Large",
+            ResponseStatus::UnsupportedMediaType => "415 Unsupported Media Type",
+            ResponseStatus::ExpectationFailed => "417 Expectation Failed",
+            ResponseStatus::TooManyRequests => "429 Too Many Requests",
+            ResponseStatus::InternalServerError => "500 Internal Server Error",
+            ResponseStatus::NotImplemented => "501 Not Implemented",
+            ResponseStatus::HttpVersionNotSupported => "505 HTTP Version Not Supported",
         };
         write!(f, "{}", msg)
     }
@@ -192,7 +811,7 @@ impl Display for ResponseStatus {
 
 // Represents Accept-Ranges header values
 #[derive(Debug)]
-enum AcceptRanges {
+pub enum AcceptRanges {
     Bytes,
     None,
 }
@@ -206,3 +825,214 @@ impl Display for AcceptRanges {
         write!(f, "{}", msg)
     }
 }
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::http::directory::Directories;
+    use crate::testing::TempDir;
+    use std::os::unix::fs::symlink;
+
+    // A root to serve next to a directory outside of it
+    //   root/a.txt, root/docs/index.html, root/private/, root/alias -> private, root/out -> outside, root/secret -> outside/secret.txt
+    //   outside/secret.txt, outside/index.html
+    struct Tree {
+        dir: TempDir,
+        server: FileServer,
+    }
+
+    impl Tree {
+        fn new(name: &str) -> Tree {
+            let dir = TempDir::new(name);
+            let (root, outside) = (dir.mkdir("root"), dir.mkdir("outside"));
+            fs::create_dir(root.join("private")).unwrap();
+            fs::write(root.join("a.txt"), "a").unwrap();
+            fs::create_dir(root.join("docs")).unwrap();
+            fs::write(root.join("docs/index.html"), "docs").unwrap();
+            fs::write(outside.join("secret.txt"), "secret").unwrap();
+            fs::write(outside.join("index.html"), "index").unwrap();
+            symlink(root.join("private"), root.join("alias")).unwrap();
+            symlink(&outside, root.join("out")).unwrap();
+            symlink(outside.join("secret.txt"), root.join("secret")).unwrap();
+            let directories = Directories::new().rule("private", false).unwrap();
+            Tree { dir, server: FileServer::new(root).directories(directories) }
+        }
+
+        fn get(&self, target: &str) -> HttpResponse {
+            let request = HttpRequest::new(&format!("GET {} HTTP/1.1\r\nHost: a\r\n\r\n", target)).unwrap();
+            HttpResponse::from_file_server(&request, &self.server).unwrap()
+        }
+    }
+
+    #[test]
+    fn serves_files_below_the_root() {
+        let tree = Tree::new("inside");
+        assert_eq!(tree.get("/a.txt").status, ResponseStatus::OK);
+        assert_eq!(tree.get("/./a.txt").status, ResponseStatus::OK);
+        assert_eq!(tree.get("/").status, ResponseStatus::OK);
+    }
+
+    #[test]
+    fn keeps_requests_inside_the_root() {
+        let tree = Tree::new("outside");
+        let secret = tree.dir.join("outside/secret.txt").to_string_lossy().replace('/', "%2F");
+        let targets = [
+            format!("/{}", secret),                // Absolute once decoded
+            "/../outside/secret.txt".to_string(),  // Parent directories
+            "/%2E%2E/outside/secret.txt".to_string(),
+            "/private/../../outside/secret.txt".to_string(),
+            "/out/secret.txt".to_string(),         // Symlinks leading out of the root
+            "/secret".to_string(),
+            "/%2Fetc%2F".to_string(),              // Listings outside the root
+            "/out".to_string(),                    // No redirect to an index outside the root either
+            "/out/".to_string(),
+        ];
+        for target in targets {
+            let response = tree.get(&target);
+            assert_eq!(response.status, ResponseStatus::NotFound, "{}", target);
+            assert!(response.headers.iter().all(|(name, _)| name != "Location"), "{}", target);
+        }
+    }
+
+    #[test]
+    fn listing_rules_match_the_canonical_directory() {
+        let tree = Tree::new("listings");
+        for target in ["/private/", "/./private/", "//private/", "/alias/", "/%2E/private/"] {
+            assert_eq!(tree.get(target).status, ResponseStatus::Forbidden, "{}", target);
+        }
+    }
+
+    #[test]
+    fn serves_index_files() {
+        let tree = Tree::new("index");
+        let response = tree.get("/docs?x=1");
+        assert_eq!(response.status, ResponseStatus::MovedPermanently);
+        assert_eq!(response.headers, [("Location".to_string(), "/docs/?x=1".to_string())]);
+        let response = tree.get("/docs/");
+        assert_eq!(response.status, ResponseStatus::OK);
+        assert_eq!(response.content_length, 4);
+        assert!(response.content_type.starts_with("text/html"));
+    }
+
+    #[test]
+    fn escapes_names_and_paths_in_html() {
+        let tree = Tree::new("escape");
+        let root = tree.dir.join("root");
+        fs::create_dir(root.join("<i>")).unwrap();
+        fs::write(root.join("<i>/<b>&\"'.txt"), "x").unwrap();
+        let mut html = String::new();
+        match tree.get("/%3Ci%3E/").response_body {
+            ResponseBody::Stream(mut listing) => listing.read_to_string(&mut html).unwrap(),
+            _ => panic!("listing is not streamed"),
+        };
+        assert!(html.contains("Currently in &lt;i&gt;/"), "{}", html);
+        assert!(html.contains(">&lt;b&gt;&amp;&quot;&#39;.txt</a>"), "{}", html);
+        assert!(!html.contains("<i>") && !html.contains("<b>"), "{}", html);
+
+        let response = tree.get("/<script>alert(1)</script>");
+        assert_eq!(response.status, ResponseStatus::NotFound);
+        let body = match response.response_body {
+            ResponseBody::Bytes(body) => String::from_utf8(body).unwrap(),
+            _ => panic!("404 body is not in memory"),
+        };
+        assert!(body.contains("&lt;script&gt;alert(1)&lt;/script&gt;"), "{}", body);
+        assert!(!body.contains("<script>"), "{}", body);
+    }
+
+    #[test]
+    fn redirects_to_indexes_on_this_host() {
+        let tree = Tree::new("index-redirect");
+        let root = tree.dir.join("root");
+        let evil = root.join("evil.example").with_file_name("\\evil.example"); // A name, on Unix
+        fs::create_dir(&evil).unwrap();
+        fs::write(evil.join("index.html"), "index").unwrap();
+        let cases = [
+            ("//docs", "/docs/"),
+            ("/./docs", "/docs/"),
+            ("/\\evil.example", "/%5Cevil.example/"),
+            ("/%5Cevil.example?x=1", "/%5Cevil.example/?x=1"),
+        ];
+        for (target, location) in cases {
+            let response = tree.get(target);
+            assert_eq!(response.status, ResponseStatus::MovedPermanently, "{}", target);
+            assert_eq!(response.headers, [("Location".to_string(), location.to_string())], "{}", target);
+        }
+    }
+
+    #[test]
+    fn reads_listings_in_as_few_pieces_as_fit() {
+        let tree = Tree::new("listing-reads");
+        let mut listing = match tree.get("/").response_body {
+            ResponseBody::Stream(listing) => listing,
+            _ => panic!("listing is not streamed"),
+        };
+        let mut buf = [0; 16 * 1024];
+        let read = listing.read(&mut buf).unwrap();
+        let html = String::from_utf8_lossy(&buf[..read]);
+        assert!(html.contains("a.txt") && html.contains("docs/") && html.contains("</html>"), "{}", html);
+        assert_eq!(listing.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn refuses_methods_other_than_get_and_head() {
+        use crate::http::handler::Handler;
+
+        let tree = Tree::new("methods");
+        for method in ["POST", "PUT", "DELETE", "OPTIONS"] {
+            let mut request = HttpRequest::new(&format!("{} /a.txt HTTP/1.1\r\nHost: a\r\n\r\n", method)).unwrap();
+            let early = tree.server.precheck(&request).expect(method);
+            assert_eq!(early.status, ResponseStatus::MethodNotAllowed, "{}", method);
+            let response = tree.server.handle(&mut request).unwrap();
+            assert_eq!(response.status, ResponseStatus::MethodNotAllowed, "{}", method);
+            assert_eq!(response.headers, [("Allow".to_string(), "GET, HEAD".to_string())]);
+        }
+        let request = HttpRequest::new("HEAD /a.txt HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+        assert!(tree.server.precheck(&request).is_none());
+    }
+
+    #[test]
+    fn strips_line_breaks_from_header_fields() {
+        let response = HttpResponse::builder()
+            .redirect("/a\r\nSet-Cookie: x=1")
+            .header("Bad\r\nName", "value")
+            .content_type("text/plain\r\nX-Injected: 1")
+            .build();
+        let mut head = Vec::new();
+        response.write_head_to(&mut head).unwrap();
+        let head = String::from_utf8(head).unwrap();
+        assert!(head.contains("Location: /aSet-Cookie: x=1\r\n"), "{}", head);
+        assert!(head.contains("Content-Type: text/plainX-Injected: 1\r\n"), "{}", head);
+        assert!(!head.contains("Bad"), "{}", head);
+        assert_eq!(head.matches("\r\n").count(), 5, "{}", head); // Status line, three fields, end of head
+    }
+
+    #[test]
+    fn writes_built_responses() {
+        let response = HttpResponse::builder().status(ResponseStatus::Created).header("X-Id", 7).text("hi").build();
+        let mut written = Vec::new();
+        response.write_to(&mut written).unwrap();
+        assert_eq!(
+            String::from_utf8(written).unwrap(),
+            "HTTP/1.1 201 Created\r\nContent-Length: 2\r\nContent-Type: text/plain; charset=utf-8\r\nX-Id: 7\r\n\r\nhi"
+        );
+
+        // Streamed bodies are sent chunked, and readers never past their length
+        let streamed = HttpResponse::builder().reader(&b"abc"[..], None).build();
+        let mut written = Vec::new();
+        streamed.write_to(&mut written).unwrap();
+        assert!(written.ends_with(b"Transfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\n"));
+        let limited = HttpResponse::builder().reader(&b"abcdef"[..], Some(3)).build();
+        let mut written = Vec::new();
+        limited.write_to(&mut written).unwrap();
+        assert!(written.ends_with(b"\r\n\r\nabc"));
+    }
+
+    #[test]
+    fn redirects_default_to_found() {
+        let response = HttpResponse::builder().redirect("/b").build();
+        assert_eq!(response.status, ResponseStatus::Found);
+        let response = HttpResponse::builder().status(ResponseStatus::MovedPermanently).redirect("/b").build();
+        assert_eq!(response.status, ResponseStatus::MovedPermanently);
+        assert_eq!(response.headers, [("Location".to_string(), "/b".to_string())]);
+    }
+}
@@ -0,0 +1,356 @@
+use super::handler::{BodySink, FileServer, Handler};
+use super::request::{HttpRequest, Method};
+use super::response::{HttpResponse, ResponseStatus};
+use super::tus::{TusHandler, TUS_ENDPOINT};
+use crate::config::Config;
+use std::io;
+use std::path::PathBuf;
+
+// Dispatches requests to handlers by method and path
+//
+// Routes match a method and a pattern, in the order they were added:
+//   /users/:id     captures one segment as the "id" parameter
+//   /files/*path   captures the rest of the path as the "path" parameter
+// Mounts match any method on a path prefix; the longest matching prefix wins.
+// Requests matching neither go to the fallback handler (404 if there is none).
+// Captured parameters are available through `HttpRequest::param`.
+pub struct Router {
+    routes: Vec<Route>,
+    mounts: Vec<Mount>,
+    fallback: Option<Box<dyn Handler>>,
+}
+
+// A handler for one method and path pattern
+struct Route {
+    method: Method,
+    pattern: Vec<Segment>,
+    handler: Box<dyn Handler>,
+}
+
+// A handler for every request below a path prefix
+struct Mount {
+    prefix: Vec<String>,
+    handler: Box<dyn Handler>,
+}
+
+// One segment of a route pattern
+enum Segment {
+    Literal(String), // Must match exactly
+    Param(String),   // `:name`, matches any single segment
+    Rest(String),    // `*name`, matches all remaining segments (possibly none)
+}
+
+// Outcome of looking up the handler for a request
+enum Match<'a> {
+    Found(&'a dyn Handler, Vec<(String, String)>),
+    MethodNotAllowed(Vec<&'a Method>), // The path matched, but only for other methods
+    NotFound,
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router {
+            routes: Vec::new(),
+            mounts: Vec::new(),
+            fallback: None,
+        }
+    }
+
+    // The default setup: static files below `root`
+    pub fn static_files(root: impl Into<PathBuf>) -> Router {
+        Router::static_files_with(root, &Config::default())
+    }
+
+    // The default setup, with the file server options from a config file, and tus uploads
+    // if the config enables them
+    pub fn static_files_with(root: impl Into<PathBuf>, config: &Config) -> Router {
+        let files = FileServer::with_config(root, config);
+        let root = files.root().clone();
+        let router = Router::for_file_server(files);
+        if config.uploads.enabled {
+            router.uploads(TusHandler::with_config(root, &config.uploads))
+        } else {
+            router
+        }
+    }
+
+    // The default setup around an already configured file server
+    pub fn for_file_server(files: FileServer) -> Router {
+        Router::new().fallback(files)
+    }
+
+    // Accepts tus uploads at /_tus, e.g. `.uploads(TusHandler::new(root))`
+    // Anyone can upload into the served tree, so guard the router with a middleware such as
+    // BasicAuth (its precheck rejects uploads before their body is read)
+    pub fn uploads(self, uploads: TusHandler) -> Router {
+        self.mount(&format!("/{}", TUS_ENDPOINT), uploads)
+    }
+
+    // Adds a handler for a method and path pattern (GET routes also answer HEAD)
+    pub fn route(mut self, method: Method, pattern: &str, handler: impl Handler + 'static) -> Router {
+        let pattern = segments(pattern)
+            .map(|segment| {
+                if let Some(name) = segment.strip_prefix(':') {
+                    Segment::Param(name.to_string())
+                } else if let Some(name) = segment.strip_prefix('*') {
+                    Segment::Rest(name.to_string())
+                } else {
+                    Segment::Literal(segment.to_string())
+                }
+            })
+            .collect();
+        self.routes.push(Route {
+            method,
+            pattern,
+            handler: Box::new(handler),
+        });
+        self
+    }
+
+    pub fn get(self, pattern: &str, handler: impl Handler + 'static) -> Router {
+        self.route(Method::Get, pattern, handler)
+    }
+
+    pub fn post(self, pattern: &str, handler: impl Handler + 'static) -> Router {
+        self.route(Method::Post, pattern, handler)
+    }
+
+    pub fn put(self, pattern: &str, handler: impl Handler + 'static) -> Router {
+        self.route(Method::Put, pattern, handler)
+    }
+
+    pub fn patch(self, pattern: &str, handler: impl Handler + 'static) -> Router {
+        self.route(Method::Patch, pattern, handler)
+    }
+
+    pub fn delete(self, pattern: &str, handler: impl Handler + 'static) -> Router {
+        self.route(Method::Delete, pattern, handler)
+    }
+
+    // Sends every request below `prefix` (any method) to the handler
+    // The path is passed on unchanged, prefix included
+    pub fn mount(mut self, prefix: &str, handler: impl Handler + 'static) -> Router {
+        self.mounts.push(Mount {
+            prefix: segments(prefix).map(str::to_string).collect(),
+            handler: Box::new(handler),
+        });
+        self
+    }
+
+    // Sets the handler for requests no route or mount matches
+    pub fn fallback(mut self, handler: impl Handler + 'static) -> Router {
+        self.fallback = Some(Box::new(handler));
+        self
+    }
+
+    // Finds the handler for a request along with the captured path parameters
+    fn find(&self, request: &HttpRequest) -> Match<'_> {
+        let path: Vec<&str> = segments(request.path()).collect();
+        let method = request.method();
+
+        let mut allowed = Vec::new();
+        for route in &self.routes {
+            if let Some(params) = match_pattern(&route.pattern, &path) {
+                let method_matches =
+                    route.method == *method || (route.method == Method::Get && *method == Method::Head);
+                if method_matches {
+                    return Match::Found(route.handler.as_ref(), params);
+                }
+                allowed.push(&route.method);
+            }
+        }
+
+        let mount = self
+            .mounts
+            .iter()
+            .filter(|mount| path.starts_with(&mount.prefix.iter().map(String::as_str).collect::<Vec<_>>()))
+            .max_by_key(|mount| mount.prefix.len());
+        if let Some(mount) = mount {
+            return Match::Found(mount.handler.as_ref(), Vec::new());
+        }
+
+        match (&self.fallback, allowed.is_empty()) {
+            (_, false) => Match::MethodNotAllowed(allowed),
+            (Some(fallback), true) => Match::Found(fallback.as_ref(), Vec::new()),
+            (None, true) => Match::NotFound,
+        }
+    }
+}
+
+impl Default for Router {
+    fn default() -> Router {
+        Router::new()
+    }
+}
+
+impl Handler for Router {
+    fn handle(&self, request: &mut HttpRequest) -> io::Result<HttpResponse> {
+        if let Some(response) = request.server_wide_response() {
+            return Ok(response);
+        }
+        match self.find(request) {
+            Match::Found(handler, params) => {
+                request.params = params;
+                handler.handle(request)
+            }
+            Match::MethodNotAllowed(allowed) => Ok(method_not_allowed(&allowed)),
+            Match::NotFound => Ok(HttpResponse::with_status(ResponseStatus::NotFound)),
+        }
+    }
+
+    fn precheck(&self, request: &HttpRequest) -> Option<HttpResponse> {
+        if request.server_wide_response().is_some() {
+            return None;
+        }
+        match self.find(request) {
+            Match::Found(handler, _) => handler.precheck(request),
+            Match::MethodNotAllowed(allowed) => Some(method_not_allowed(&allowed)),
+            Match::NotFound => Some(HttpResponse::with_status(ResponseStatus::NotFound)),
+        }
+    }
+
+    fn body_sink(&self, request: &HttpRequest) -> BodySink {
+        match self.find(request) {
+            Match::Found(handler, _) if request.server_wide_response().is_none() => {
+                handler.body_sink(request)
+            }
+            _ => BodySink::Buffer,
+        }
+    }
+}
+
+// Splits a path into its non-empty segments
+fn segments(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|segment| !segment.is_empty())
+}
+
+// Matches path segments against a route pattern, returning the captured parameters
+fn match_pattern(pattern: &[Segment], path: &[&str]) -> Option<Vec<(String, String)>> {
+    let mut params = Vec::new();
+    for (index, segment) in pattern.iter().enumerate() {
+        match segment {
+            Segment::Rest(name) => {
+                params.push((name.clone(), path.get(index..).unwrap_or(&[]).join("/")));
+                return Some(params);
+            }
+            Segment::Literal(literal) => {
+                if path.get(index) != Some(&literal.as_str()) {
+                    return None;
+                }
+            }
+            Segment::Param(name) => params.push((name.clone(), path.get(index)?.to_string())),
+        }
+    }
+    if path.len() == pattern.len() {
+        Some(params)
+    } else {
+        None
+    }
+}
+
+// 405 response listing the methods the path does support
+fn method_not_allowed(allowed: &[&Method]) -> HttpResponse {
+    let mut response = HttpResponse::with_status(ResponseStatus::MethodNotAllowed);
+    let allow: Vec<&str> = allowed.iter().map(|method| method.as_str()).collect();
+    response.add_header("Allow", allow.join(", "));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::response::ResponseBody;
+    use crate::testing::TempDir;
+
+    // A handler answering with its name and the captured parameters
+    fn named(name: &'static str) -> impl Handler {
+        move |request: &mut HttpRequest| {
+            let params: Vec<String> = request.params.iter().map(|(name, value)| format!("{}={}", name, value)).collect();
+            Ok(HttpResponse::builder().text(format!("{} {}", name, params.join(" "))).build())
+        }
+    }
+
+    // Sends a request through the router, returning the status and body
+    fn dispatch(router: &Router, method: &str, target: &str) -> (ResponseStatus, String) {
+        let mut request = HttpRequest::new(&format!("{} {} HTTP/1.1\r\nHost: a\r\n\r\n", method, target)).unwrap();
+        let response = router.handle(&mut request).unwrap();
+        let body = match response.response_body {
+            ResponseBody::Bytes(bytes) => String::from_utf8(bytes).unwrap(),
+            _ => panic!("unexpected streamed body"),
+        };
+        (response.status, body.trim_end().to_string())
+    }
+
+    fn router() -> Router {
+        Router::new()
+            .get("/users/:id", named("user"))
+            .post("/users/:id", named("update"))
+            .get("/users/:id/files/*path", named("files"))
+            .mount("/static", named("static"))
+            .mount("/static/img", named("img"))
+    }
+
+    #[test]
+    fn captures_parameters() {
+        let router = router();
+        assert_eq!(dispatch(&router, "GET", "/users/42"), (ResponseStatus::OK, "user id=42".to_string()));
+        assert_eq!(dispatch(&router, "GET", "//users//42/"), (ResponseStatus::OK, "user id=42".to_string()));
+        assert_eq!(dispatch(&router, "POST", "/users/42"), (ResponseStatus::OK, "update id=42".to_string()));
+        assert_eq!(dispatch(&router, "GET", "/users/a%20b"), (ResponseStatus::OK, "user id=a b".to_string()));
+        assert_eq!(
+            dispatch(&router, "GET", "/users/7/files/a/b.txt"),
+            (ResponseStatus::OK, "files id=7 path=a/b.txt".to_string())
+        );
+        assert_eq!(dispatch(&router, "GET", "/users/7/files"), (ResponseStatus::OK, "files id=7 path=".to_string()));
+    }
+
+    #[test]
+    fn patterns_match_whole_segments() {
+        let router = router();
+        assert_eq!(dispatch(&router, "GET", "/users").0, ResponseStatus::NotFound);
+        assert_eq!(dispatch(&router, "GET", "/users/1/2").0, ResponseStatus::NotFound);
+        assert_eq!(dispatch(&router, "GET", "/usersx/1").0, ResponseStatus::NotFound);
+        assert_eq!(dispatch(&router, "GET", "/staticx/a").0, ResponseStatus::NotFound);
+    }
+
+    #[test]
+    fn longest_mount_wins() {
+        let router = router();
+        assert_eq!(dispatch(&router, "GET", "/static/a.css").1, "static");
+        assert_eq!(dispatch(&router, "DELETE", "/static/img/a.png").1, "img");
+        assert_eq!(dispatch(&router, "GET", "/static").1, "static");
+    }
+
+    #[test]
+    fn head_uses_get_routes_and_other_methods_are_not_allowed() {
+        let router = router();
+        assert_eq!(dispatch(&router, "HEAD", "/users/1").1, "user id=1");
+
+        let mut request = HttpRequest::new("DELETE /users/1 HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+        let response = router.handle(&mut request).unwrap();
+        assert_eq!(response.status, ResponseStatus::MethodNotAllowed);
+        assert_eq!(response.headers, [("Allow".to_string(), "GET, POST".to_string())]);
+        assert_eq!(router.precheck(&request).map(|response| response.status), Some(ResponseStatus::MethodNotAllowed));
+    }
+
+    #[test]
+    fn fallback_takes_unmatched_requests() {
+        let router = router().fallback(named("fallback"));
+        assert_eq!(dispatch(&router, "GET", "/other").1, "fallback");
+        // A path some route matches still answers 405 for other methods
+        assert_eq!(dispatch(&router, "PUT", "/users/1").0, ResponseStatus::MethodNotAllowed);
+    }
+
+    #[test]
+    fn uploads_are_opt_in() {
+        let dir = TempDir::new("router-uploads");
+        let options = |router: &Router| {
+            let mut request = HttpRequest::new("OPTIONS /_tus HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+            let response = router.handle(&mut request).unwrap();
+            response.headers.iter().any(|(name, _)| name == "Tus-Version")
+        };
+        assert!(!options(&Router::static_files(dir.path())));
+        let config = Config::parse("[uploads]\nenabled = true").unwrap();
+        assert!(options(&Router::static_files_with(dir.path(), &config)));
+    }
+}
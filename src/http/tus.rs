@@ -0,0 +1,1071 @@
+use super::handler::{BodySink, Handler};
+use super::request::{HttpRequest, Method, StreamedBody};
+use super::response::{HttpResponse, ResponseStatus};
+use crate::config::UploadsConfig;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Implementation of the tus 1.0 resumable upload protocol (https://tus.io/protocols/resumable-upload)
+// Supported extensions: creation, termination and expiration.
+
+pub const TUS_ENDPOINT: &str = "_tus"; // Upload endpoint, relative to the server root (e.g. POST /_tus)
+const TUS_VERSION: &str = "1.0.0"; // The only protocol version we speak
+const TUS_EXTENSIONS: &str = "creation,termination,expiration";
+const OFFSET_CONTENT_TYPE: &str = "application/offset+octet-stream"; // Required Content-Type for PATCH
+const MAX_UPLOAD_SIZE: u64 = 8 * 1024 * 1024 * 1024; // Largest upload we accept (8 GiB), in one PATCH or many
+const UPLOAD_EXPIRY: Duration = Duration::from_secs(24 * 60 * 60); // Abandoned uploads are removed after a day
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(10 * 60); // How often the staging dir is swept
+
+// Handler for the tus endpoint; completed uploads are moved below `root`
+// Mounted with Router::uploads; it doesn't authenticate anyone by itself
+pub struct TusHandler {
+    root: PathBuf,
+    staging: Option<PathBuf>, // Directory for partial uploads, next to the root by default
+    verified: OnceLock<PathBuf>, // The staging directory once it was created and checked
+    last_expiry: Mutex<Option<Instant>>, // When abandoned uploads were last swept
+}
+
+impl TusHandler {
+    pub fn new(root: impl Into<PathBuf>) -> TusHandler {
+        TusHandler {
+            root: root.into(),
+            staging: None,
+            verified: OnceLock::new(),
+            last_expiry: Mutex::new(None),
+        }
+    }
+
+    // Uploads into `root` with the settings of the `[uploads]` config section
+    pub fn with_config(root: impl Into<PathBuf>, config: &UploadsConfig) -> TusHandler {
+        let uploads = TusHandler::new(root);
+        match &config.staging {
+            Some(dir) => uploads.staging(dir),
+            None => uploads,
+        }
+    }
+
+    // Keeps partial uploads in `dir` instead of `.<root name>.uploads` next to the root
+    pub fn staging(mut self, dir: impl Into<PathBuf>) -> TusHandler {
+        self.staging = Some(dir.into());
+        self
+    }
+
+    // Returns the staging directory for partial uploads, creating it on first use
+    // Partial uploads live outside the served tree so they are never listed or downloaded
+    fn staging_dir(&self) -> io::Result<&Path> {
+        if let Some(dir) = self.verified.get() {
+            return Ok(dir);
+        }
+        let dir = match &self.staging {
+            Some(dir) => dir.clone(),
+            None => {
+                let root = self.root.canonicalize()?;
+                match (root.parent(), root.file_name()) {
+                    (Some(parent), Some(name)) => {
+                        parent.join(format!(".{}.uploads", name.to_string_lossy()))
+                    }
+                    _ => {
+                        let msg = "the root has no parent directory, configure a staging directory";
+                        return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+                    }
+                }
+            }
+        };
+        create_private_dir(&dir)?;
+        Ok(self.verified.get_or_init(|| dir))
+    }
+
+    // Removes abandoned uploads, at most once per EXPIRY_INTERVAL so requests don't each scan
+    // the staging directory
+    fn expire_if_due(&self, staging: &Path) {
+        {
+            let mut last = self
+                .last_expiry
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            if last.is_some_and(|last| last.elapsed() < EXPIRY_INTERVAL) {
+                return;
+            }
+            *last = Some(Instant::now());
+        }
+        expire_stale_uploads(staging);
+    }
+}
+
+impl Handler for TusHandler {
+    // Handles a request to the tus endpoint, dispatching on the method
+    fn handle(&self, request: &mut HttpRequest) -> io::Result<HttpResponse> {
+        // OPTIONS is the only request that doesn't need the Tus-Resumable header
+        if *request.method() == Method::Options {
+            let mut response = HttpResponse::with_status(ResponseStatus::NoContent);
+            response.add_header("Tus-Resumable", TUS_VERSION);
+            response.add_header("Tus-Version", TUS_VERSION);
+            response.add_header("Tus-Extension", TUS_EXTENSIONS);
+            response.add_header("Tus-Max-Size", MAX_UPLOAD_SIZE);
+            return Ok(response);
+        }
+
+        if request.header("Tus-Resumable") != Some(TUS_VERSION) {
+            let mut response = HttpResponse::with_status(ResponseStatus::PreconditionFailed);
+            response.add_header("Tus-Version", TUS_VERSION);
+            return Ok(response);
+        }
+
+        let streamed = request.streamed_body.take();
+        let root = &self.root;
+        let staging = self.staging_dir()?;
+        self.expire_if_due(staging);
+
+        let id = request.resource.path[TUS_ENDPOINT.len()..].trim_matches('/');
+        let mut response = match (request.method(), id.is_empty()) {
+            (Method::Post, true) => create(request, root, staging)?,
+            (Method::Head, false) => status(staging, id)?,
+            (Method::Patch, false) => append(request, streamed, root, staging, id)?,
+            (Method::Delete, false) => terminate(staging, id)?,
+            _ => HttpResponse::with_status(ResponseStatus::MethodNotAllowed),
+        };
+        response.add_header("Tus-Resumable", TUS_VERSION);
+        Ok(response)
+    }
+
+    // Checks a tus request before its body is read (e.g. for `Expect: 100-continue`)
+    fn precheck(&self, request: &HttpRequest) -> Option<HttpResponse> {
+        let mut rejection = if request.header("Tus-Resumable") != Some(TUS_VERSION) {
+            if *request.method() == Method::Options {
+                return None;
+            }
+            let mut response = HttpResponse::with_status(ResponseStatus::PreconditionFailed);
+            response.add_header("Tus-Version", TUS_VERSION);
+            response
+        } else {
+            let id = request.resource.path[TUS_ENDPOINT.len()..].trim_matches('/');
+            match (request.method(), id.is_empty()) {
+                (Method::Patch, false) => {
+                    let staging = self.staging_dir().ok()?;
+                    validate_append(request, staging, id).ok()?.err()?
+                }
+                (Method::Post, true) | (Method::Head, false) | (Method::Delete, false) => {
+                    return None
+                }
+                _ => HttpResponse::with_status(ResponseStatus::MethodNotAllowed),
+            }
+        };
+        rejection.add_header("Tus-Resumable", TUS_VERSION);
+        Some(rejection)
+    }
+
+    // Streams PATCH bodies into the upload's data as they arrive, under the upload's lock,
+    // so one request can carry a whole upload and an interrupted one keeps what it sent
+    fn body_sink(&self, request: &HttpRequest) -> BodySink {
+        let id = request.resource.path[TUS_ENDPOINT.len()..].trim_matches('/');
+        let tus = request.header("Tus-Resumable") == Some(TUS_VERSION);
+        if *request.method() != Method::Patch || id.is_empty() || !tus {
+            return BodySink::Buffer;
+        }
+        let staging = match self.staging_dir() {
+            Ok(staging) => staging,
+            Err(_) => return BodySink::Buffer, // The handler runs into the error again and reports it
+        };
+        match PartWriter::open(request, staging, id) {
+            Ok(Ok(writer)) => BodySink::Stream(StreamedBody::new(writer)),
+            Ok(Err(mut rejection)) => {
+                rejection.add_header("Tus-Resumable", TUS_VERSION);
+                BodySink::Reject(rejection)
+            }
+            Err(_) => BodySink::Buffer,
+        }
+    }
+}
+
+// Creates a directory only this user can enter, or checks that an existing one is such
+// Otherwise another user could read partial uploads or plant symlinks among them
+#[cfg(unix)]
+fn create_private_dir(dir: &Path) -> io::Result<()> {
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+
+    match fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)
+    {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {}
+        Err(err) => return Err(err),
+    }
+    let metadata = fs::symlink_metadata(dir)?;
+    // SAFETY: geteuid takes no arguments and always succeeds
+    let uid = unsafe { libc::geteuid() };
+    if !metadata.is_dir() || metadata.uid() != uid || metadata.mode() & 0o077 != 0 {
+        let msg = format!(
+            "staging directory {} must be a directory owned by this user with mode 0700",
+            dir.display()
+        );
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, msg));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn create_private_dir(dir: &Path) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    if !fs::symlink_metadata(dir)?.is_dir() {
+        let msg = format!("staging directory {} is not a directory", dir.display());
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, msg));
+    }
+    Ok(())
+}
+
+// Metadata describing an upload, persisted next to its data as `<id>.info` (TOML)
+#[derive(Serialize, Deserialize)]
+struct Upload {
+    #[serde(skip)]
+    id: String, // Random id, also the file name of the data and metadata
+    length: u64,     // Total size of the upload in bytes
+    target: PathBuf, // Destination relative to the server root
+    complete: bool,  // Whether the upload has been moved into the served tree
+}
+
+impl Upload {
+    // Loads the upload with the given id, or None if it doesn't exist
+    fn load(staging: &Path, id: &str) -> io::Result<Option<Upload>> {
+        if !is_valid_id(id) {
+            return Ok(None);
+        }
+        let info = match fs::read_to_string(staging.join(format!("{}.info", id))) {
+            Ok(info) => info,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let corrupt = || io::Error::new(io::ErrorKind::InvalidData, "corrupt upload info");
+        let mut upload: Upload = toml::from_str(&info).map_err(|_| corrupt())?;
+        // The target is checked again, so a tampered info file can't move the upload elsewhere
+        if !is_safe_target(&upload.target) {
+            return Err(corrupt());
+        }
+        upload.id = id.to_string();
+        Ok(Some(upload))
+    }
+
+    // Writes the upload metadata to the staging directory
+    fn save(&self, staging: &Path) -> io::Result<()> {
+        let info =
+            toml::to_string(self).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        fs::write(staging.join(format!("{}.info", self.id)), info)
+    }
+
+    // Returns how many bytes of the upload have been received
+    fn offset(&self, staging: &Path) -> io::Result<u64> {
+        if self.complete {
+            return Ok(self.length);
+        }
+        Ok(fs::metadata(staging.join(format!("{}.part", self.id)))?.len())
+    }
+}
+
+// Creates a new upload (POST to the endpoint)
+fn create(request: &HttpRequest, root: &Path, staging: &Path) -> io::Result<HttpResponse> {
+    let length = match request
+        .header("Upload-Length")
+        .and_then(|len| len.parse::<u64>().ok())
+    {
+        Some(length) => length,
+        None => return Ok(HttpResponse::with_status(ResponseStatus::BadRequest)),
+    };
+    if length > MAX_UPLOAD_SIZE {
+        return Ok(HttpResponse::with_status(ResponseStatus::PayloadTooLarge));
+    }
+
+    let id = new_upload_id();
+    let metadata = match parse_metadata(request.header("Upload-Metadata").unwrap_or("")) {
+        Some(metadata) => metadata,
+        None => return Ok(HttpResponse::with_status(ResponseStatus::BadRequest)),
+    };
+    let file_name = metadata
+        .iter()
+        .find(|(key, _)| key == "filename")
+        .map_or(id.clone(), |(_, value)| value.clone());
+    let dir = metadata
+        .iter()
+        .find(|(key, _)| key == "dir")
+        .map_or(String::new(), |(_, value)| value.clone());
+
+    // Reject targets escaping the server root
+    let target = match sanitize_target(&dir, &file_name) {
+        Some(target) => target,
+        None => return Ok(HttpResponse::with_status(ResponseStatus::BadRequest)),
+    };
+    // The directory must stay below the root, and existing files are never replaced (a
+    // dangling symlink counts as existing)
+    let parent = target.parent().unwrap_or(Path::new(""));
+    let dir = match resolve_dir(root, parent, false)? {
+        Some(dir) => dir,
+        None => return Ok(HttpResponse::with_status(ResponseStatus::Forbidden)),
+    };
+    if fs::symlink_metadata(dir.join(target.file_name().unwrap_or_default())).is_ok() {
+        return Ok(HttpResponse::with_status(ResponseStatus::Conflict));
+    }
+
+    fs::File::create(staging.join(format!("{}.part", id)))?;
+    let upload = Upload {
+        id: id.clone(),
+        length,
+        target,
+        complete: false,
+    };
+    upload.save(staging)?;
+    // Empty uploads are complete right away
+    if length == 0 {
+        if let Some(refusal) = finish(root, staging, upload)? {
+            remove_upload(staging, &id);
+            return Ok(HttpResponse::with_status(refusal));
+        }
+    }
+
+    let mut response = HttpResponse::with_status(ResponseStatus::Created);
+    response.add_header("Location", format!("/{}/{}", TUS_ENDPOINT, id));
+    response.add_header(
+        "Upload-Expires",
+        httpdate::fmt_http_date(SystemTime::now() + UPLOAD_EXPIRY),
+    );
+    Ok(response)
+}
+
+// Reports the current offset of an upload (HEAD)
+fn status(staging: &Path, id: &str) -> io::Result<HttpResponse> {
+    let upload = match Upload::load(staging, id)? {
+        Some(upload) => upload,
+        None => return Ok(HttpResponse::with_status(ResponseStatus::NotFound)),
+    };
+    let mut response = HttpResponse::with_status(ResponseStatus::OK);
+    response.add_header("Upload-Offset", upload.offset(staging)?);
+    response.add_header("Upload-Length", upload.length);
+    response.add_header("Cache-Control", "no-store");
+    Ok(response)
+}
+
+// Appends the request body to an upload (PATCH)
+// A streamed body was already written to the upload's data as it arrived (see body_sink)
+fn append(
+    request: &HttpRequest,
+    streamed: Option<StreamedBody>,
+    root: &Path,
+    staging: &Path,
+    id: &str,
+) -> io::Result<HttpResponse> {
+    // The writer holds the upload's lock until the offset has moved
+    let _writer = match streamed {
+        Some(streamed) => streamed,
+        None => {
+            let mut writer = match PartWriter::open(request, staging, id)? {
+                Ok(writer) => writer,
+                Err(rejection) => return Ok(rejection),
+            };
+            if request.request_body.len() as u64 > writer.remaining {
+                return Ok(HttpResponse::with_status(ResponseStatus::PayloadTooLarge));
+            }
+            writer.write_all(&request.request_body)?;
+            writer.flush()?;
+            StreamedBody::new(writer)
+        }
+    };
+    let upload = match Upload::load(staging, id)? {
+        Some(upload) => upload,
+        None => return Ok(HttpResponse::with_status(ResponseStatus::NotFound)),
+    };
+
+    let new_offset = upload.offset(staging)?;
+    if new_offset == upload.length && !upload.complete {
+        if let Some(refusal) = finish(root, staging, upload)? {
+            // The data stays staged, and the upload expires like an abandoned one
+            return Ok(HttpResponse::with_status(refusal));
+        }
+    }
+
+    let mut response = HttpResponse::with_status(ResponseStatus::NoContent);
+    response.add_header("Upload-Offset", new_offset);
+    response.add_header(
+        "Upload-Expires",
+        httpdate::fmt_http_date(SystemTime::now() + UPLOAD_EXPIRY),
+    );
+    Ok(response)
+}
+
+// Writes a PATCH body to the end of an upload's data, holding the upload's lock while it
+// exists; bytes beyond the upload's length are refused
+struct PartWriter {
+    part: Option<File>, // None once the upload is complete
+    remaining: u64,     // Bytes the upload still takes
+    _lock: File,
+}
+
+impl PartWriter {
+    // Locks the upload and validates the PATCH headers against it
+    // Returns the response rejecting the request if it doesn't fit the upload
+    fn open(
+        request: &HttpRequest,
+        staging: &Path,
+        id: &str,
+    ) -> io::Result<Result<PartWriter, HttpResponse>> {
+        let lock = match lock_upload(staging, id)? {
+            Ok(lock) => lock,
+            Err(status) => return Ok(Err(HttpResponse::with_status(status))),
+        };
+        let (upload, offset) = match validate_append(request, staging, id)? {
+            Ok(valid) => valid,
+            Err(rejection) => return Ok(Err(rejection)),
+        };
+        let part = if upload.complete {
+            None
+        } else {
+            let path = staging.join(format!("{}.part", id));
+            Some(OpenOptions::new().append(true).open(path)?)
+        };
+        Ok(Ok(PartWriter {
+            part,
+            remaining: upload.length - offset,
+            _lock: lock,
+        }))
+    }
+}
+
+impl Write for PartWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() as u64 > self.remaining {
+            return Err(io::Error::new(
+                io::ErrorKind::FileTooLarge,
+                "body longer than the rest of the upload",
+            ));
+        }
+        let written = match &mut self.part {
+            Some(part) => part.write(buf)?,
+            None => 0, // Nothing remains, so the buffer is empty
+        };
+        self.remaining -= written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.part {
+            Some(part) => part.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+// Validates the headers of a PATCH: the upload must exist and the declared offset must match
+// Returns the upload and its current offset, or the response rejecting the request
+fn validate_append(
+    request: &HttpRequest,
+    staging: &Path,
+    id: &str,
+) -> io::Result<Result<(Upload, u64), HttpResponse>> {
+    let upload = match Upload::load(staging, id)? {
+        Some(upload) => upload,
+        None => return Ok(Err(HttpResponse::with_status(ResponseStatus::NotFound))),
+    };
+    if request.header("Content-Type") != Some(OFFSET_CONTENT_TYPE) {
+        return Ok(Err(HttpResponse::with_status(
+            ResponseStatus::UnsupportedMediaType,
+        )));
+    }
+    let offset = upload.offset(staging)?;
+    match request
+        .header("Upload-Offset")
+        .and_then(|o| o.parse::<u64>().ok())
+    {
+        Some(declared) if declared == offset => {}
+        Some(_) => return Ok(Err(HttpResponse::with_status(ResponseStatus::Conflict))),
+        None => return Ok(Err(HttpResponse::with_status(ResponseStatus::BadRequest))),
+    }
+    // With Content-Length we can tell up front whether the chunk overflows the upload
+    if let Some(length) = request.content_length() {
+        if offset + length as u64 > upload.length {
+            return Ok(Err(HttpResponse::with_status(
+                ResponseStatus::PayloadTooLarge,
+            )));
+        }
+    }
+    Ok(Ok((upload, offset)))
+}
+
+// Deletes an upload and its partial data (DELETE, termination extension)
+fn terminate(staging: &Path, id: &str) -> io::Result<HttpResponse> {
+    let _lock = match lock_upload(staging, id)? {
+        Ok(lock) => lock,
+        Err(status) => return Ok(HttpResponse::with_status(status)),
+    };
+    if Upload::load(staging, id)?.is_none() {
+        return Ok(HttpResponse::with_status(ResponseStatus::NotFound));
+    }
+    remove_upload(staging, id);
+    Ok(HttpResponse::with_status(ResponseStatus::NoContent))
+}
+
+// Takes an upload's lock (an exclusive flock on its info file) for a PATCH or DELETE, held until
+// the returned file is dropped. Two PATCHes at the same offset would otherwise both append, so
+// the request that finds the lock taken gets a 409, as does one that finds its offset outdated
+// Returns the status refusing the request if the upload doesn't exist or is locked
+fn lock_upload(staging: &Path, id: &str) -> io::Result<Result<File, ResponseStatus>> {
+    if !is_valid_id(id) {
+        return Ok(Err(ResponseStatus::NotFound));
+    }
+    let info = match File::open(staging.join(format!("{}.info", id))) {
+        Ok(info) => info,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            return Ok(Err(ResponseStatus::NotFound))
+        }
+        Err(err) => return Err(err),
+    };
+    match info.try_lock() {
+        Ok(()) => Ok(Ok(info)),
+        Err(TryLockError::WouldBlock) => Ok(Err(ResponseStatus::Conflict)),
+        Err(TryLockError::Error(err)) => Err(err),
+    }
+}
+
+// Moves a completed upload into the served tree
+// Leaves the upload staged and returns the status refusing it if a file already exists at
+// the target (409), or if its directory leads out of the root (403)
+fn finish(root: &Path, staging: &Path, mut upload: Upload) -> io::Result<Option<ResponseStatus>> {
+    let part = staging.join(format!("{}.part", upload.id));
+    let parent = upload.target.parent().unwrap_or(Path::new(""));
+    let destination = match (resolve_dir(root, parent, true)?, upload.target.file_name()) {
+        (Some(dir), Some(file_name)) => dir.join(file_name),
+        _ => return Ok(Some(ResponseStatus::Forbidden)),
+    };
+    let exists = Some(ResponseStatus::Conflict);
+    // Unlike rename(), linking and create_new fail instead of replacing an existing file
+    match fs::hard_link(&part, &destination) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => return Ok(exists),
+        // Links fail across filesystems (e.g. a configured staging dir on tmpfs), so fall back to copying
+        Err(_) => {
+            let mut copy = match OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&destination)
+            {
+                Ok(copy) => copy,
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => return Ok(exists),
+                Err(err) => return Err(err),
+            };
+            if let Err(err) = io::copy(&mut File::open(&part)?, &mut copy) {
+                let _ = fs::remove_file(&destination); // Don't leave a partial file behind
+                return Err(err);
+            }
+        }
+    }
+    fs::remove_file(&part)?;
+    upload.complete = true;
+    upload.save(staging)?; // Keep the info around so HEAD still reports the final offset
+    Ok(None)
+}
+
+// Resolves the directory `dir` (relative, plain names only) below the root, creating the
+// missing parts if `create` is set. Every existing directory on the way is canonicalized
+// and must stay below the canonical root, so a symlink in the tree can't send an upload
+// elsewhere; returns None if one leads out. Without `create`, the part that doesn't exist
+// yet is appended as is
+fn resolve_dir(root: &Path, dir: &Path, create: bool) -> io::Result<Option<PathBuf>> {
+    let canonical_root = root.canonicalize()?;
+    let mut resolved = canonical_root.clone();
+    let mut components = dir.components();
+    while let Some(component) = components.next() {
+        let next = resolved.join(component);
+        if fs::symlink_metadata(&next).is_err() {
+            if !create {
+                return Ok(Some(next.join(components.as_path()))); // Nothing below exists either
+            }
+            // A directory created meanwhile is checked like any other
+            match fs::create_dir(&next) {
+                Err(err) if err.kind() != io::ErrorKind::AlreadyExists => return Err(err),
+                _ => {}
+            }
+        }
+        match next.canonicalize() {
+            Ok(canonical) if canonical.starts_with(&canonical_root) && canonical.is_dir() => {
+                resolved = canonical
+            }
+            Ok(_) => return Ok(None), // Out of the root, or not a directory
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None), // Dangling symlink
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(Some(resolved))
+}
+
+// Removes uploads that haven't been touched within the expiry window
+fn expire_stale_uploads(staging: &Path) {
+    let entries = match fs::read_dir(staging) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some("info") {
+            continue;
+        }
+        let modified = entry.metadata().and_then(|meta| meta.modified());
+        let part_modified =
+            fs::metadata(path.with_extension("part")).and_then(|meta| meta.modified());
+        let last_activity = match (modified, part_modified) {
+            (Ok(info), Ok(part)) => info.max(part),
+            (Ok(info), Err(_)) => info,
+            _ => continue,
+        };
+        let expired = last_activity.elapsed().is_ok_and(|age| age > UPLOAD_EXPIRY);
+        if let (true, Some(id)) = (expired, path.file_stem().and_then(|stem| stem.to_str())) {
+            // An upload whose lock is taken is being written to right now
+            if let Ok(Ok(_lock)) = lock_upload(staging, id) {
+                remove_upload(staging, id);
+            }
+        }
+    }
+}
+
+// Deletes the data and metadata of an upload, ignoring missing files
+fn remove_upload(staging: &Path, id: &str) {
+    let _ = fs::remove_file(staging.join(format!("{}.part", id)));
+    let _ = fs::remove_file(staging.join(format!("{}.info", id)));
+}
+
+// Parses the Upload-Metadata header: comma separated `key base64value` pairs
+// Returns None if a key or value holds control characters (e.g. a newline in a file name)
+fn parse_metadata(header: &str) -> Option<Vec<(String, String)>> {
+    let metadata: Vec<(String, String)> = header
+        .split(',')
+        .filter_map(|pair| {
+            let mut parts = pair.trim().splitn(2, ' ');
+            let key = parts.next().filter(|key| !key.is_empty())?;
+            let value = match parts.next() {
+                Some(encoded) => String::from_utf8(STANDARD.decode(encoded.trim()).ok()?).ok()?,
+                None => String::new(),
+            };
+            Some((key.to_string(), value))
+        })
+        .collect();
+    let clean = |text: &String| !text.chars().any(char::is_control);
+    metadata
+        .iter()
+        .all(|(key, value)| clean(key) && clean(value))
+        .then_some(metadata)
+}
+
+// Builds the destination path for an upload, rejecting anything that could escape the root
+fn sanitize_target(dir: &str, file_name: &str) -> Option<PathBuf> {
+    let target = Path::new(dir.trim_start_matches('/')).join(file_name);
+    let mut clean = PathBuf::new();
+    for component in target.components() {
+        match component {
+            Component::Normal(part) => clean.push(part),
+            Component::CurDir => {}
+            _ => return None, // Parent directories, absolute paths and prefixes are not allowed
+        }
+    }
+    if clean.as_os_str().is_empty() || clean.starts_with(TUS_ENDPOINT) {
+        return None;
+    }
+    Some(clean)
+}
+
+// Whether a stored target stays below the root: relative, with plain names only
+fn is_safe_target(target: &Path) -> bool {
+    !target.as_os_str().is_empty()
+        && !target.starts_with(TUS_ENDPOINT)
+        && target
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+}
+
+// Generates a random, URL-safe upload id
+fn new_upload_id() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_nanos());
+    let mut id = String::new();
+    for _ in 0..2 {
+        let mut hasher = RandomState::new().build_hasher(); // Randomly keyed per call
+        hasher.write_u128(nanos);
+        hasher.write_u32(std::process::id());
+        id.push_str(&format!("{:016x}", hasher.finish()));
+    }
+    id
+}
+
+// Upload ids are 32 lowercase hex characters
+fn is_valid_id(id: &str) -> bool {
+    id.len() == 32
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    // A served root and a staging directory of its own
+    struct Dirs {
+        dir: TempDir, // Removes both when dropped
+        root: PathBuf,
+        staging: PathBuf,
+    }
+
+    impl Dirs {
+        fn new(name: &str) -> Dirs {
+            let dir = TempDir::new(name);
+            let staging = dir.mkdir("staging");
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                fs::set_permissions(&staging, fs::Permissions::from_mode(0o700)).unwrap();
+            }
+            Dirs {
+                root: dir.mkdir("root"),
+                staging,
+                dir,
+            }
+        }
+    }
+
+    fn tus_request(head: &str, body: &[u8]) -> HttpRequest {
+        let mut request = HttpRequest::new(&format!(
+            "{}\r\nHost: a\r\nTus-Resumable: 1.0.0\r\n\r\n",
+            head
+        ))
+        .unwrap();
+        request.request_body = body.to_vec();
+        request
+    }
+
+    // Starts an upload to `path` (directory and file name) below the root
+    fn post(dirs: &Dirs, length: u64, path: &str) -> HttpResponse {
+        let (dir, file_name) = path.rsplit_once('/').unwrap_or(("", path));
+        let metadata = format!(
+            "filename {},dir {}",
+            STANDARD.encode(file_name),
+            STANDARD.encode(dir)
+        );
+        let request = tus_request(
+            &format!(
+                "POST /_tus HTTP/1.1\r\nUpload-Length: {}\r\nUpload-Metadata: {}",
+                length, metadata
+            ),
+            b"",
+        );
+        create(&request, &dirs.root, &dirs.staging).unwrap()
+    }
+
+    // Creates an upload, returning its id
+    fn create_upload(dirs: &Dirs, length: u64, path: &str) -> String {
+        let response = post(dirs, length, path);
+        assert_eq!(response.status, ResponseStatus::Created);
+        let (_, location) = response
+            .headers
+            .iter()
+            .find(|(name, _)| name == "Location")
+            .unwrap();
+        location.rsplit('/').next().unwrap().to_string()
+    }
+
+    fn patch(dirs: &Dirs, id: &str, offset: u64, body: &[u8]) -> ResponseStatus {
+        let request = tus_request(
+            &format!(
+                "PATCH /_tus/{} HTTP/1.1\r\nContent-Type: {}\r\nUpload-Offset: {}\r\nContent-Length: {}",
+                id,
+                OFFSET_CONTENT_TYPE,
+                offset,
+                body.len()
+            ),
+            body,
+        );
+        append(&request, None, &dirs.root, &dirs.staging, id)
+            .unwrap()
+            .status
+    }
+
+    #[test]
+    fn appends_at_the_current_offset() {
+        let dirs = Dirs::new("append");
+        let id = create_upload(&dirs, 6, "a.txt");
+        assert_eq!(patch(&dirs, &id, 0, b"abc"), ResponseStatus::NoContent);
+        assert_eq!(patch(&dirs, &id, 3, b"def"), ResponseStatus::NoContent);
+        assert_eq!(fs::read(dirs.root.join("a.txt")).unwrap(), b"abcdef");
+    }
+
+    #[test]
+    fn rejects_offset_mismatches() {
+        let dirs = Dirs::new("offset");
+        let id = create_upload(&dirs, 6, "a.txt");
+        assert_eq!(patch(&dirs, &id, 1, b"abc"), ResponseStatus::Conflict);
+        assert_eq!(patch(&dirs, &id, 0, b"abc"), ResponseStatus::NoContent);
+        // A retried chunk doesn't get appended twice
+        assert_eq!(patch(&dirs, &id, 0, b"abc"), ResponseStatus::Conflict);
+        assert_eq!(patch(&dirs, &id, 6, b""), ResponseStatus::Conflict);
+        // Nor does one that overflows the declared length
+        assert_eq!(
+            patch(&dirs, &id, 3, b"defg"),
+            ResponseStatus::PayloadTooLarge
+        );
+        let upload = Upload::load(&dirs.staging, &id).unwrap().unwrap();
+        assert_eq!(upload.offset(&dirs.staging).unwrap(), 3);
+    }
+
+    #[test]
+    fn rejects_appends_without_a_valid_offset() {
+        let dirs = Dirs::new("headers");
+        let id = create_upload(&dirs, 6, "a.txt");
+        let request = tus_request(
+            &format!(
+                "PATCH /_tus/{} HTTP/1.1\r\nContent-Type: {}\r\nUpload-Offset: -1",
+                id, OFFSET_CONTENT_TYPE
+            ),
+            b"",
+        );
+        let response = validate_append(&request, &dirs.staging, &id).unwrap();
+        assert_eq!(response.err().unwrap().status, ResponseStatus::BadRequest);
+        let request = tus_request(
+            &format!("PATCH /_tus/{} HTTP/1.1\r\nUpload-Offset: 0", id),
+            b"",
+        );
+        let response = validate_append(&request, &dirs.staging, &id).unwrap();
+        assert_eq!(
+            response.err().unwrap().status,
+            ResponseStatus::UnsupportedMediaType
+        );
+        let unknown = "0123456789abcdef0123456789abcdef";
+        assert_eq!(patch(&dirs, unknown, 0, b"abc"), ResponseStatus::NotFound);
+    }
+
+    #[test]
+    fn never_replaces_existing_files() {
+        let dirs = Dirs::new("existing");
+        fs::write(dirs.root.join("taken.txt"), "original").unwrap();
+        let request = tus_request(
+            &format!(
+                "POST /_tus HTTP/1.1\r\nUpload-Length: 3\r\nUpload-Metadata: filename {}",
+                STANDARD.encode("taken.txt")
+            ),
+            b"",
+        );
+        let response = create(&request, &dirs.root, &dirs.staging).unwrap();
+        assert_eq!(response.status, ResponseStatus::Conflict);
+
+        // A file created while the upload was in progress is kept too
+        let id = create_upload(&dirs, 3, "late.txt");
+        fs::write(dirs.root.join("late.txt"), "original").unwrap();
+        assert_eq!(patch(&dirs, &id, 0, b"abc"), ResponseStatus::Conflict);
+        assert_eq!(
+            fs::read_to_string(dirs.root.join("late.txt")).unwrap(),
+            "original"
+        );
+    }
+
+    #[test]
+    fn rejects_unsafe_targets() {
+        assert_eq!(
+            sanitize_target("/docs/", "a.txt"),
+            Some(PathBuf::from("docs/a.txt"))
+        );
+        assert_eq!(sanitize_target("", "./a.txt"), Some(PathBuf::from("a.txt")));
+        for (dir, file_name) in [
+            ("", "../a.txt"),
+            ("docs/../..", "a.txt"),
+            ("", "/etc/passwd"),
+            ("", ""),
+            ("_tus", "a.txt"),
+        ] {
+            assert_eq!(
+                sanitize_target(dir, file_name),
+                None,
+                "{:?}",
+                (dir, file_name)
+            );
+        }
+        assert!(is_safe_target(Path::new("docs/a.txt")));
+        for target in ["/etc/cron.d/x", "../x", "./x", "", "_tus/x"] {
+            assert!(!is_safe_target(Path::new(target)), "{:?}", target);
+        }
+    }
+
+    #[test]
+    fn rejects_control_characters_in_metadata() {
+        let encoded = STANDARD.encode("a\ntarget=/etc/cron.d/x");
+        assert_eq!(parse_metadata(&format!("filename {}", encoded)), None);
+        assert_eq!(parse_metadata("bad\tkey"), None);
+        assert_eq!(
+            parse_metadata(&format!("filename {},flag", STANDARD.encode("a b.txt"))),
+            Some(vec![
+                ("filename".to_string(), "a b.txt".to_string()),
+                ("flag".to_string(), String::new())
+            ])
+        );
+    }
+
+    #[test]
+    fn rejects_tampered_upload_info() {
+        let dirs = Dirs::new("tampered");
+        let id = create_upload(&dirs, 3, "a.txt");
+        let info = dirs.staging.join(format!("{}.info", id));
+        let tampered = fs::read_to_string(&info)
+            .unwrap()
+            .replace("a.txt", "/etc/cron.d/x");
+        fs::write(&info, tampered).unwrap();
+        let err = Upload::load(&dirs.staging, &id).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn keeps_uploads_inside_the_root() {
+        let dirs = Dirs::new("symlinked");
+        let outside = dirs.dir.mkdir("outside");
+        std::os::unix::fs::symlink(&outside, dirs.root.join("link")).unwrap();
+        assert_eq!(
+            post(&dirs, 3, "link/a.txt").status,
+            ResponseStatus::Forbidden
+        );
+        assert_eq!(
+            post(&dirs, 3, "link/sub/a.txt").status,
+            ResponseStatus::Forbidden
+        );
+        assert_eq!(
+            post(&dirs, 0, "link/b.txt").status,
+            ResponseStatus::Forbidden
+        );
+
+        // A directory swapped for a symlink while the upload is in progress
+        let id = create_upload(&dirs, 3, "new/sub/a.txt");
+        std::os::unix::fs::symlink(&outside, dirs.root.join("new")).unwrap();
+        assert_eq!(patch(&dirs, &id, 0, b"abc"), ResponseStatus::Forbidden);
+        assert_eq!(fs::read_dir(&outside).unwrap().count(), 0);
+
+        let id = create_upload(&dirs, 3, "real/sub/a.txt");
+        assert_eq!(patch(&dirs, &id, 0, b"abc"), ResponseStatus::NoContent);
+        assert_eq!(fs::read(dirs.root.join("real/sub/a.txt")).unwrap(), b"abc");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn keeps_partial_uploads_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dirs = Dirs::new("tus-staging");
+        let uploads = TusHandler::new(&dirs.root);
+        let staging = uploads.staging_dir().unwrap();
+        assert_eq!(
+            staging,
+            dirs.dir.join(".root.uploads").canonicalize().unwrap()
+        );
+        let mode = fs::metadata(staging).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+
+        // A configured directory others can enter, or a symlink in its place, is refused
+        fs::set_permissions(&dirs.staging, fs::Permissions::from_mode(0o755)).unwrap();
+        let uploads = TusHandler::new(&dirs.root).staging(&dirs.staging);
+        let err = uploads.staging_dir().err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        let link = dirs.dir.join("link");
+        std::os::unix::fs::symlink(dirs.dir.join(".root.uploads"), &link).unwrap();
+        let uploads = TusHandler::new(&dirs.root).staging(&link);
+        assert!(uploads.staging_dir().is_err());
+
+        let uploads = TusHandler::new(&dirs.root).staging(dirs.dir.join("new/staging"));
+        assert!(uploads.staging_dir().unwrap().is_dir());
+    }
+
+    #[test]
+    fn expires_abandoned_uploads_once_per_interval() {
+        let dirs = Dirs::new("tus-expiry");
+        let abandon = |id: &str| {
+            let old = SystemTime::now() - UPLOAD_EXPIRY * 2;
+            for ext in ["info", "part"] {
+                let file = File::options()
+                    .write(true)
+                    .open(dirs.staging.join(format!("{}.{}", id, ext)))
+                    .unwrap();
+                file.set_modified(old).unwrap();
+            }
+        };
+        let uploads = TusHandler::new(&dirs.root);
+        let first = create_upload(&dirs, 3, "a.txt");
+        let active = create_upload(&dirs, 3, "b.txt");
+        abandon(&first);
+        uploads.expire_if_due(&dirs.staging);
+        assert!(Upload::load(&dirs.staging, &first).unwrap().is_none());
+        assert!(Upload::load(&dirs.staging, &active).unwrap().is_some());
+
+        // The next sweep waits for the interval to pass
+        abandon(&active);
+        uploads.expire_if_due(&dirs.staging);
+        assert!(Upload::load(&dirs.staging, &active).unwrap().is_some());
+    }
+
+    #[test]
+    fn serializes_appends_to_one_upload() {
+        let dirs = Dirs::new("tus-lock");
+        let id = create_upload(&dirs, 6, "a.txt");
+
+        // While another request holds the lock, a PATCH at the same offset loses
+        let lock = lock_upload(&dirs.staging, &id).unwrap().unwrap();
+        assert_eq!(patch(&dirs, &id, 0, b"abc"), ResponseStatus::Conflict);
+        let response = terminate(&dirs.staging, &id).unwrap();
+        assert_eq!(response.status, ResponseStatus::Conflict);
+        drop(lock);
+
+        assert_eq!(patch(&dirs, &id, 0, b"abc"), ResponseStatus::NoContent);
+        assert_eq!(patch(&dirs, &id, 0, b"abc"), ResponseStatus::Conflict);
+        assert_eq!(patch(&dirs, &id, 3, b"def"), ResponseStatus::NoContent);
+        assert_eq!(fs::read(dirs.root.join("a.txt")).unwrap(), b"abcdef");
+    }
+
+    #[test]
+    fn streams_patch_bodies_into_the_upload() {
+        let dirs = Dirs::new("tus-stream");
+        let uploads = TusHandler::new(&dirs.root).staging(&dirs.staging);
+        let id = create_upload(&dirs, 6, "a.txt");
+        let request = |offset: u64| {
+            let head = format!(
+                "PATCH /_tus/{} HTTP/1.1\r\nContent-Type: {}\r\nUpload-Offset: {}",
+                id, OFFSET_CONTENT_TYPE, offset
+            );
+            tus_request(&head, b"")
+        };
+        let stream = |request: &HttpRequest| match uploads.body_sink(request) {
+            BodySink::Stream(streamed) => streamed,
+            _ => panic!("body not streamed"),
+        };
+        let offset = || {
+            let upload = Upload::load(&dirs.staging, &id).unwrap().unwrap();
+            upload.offset(&dirs.staging).unwrap()
+        };
+
+        // Bytes land as they arrive, and no other PATCH can start meanwhile
+        let mut streamed = stream(&request(0));
+        streamed.write_all(b"ab").unwrap();
+        assert_eq!(offset(), 2);
+        match uploads.body_sink(&request(2)) {
+            BodySink::Reject(response) => assert_eq!(response.status, ResponseStatus::Conflict),
+            _ => panic!("concurrent PATCH accepted"),
+        }
+
+        // A body cut short keeps what arrived, and the next PATCH goes on from there
+        drop(streamed);
+        let mut request = request(2);
+        let mut streamed = stream(&request);
+        assert!(streamed.write_all(b"cdefg").is_err()); // Longer than the rest of the upload
+        streamed.write_all(b"cdef").unwrap();
+        request.streamed_body = Some(streamed);
+        let response = uploads.handle(&mut request).unwrap();
+        assert_eq!(response.status, ResponseStatus::NoContent);
+        assert_eq!(fs::read(dirs.root.join("a.txt")).unwrap(), b"abcdef");
+    }
+}
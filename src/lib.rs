@@ -1 +1,8 @@
-pub mod http;
\ No newline at end of file
+pub mod config;
+pub mod http;
+pub mod server;
+
+#[cfg(test)]
+mod testing;
+
+pub use server::{Server, ServerBuilder, ServerHandle};
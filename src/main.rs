@@ -1,74 +1,251 @@
 use std::{
-    io::{self, Read, Write}, // Input/Output operations
-    net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream}, // Networking modules for IPv4, sockets, and streams
-    path::Path, // For handling file paths
-    fs, // For file system operations
-    env, // For handling environment variables
+    env,     // For reading command line arguments
+    io,      // Input/Output operations
+    process, // For exiting with an error status
+    thread,  // For the default number of event loop threads and the second signal
 };
 
-use simple_http::http::{request, response}; // Importing the request and response modules from the custom `simple_http::http`
+use simple_http::config::Config;
+use simple_http::http::live_reload::LiveReload;
+use simple_http::http::middleware::{Logger, SecurityHeaders};
+#[cfg(unix)]
+use simple_http::server::upgrade::{self, Signal};
+use simple_http::{Server, ServerHandle};
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
 
-// Function to create a socket address (IPv4 localhost at port 5500)
-fn create_socket() -> SocketAddr {
-    SocketAddr::new(std::net::IpAddr::V4(Ipv4Addr::LOCALHOST), 5500)
-}
-
-// Function to handle individual client connections
-fn handle_client(stream: &mut TcpStream) -> io::Result<()> {
-    let mut buffer = [0; 1024]; // Buffer to store incoming client data
-    stream.read(&mut buffer)?; // Read data from the client into the buffer
-
-    let buf_str = String::from_utf8_lossy(&buffer); // Convert the buffer into a UTF-8 string
-    let request = request::HttpRequest::new(&buf_str)?; // Create a new HttpRequest object from the string
-
-    let response = request.response()?; // Generate the appropriate HttpResponse based on the request
-
-    println!("{:?}", &response); // Print the response for debugging purposes
+const USAGE: &str = "Usage: simple-http [--bind ADDRESS] [--root DIRECTORY] [--config FILE] [--dev] [--uploads]
+                   [--workers N | --async | --epoll [--threads N]]
 
-    // Create the HTTP response headers (including content length and type)
-    let headers = format!(
-        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nContent-Type: {}\r\n\r\n",
-        response.content_length, response.content_type
-    );
+Options:
+  -b, --bind ADDRESS     Address to listen on (default 127.0.0.1:5500)
+  -r, --root DIRECTORY   Directory to serve (default: the current directory)
+  -c, --config FILE      Read options (e.g. [mime_types]) from a TOML file
+      --dev              Reload pages in the browser when files change (Linux, not with --epoll)
+      --uploads          Accept tus uploads into the root at /_tus, from anyone who can connect
+      --workers N        Accept with N threads, each with its own SO_REUSEPORT listener
+      --async            Use the async backend (needs the `tokio` feature)
+      --epoll            Use the epoll event loop backend (needs the `epoll` feature, Linux only)
+      --threads N        Number of event loops for --epoll (default: one per CPU)
+  -h, --help             Print this help
 
-    // Write the headers and response body to the stream, sending the response to the client
-    stream.write(headers.as_bytes())?;
-    stream.write(&response.response_body)?;
-    stream.flush()?; // Ensure all data is written to the client
+Signals (Unix):
+  SIGTERM, SIGINT        Stop accepting, finish the requests in progress and exit
+  SIGUSR2                Start the current binary on the same socket, then drain and exit";
 
-    Ok(())
+// How connections are handled
+enum Backend {
+    Threads, // A thread per connection
+    Async,   // Tasks on a tokio runtime
+    Epoll,   // A few epoll event loops
 }
 
-// Function to listen for incoming connections and handle each client
-fn serve(socket: SocketAddr) -> io::Result<()> {
-    let listener = TcpListener::bind(socket)?; // Bind the socket to listen for incoming connections
-    let mut counter = 0; // Counter to track the number of client connections
+// Command line options
+struct Options {
+    bind: String,
+    root: Option<String>,
+    config: Option<String>,
+    dev: bool,
+    uploads: bool,
+    backend: Backend,
+    threads: usize,
+    workers: usize,
+}
 
-    // Loop through each incoming connection
-    for stream in listener.incoming() {
-        match stream {
-            Ok(mut stream) => {
-                // Spawn a new thread to handle the client connection
-                match std::thread::spawn(move || handle_client(&mut stream)).join() {
-                    Ok(_) => {
-                        counter += 1; // Increment the counter for each successful connection
-                        println!("connected stream... {}", counter); // Print connection number
-                    }
-                    Err(_) => continue, // If the thread fails, continue to the next client
-                };
+// Parses the command line arguments, exiting with the usage text on errors or --help
+fn parse_args() -> Options {
+    let mut options = Options {
+        bind: "127.0.0.1:5500".to_string(),
+        root: None,
+        config: None,
+        dev: false,
+        uploads: false,
+        backend: Backend::Threads,
+        threads: thread::available_parallelism().map_or(4, |count| count.get()),
+        workers: 1,
+    };
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-b" | "--bind" => {
+                options.bind = args
+                    .next()
+                    .unwrap_or_else(|| usage_error("--bind needs an address"))
+            }
+            "-r" | "--root" => {
+                options.root = Some(
+                    args.next()
+                        .unwrap_or_else(|| usage_error("--root needs a directory")),
+                )
+            }
+            "-c" | "--config" => {
+                options.config = Some(
+                    args.next()
+                        .unwrap_or_else(|| usage_error("--config needs a file")),
+                )
+            }
+            "--dev" if cfg!(target_os = "linux") => options.dev = true,
+            "--dev" => usage_error("--dev needs Linux to watch for changes"),
+            "--uploads" => options.uploads = true,
+            "--async" if cfg!(feature = "tokio") => options.backend = Backend::Async,
+            "--async" => {
+                usage_error("--async needs the server to be built with `--features tokio`")
+            }
+            "--epoll" if cfg!(all(feature = "epoll", target_os = "linux")) => {
+                options.backend = Backend::Epoll
             }
-            Err(e) => {
-                // Print any errors that occur while accepting a client connection
-                eprintln!("Failed to accept a client: {}", e);
+            "--epoll" => usage_error(
+                "--epoll needs Linux and the server to be built with `--features epoll`",
+            ),
+            "--threads" => options.threads = positive_number(args.next(), "--threads"),
+            "--workers" => options.workers = positive_number(args.next(), "--workers"),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
             }
+            other => usage_error(&format!("unknown argument: {}", other)),
         }
     }
-    Ok(())
+    if options.workers > 1 && !matches!(options.backend, Backend::Threads) {
+        usage_error("--workers only works with the default threaded backend");
+    }
+    // Event loops would be blocked by the open event streams
+    if options.dev && matches!(options.backend, Backend::Epoll) {
+        usage_error("--dev doesn't work with --epoll");
+    }
+    options
+}
+
+// Parses the value of a numeric option
+fn positive_number(value: Option<String>, option: &str) -> usize {
+    value
+        .and_then(|count| count.parse().ok())
+        .filter(|&count| count > 0)
+        .unwrap_or_else(|| usage_error(&format!("{} needs a positive number", option)))
+}
+
+// Prints what went wrong with the arguments and exits
+fn usage_error(msg: &str) -> ! {
+    eprintln!("{}\n\n{}", msg, USAGE);
+    process::exit(2);
 }
 
 // Main function to start the server
 fn main() -> io::Result<()> {
-    let socket = create_socket(); // Create a socket on localhost:5500
-    serve(socket)?; // Start the server and listen for incoming connections
-    Ok(())
+    let options = parse_args();
+    // Signals are waited for on the main thread, so block them before starting others
+    #[cfg(unix)]
+    upgrade::block_signals()?;
+    let mut builder = Server::builder().bind(options.bind.as_str());
+    if let Some(root) = options.root {
+        builder = builder.root(root);
+    }
+    let mut config = match options.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    if options.uploads {
+        config.uploads.enabled = true;
+    }
+    if config.uploads.enabled {
+        eprintln!("Warning: anyone who can connect may upload files into the root at /_tus");
+    }
+    let live_reload = LiveReload::new();
+    if options.dev {
+        // Pages get the live reload script, which can't be added to a compressed page
+        config.compression.enabled = false;
+        builder = builder.middleware(live_reload.clone());
+    }
+    builder = builder.config(config);
+    // After an upgrade, serve on the listeners handed over by the old process
+    #[cfg(unix)]
+    for listener in upgrade::inherited_listeners()? {
+        builder = builder.listener(listener);
+    }
+    // Log every request and add security headers to every response
+    let server = builder
+        .middleware(Logger)
+        .middleware(SecurityHeaders::new())
+        .workers(options.workers)
+        .build()?;
+    println!("Listening on http://{}", server.local_addr()?);
+    #[cfg(target_os = "linux")]
+    if options.dev {
+        match server.watcher() {
+            Some(watcher) => {
+                let live_reload = live_reload.clone();
+                watcher.on_change(move |_| live_reload.reload());
+            }
+            None => eprintln!("Not watching for changes, pages won't reload"),
+        }
+    }
+    #[cfg(unix)]
+    let file_cache = server.file_cache().cloned(); // For its statistics on shutdown
+    #[cfg(unix)]
+    let listeners: Vec<RawFd> = server.listeners().iter().map(AsRawFd::as_raw_fd).collect();
+    let handle = match options.backend {
+        Backend::Threads => server.spawn()?,
+        Backend::Async => spawn_async(server)?,
+        Backend::Epoll => spawn_epoll(server, options.threads)?,
+    };
+    #[cfg(unix)]
+    {
+        upgrade::notify_ready()?;
+        wait_for_signals(&listeners)?;
+        // A second signal stops the process without waiting for the connections
+        thread::spawn(|| {
+            let _ = upgrade::wait_for_signal();
+            process::exit(1);
+        });
+        println!("Finishing the requests in progress");
+        live_reload.close(); // Shutdown would wait for the pages' event streams
+        handle.shutdown()?;
+        if let Some(file_cache) = file_cache {
+            println!("File cache: {}", file_cache.stats());
+        }
+        Ok(())
+    }
+    // Serve until the process is stopped
+    #[cfg(not(unix))]
+    handle.join()
+}
+
+// Serves until SIGTERM or SIGINT, or until SIGUSR2 has started a new process on the same
+// listeners
+#[cfg(unix)]
+fn wait_for_signals(listeners: &[RawFd]) -> io::Result<()> {
+    loop {
+        match upgrade::wait_for_signal()? {
+            Signal::Shutdown => return Ok(()),
+            Signal::Upgrade => match upgrade::spawn_successor(listeners) {
+                Ok(child) => {
+                    println!("Upgraded to process {}", child.id());
+                    return Ok(());
+                }
+                Err(err) => eprintln!("Upgrade failed, still serving: {}", err),
+            },
+        }
+    }
+}
+
+// Serves on a tokio runtime in the background
+#[cfg(feature = "tokio")]
+fn spawn_async(server: Server) -> io::Result<ServerHandle> {
+    server.spawn_async()
+}
+
+#[cfg(not(feature = "tokio"))]
+fn spawn_async(_server: Server) -> io::Result<ServerHandle> {
+    usage_error("--async needs the server to be built with `--features tokio`")
+}
+
+// Serves with epoll event loops in the background
+#[cfg(all(feature = "epoll", target_os = "linux"))]
+fn spawn_epoll(server: Server, threads: usize) -> io::Result<ServerHandle> {
+    server.spawn_epoll(threads)
+}
+
+#[cfg(not(all(feature = "epoll", target_os = "linux")))]
+fn spawn_epoll(_server: Server, _threads: usize) -> io::Result<ServerHandle> {
+    usage_error("--epoll needs Linux and the server to be built with `--features epoll`")
 }
@@ -0,0 +1,381 @@
+use crate::http::chunked::ChunkedReader;
+use crate::http::handler::{BodySink, Handler};
+use crate::http::request::{BodyLength, HttpRequest, Method, ParseError, StreamedBody, Version};
+use crate::http::response::{HttpResponse, ResponseStatus};
+use std::io::{self, BufReader, Read, Write};
+use std::net::{Shutdown, TcpStream};
+
+// Size limits applied while reading requests
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Limits {
+    pub max_header_size: usize, // Largest request head (request line + headers) we accept
+    pub max_body_size: usize,   // Largest request body we accept
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_header_size: 64 * 1024,
+            max_body_size: 256 * 1024 * 1024,
+        }
+    }
+}
+
+// Reads the request head (request line and headers) from the client
+// Returns the parsed request along with any body bytes received after the head
+pub(crate) fn read_head(
+    stream: &mut impl Read,
+    limits: &Limits,
+) -> io::Result<(HttpRequest, Vec<u8>)> {
+    let mut buffer = Vec::new(); // Everything received so far
+    let mut chunk = [0; 1024]; // Buffer to store incoming client data
+    loop {
+        let read = stream.read(&mut chunk)?; // Read data from the client into the buffer
+        if read == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed before request head",
+            ));
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+        if let Some(head_end) = head_end(&buffer, limits)? {
+            return split_head(buffer, head_end);
+        }
+    }
+}
+
+// Finds the end of the request head in the bytes received so far
+// Fails once more than the allowed head size arrived without one
+pub(crate) fn head_end(buffer: &[u8], limits: &Limits) -> io::Result<Option<usize>> {
+    if let Some(pos) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+        return Ok(Some(pos + 4)); // The head ends with an empty line
+    }
+    if buffer.len() > limits.max_header_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "request head too large",
+        ));
+    }
+    Ok(None)
+}
+
+// Parses the head from the raw bytes, returning the request and the start of its body
+pub(crate) fn split_head(
+    mut buffer: Vec<u8>,
+    head_end: usize,
+) -> io::Result<(HttpRequest, Vec<u8>)> {
+    let request = HttpRequest::parse(&buffer[..head_end])?;
+    let leftover = buffer.split_off(head_end); // Whatever followed the head is the start of the body
+    Ok((request, leftover))
+}
+
+// Reads the request body, framed either by Content-Length or by chunked transfer encoding
+// A body the handler chose to stream goes into its writer instead of request_body
+pub(crate) fn read_body(
+    stream: &mut impl Read,
+    request: &mut HttpRequest,
+    leftover: Vec<u8>,
+    limits: &Limits,
+) -> io::Result<()> {
+    if let Some(mut streamed) = request.streamed_body.take() {
+        stream_body(stream, request, leftover, &mut streamed)?;
+        request.streamed_body = Some(streamed);
+        return Ok(());
+    }
+    match request.body_length()? {
+        BodyLength::None => {}
+        BodyLength::Fixed(length) => {
+            if length > limits.max_body_size {
+                return Err(io::Error::new(
+                    io::ErrorKind::FileTooLarge,
+                    "request body too large",
+                ));
+            }
+            let mut body = leftover;
+            if body.len() < length {
+                let remaining = (length - body.len()) as u64;
+                stream.take(remaining).read_to_end(&mut body)?;
+            }
+            if body.len() < length {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "truncated request body",
+                ));
+            }
+            body.truncate(length);
+            request.request_body = body;
+        }
+        BodyLength::Chunked => {
+            let source = BufReader::new(io::Cursor::new(leftover).chain(stream));
+            let mut decoder = ChunkedReader::new(source, limits.max_body_size as u64);
+            let mut body = Vec::new();
+            decoder.read_to_end(&mut body)?;
+            request.request_body = body;
+            request.request_trailers = decoder.trailers().to_vec();
+        }
+    }
+    Ok(())
+}
+
+// Copies the request body into the handler's writer as it arrives
+// The writer enforces its own limit, so the server's body size limit doesn't apply
+fn stream_body(
+    stream: &mut impl Read,
+    request: &mut HttpRequest,
+    leftover: Vec<u8>,
+    streamed: &mut StreamedBody,
+) -> io::Result<()> {
+    let source = io::Cursor::new(leftover).chain(stream);
+    match request.body_length()? {
+        BodyLength::None => {}
+        BodyLength::Fixed(length) => {
+            if io::copy(&mut source.take(length as u64), streamed)? < length as u64 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "truncated request body",
+                ));
+            }
+        }
+        BodyLength::Chunked => {
+            let mut decoder = ChunkedReader::new(BufReader::new(source), u64::MAX);
+            io::copy(&mut decoder, streamed)?;
+            request.request_trailers = decoder.trailers().to_vec();
+        }
+    }
+    streamed.flush()
+}
+
+// Maps errors raised while reading a request to the status sent back to the client
+pub(crate) fn error_status(err: &io::Error) -> Option<ResponseStatus> {
+    if let Some(parse_error) = err
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<ParseError>())
+    {
+        return Some(parse_error.status());
+    }
+    match err.kind() {
+        io::ErrorKind::InvalidData => Some(ResponseStatus::BadRequest),
+        io::ErrorKind::FileTooLarge => Some(ResponseStatus::PayloadTooLarge),
+        io::ErrorKind::Unsupported => Some(ResponseStatus::NotImplemented),
+        _ => None, // The connection itself failed; there's nobody to answer
+    }
+}
+
+// Writes a response to the client; HEAD responses get the headers only
+pub(crate) fn write_response(
+    stream: &mut impl Write,
+    response: HttpResponse,
+    head_only: bool,
+) -> io::Result<()> {
+    if head_only {
+        response.write_head_to(&mut *stream)?;
+        stream.flush()
+    } else {
+        response.write_to(stream)
+    }
+}
+
+// Decides whether and where to take in a request body before reading it
+// Returns the early final response for requests rejected on their headers alone, or where
+// the body goes
+pub(crate) fn precheck(request: &HttpRequest, handler: &dyn Handler, limits: &Limits) -> BodySink {
+    // 100-continue is the only expectation defined by HTTP
+    if let Some(expect) = request.header("Expect") {
+        if !expect.eq_ignore_ascii_case("100-continue") {
+            return BodySink::Reject(HttpResponse::with_status(ResponseStatus::ExpectationFailed));
+        }
+    }
+    // Endpoint specific checks (upload exists, offset matches, ...)
+    if let Some(rejection) = handler.precheck(request) {
+        return BodySink::Reject(rejection);
+    }
+    let sink = handler.body_sink(request);
+    if let (BodySink::Buffer, Ok(BodyLength::Fixed(length))) = (&sink, request.body_length()) {
+        if length > limits.max_body_size {
+            return BodySink::Reject(HttpResponse::with_status(ResponseStatus::PayloadTooLarge));
+        }
+    }
+    sink
+}
+
+// Whether the client waits for `100 Continue` before sending the body
+// HTTP/1.0 clients don't know 1xx responses, so their expectation is ignored (RFC 9110 section 10.1.1)
+pub(crate) fn expects_continue(request: &HttpRequest, leftover: &[u8]) -> bool {
+    let has_body = !matches!(
+        request.body_length(),
+        Ok(BodyLength::None) | Ok(BodyLength::Fixed(0))
+    );
+    let expects_continue = request
+        .header("Expect")
+        .is_some_and(|expect| expect.eq_ignore_ascii_case("100-continue"));
+    expects_continue && has_body && leftover.is_empty() && *request.version() != Version::V1_0
+}
+
+// Adapts a response to the client's protocol version before it is sent
+// HTTP/1.0 clients can't decode chunked bodies, so a body of unknown length goes out as is
+// and ends with the connection, which is closed after every HTTP/1.0 response
+pub(crate) fn fit_version(request: &HttpRequest, response: &mut HttpResponse) {
+    response.close_delimited = *request.version() == Version::V1_0;
+}
+
+// Sends an error response and closes the connection; any unread body is discarded
+fn reject(stream: &mut TcpStream, mut response: HttpResponse) -> io::Result<()> {
+    response.add_header("Connection", "close");
+    write_response(stream, response, false)?;
+    stream.shutdown(Shutdown::Write) // Tell the client no more data is coming
+}
+
+// Handles one client connection: reads a request, runs the handler and writes the response
+pub(crate) fn handle_client(
+    stream: &mut TcpStream,
+    handler: &dyn Handler,
+    limits: &Limits,
+) -> io::Result<()> {
+    let (mut request, leftover) = match read_head(stream, limits) {
+        Ok(head) => head,
+        Err(err) => {
+            // Malformed requests get an error status before the connection is closed
+            if let Some(status) = error_status(&err) {
+                reject(stream, HttpResponse::with_status(status))?;
+            }
+            return Err(err);
+        }
+    };
+    if let Ok(peer_addr) = stream.peer_addr() {
+        request.set_peer_addr(peer_addr); // Let handlers see who they are talking to
+    }
+
+    // Answer from the headers alone when possible, so rejected uploads don't transfer their body
+    match precheck(&request, handler, limits) {
+        BodySink::Reject(rejection) => return reject(stream, rejection),
+        BodySink::Stream(streamed) => request.streamed_body = Some(streamed),
+        BodySink::Buffer => {}
+    }
+    if expects_continue(&request, &leftover) {
+        stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?; // The client waits for this before sending the body
+        stream.flush()?;
+    }
+
+    if let Err(err) = read_body(stream, &mut request, leftover, limits) {
+        if let Some(status) = error_status(&err) {
+            reject(stream, HttpResponse::with_status(status))?;
+        }
+        return Err(err);
+    }
+
+    // Generate the appropriate HttpResponse based on the request
+    let mut response = match handler.handle(&mut request) {
+        Ok(response) => response,
+        Err(err) => {
+            reject(
+                stream,
+                HttpResponse::with_status(ResponseStatus::InternalServerError),
+            )?;
+            return Err(err);
+        }
+    };
+
+    fit_version(&request, &mut response);
+    write_response(stream, response, *request.method() == Method::Head)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::http::request::HttpRequest;
+    use crate::http::response::HttpResponse;
+    use crate::server::Server;
+    use crate::testing::{echo, exchange, StreamingEcho};
+
+    #[test]
+    fn reads_bodies_sent_in_pieces() {
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .handler(echo)
+            .max_body_size(16)
+            .spawn()
+            .unwrap();
+        let chunked = b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n";
+        let pieces: [&[u8]; 4] = [chunked, b"5\r\nhel", b"lo\r\n0\r\nX-Sum: 1\r", b"\n\r\n"];
+        let response = exchange(server.addr(), &pieces);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(
+            response.ends_with("\r\n\r\nhello\nX-Sum: 1"),
+            "{}",
+            response
+        );
+
+        let pieces: [&[u8]; 2] = [
+            b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\n\r\nhe",
+            b"llo",
+        ];
+        let response = exchange(server.addr(), &pieces);
+        assert!(response.ends_with("\r\n\r\nhello"), "{}", response);
+
+        // Declared lengths over the limit are refused before the body is read
+        let pieces: [&[u8]; 1] = [b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 17\r\n\r\n"];
+        let response = exchange(server.addr(), &pieces);
+        assert!(response.starts_with("HTTP/1.1 413 "), "{}", response);
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn streams_bodies_past_the_body_limit() {
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .handler(StreamingEcho::default())
+            .max_body_size(4)
+            .spawn()
+            .unwrap();
+        let pieces: [&[u8]; 2] = [
+            b"PUT / HTTP/1.1\r\nHost: a\r\nContent-Length: 11\r\n\r\nhello",
+            b" world",
+        ];
+        let response = exchange(server.addr(), &pieces);
+        assert!(response.ends_with("\r\n\r\nhello world"), "{}", response);
+
+        let chunked = b"PUT / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n";
+        let pieces: [&[u8]; 3] = [
+            chunked,
+            b"6\r\nhello \r\n",
+            b"5\r\nworld\r\n0\r\nX-Sum: 1\r\n\r\n",
+        ];
+        let response = exchange(server.addr(), &pieces);
+        assert!(
+            response.ends_with("\r\n\r\nhello world\nX-Sum: 1"),
+            "{}",
+            response
+        );
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn answers_http_1_0_clients() {
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .handler(|request: &mut HttpRequest| {
+                let body = std::io::Cursor::new(request.request_body.clone());
+                Ok(HttpResponse::builder().reader(body, None).build())
+            })
+            .spawn()
+            .unwrap();
+        // Without a Host header, and with a body of unknown length that can't be chunked
+        let pieces: [&[u8]; 1] = [b"GET / HTTP/1.0\r\n\r\n"];
+        let response = exchange(server.addr(), &pieces);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(!response.contains("Transfer-Encoding"), "{}", response);
+
+        // The expectation is ignored, as HTTP/1.0 clients don't wait for 100 Continue
+        let pieces: [&[u8]; 2] = [
+            b"POST / HTTP/1.0\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\n",
+            b"hello",
+        ];
+        let response = exchange(server.addr(), &pieces);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.ends_with("\r\n\r\nhello"), "{}", response);
+
+        let pieces: [&[u8]; 1] = [b"GET / HTTP/2.0\r\n\r\n"];
+        let response = exchange(server.addr(), &pieces);
+        assert!(response.starts_with("HTTP/1.1 505 "), "{}", response);
+        server.shutdown().unwrap();
+    }
+}
@@ -0,0 +1,1082 @@
+// Event loop backend on Linux epoll, enabled with the `epoll` cargo feature
+//
+// A few threads each run their own epoll instance and share the listening socket
+// (registered with EPOLLEXCLUSIVE so a new connection wakes a single thread). Sockets are
+// non-blocking and every connection is a small state machine advanced whenever its socket
+// is ready, so idle keep-alive connections cost a buffer and a map entry, not a thread.
+// Anything that may block on the disk (handlers, writes of streamed request bodies and
+// reads of response bodies) runs on a pool of threads shared by the loops; the connection
+// waits unregistered until the pool hands the result back, while the loop serves the others.
+
+use super::connection::{self, Limits};
+use super::{Server, ServerBuilder, ServerHandle, READ_TIMEOUT};
+use crate::http::chunked::ChunkedDecoder;
+use crate::http::handler::{BodySink, Handler};
+use crate::http::request::{BodyLength, HttpRequest, Method, Version};
+use crate::http::response::{HttpResponse, ResponseBody, ResponseStatus};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+const LISTENER: u64 = u64::MAX; // Event token of the listening socket
+const WAKER: u64 = u64::MAX - 1; // Event token of the eventfd the pool signals finished work on
+const MAX_EVENTS: usize = 1024; // Events handled per wakeup
+const TICK: Duration = Duration::from_secs(1); // How often timeouts and shutdown are checked
+const KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(60); // How long an idle keep-alive connection stays open
+const BLOCK_SIZE: usize = 64 * 1024; // How much of a response body is read at a time
+const POOL_THREADS_PER_LOOP: usize = 4; // Pool threads per event loop, so a few slow disks don't hold up the rest
+
+impl ServerBuilder {
+    // Builds the server and serves requests with `threads` epoll event loops
+    pub fn run_epoll(self, threads: usize) -> io::Result<()> {
+        self.build()?.run_epoll(threads)
+    }
+
+    // Builds the server and runs its epoll event loops in the background
+    pub fn spawn_epoll(self, threads: usize) -> io::Result<ServerHandle> {
+        self.build()?.spawn_epoll(threads)
+    }
+}
+
+impl Server {
+    // Serves requests with `threads` epoll event loops until shut down, then lets them
+    // finish the requests in progress
+    pub fn run_epoll(mut self, threads: usize) -> io::Result<()> {
+        let listener = self.single_listener()?;
+        listener.set_nonblocking(true)?;
+        let listener = Arc::new(listener);
+        let threads = threads.max(1);
+        let pool = Pool::new(threads * POOL_THREADS_PER_LOOP)?; // Stopped once the loops are done
+        let workers = (0..threads)
+            .map(|index| {
+                let listener = Arc::clone(&listener);
+                let handler = Arc::clone(&self.handler);
+                let shutdown = Arc::clone(&self.shutdown);
+                let limits = self.limits;
+                let jobs = pool.jobs();
+                thread::Builder::new()
+                    .name(format!("simple-http-epoll-{}", index))
+                    .spawn(move || event_loop(&listener, handler, limits, &shutdown, jobs))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let mut result = Ok(());
+        for worker in workers {
+            let outcome = worker
+                .join()
+                .unwrap_or_else(|_| Err(io::Error::other("event loop panicked")));
+            if let Err(err) = outcome {
+                eprintln!("Event loop failed: {}", err);
+                result = Err(err);
+            }
+        }
+        result
+    }
+
+    // Runs the epoll event loops on background threads, returning a handle to stop them
+    pub fn spawn_epoll(self, threads: usize) -> io::Result<ServerHandle> {
+        let addr = self.local_addr()?;
+        let shutdown = Arc::clone(&self.shutdown);
+        let thread = thread::Builder::new()
+            .name("simple-http".to_string())
+            .spawn(move || self.run_epoll(threads))?;
+        Ok(ServerHandle {
+            addr,
+            shutdown,
+            thread,
+            control: None,
+        })
+    }
+}
+
+// Runs one event loop until shutdown is requested and its connections are done
+fn event_loop(
+    listener: &TcpListener,
+    handler: Arc<dyn Handler>,
+    limits: Limits,
+    shutdown: &AtomicBool,
+    jobs: Sender<Job>,
+) -> io::Result<()> {
+    let epoll = Epoll::new()?;
+    epoll.add(
+        listener.as_raw_fd(),
+        (libc::EPOLLIN | libc::EPOLLEXCLUSIVE) as u32,
+        LISTENER,
+    )?;
+    let waker = Arc::new(Waker::new()?);
+    epoll.add(waker.fd, libc::EPOLLIN as u32, WAKER)?;
+    let (done, finished) = mpsc::channel();
+    let dispatcher = Dispatcher {
+        handler,
+        jobs,
+        done,
+        waker: Arc::clone(&waker),
+    };
+    let mut connections: HashMap<u64, Connection> = HashMap::new();
+    let mut next_token = 0;
+    let mut events = Vec::with_capacity(MAX_EVENTS);
+    let mut accepting = true;
+
+    loop {
+        if accepting && shutdown.load(Ordering::SeqCst) {
+            epoll.delete(listener.as_raw_fd())?;
+            accepting = false;
+            // Connections this loop was woken for are ours to serve; with EPOLLEXCLUSIVE a
+            // new process sharing the socket after an upgrade would not be woken for them
+            accept_all(listener, &epoll, &mut connections, &mut next_token);
+        }
+        if !accepting {
+            // Draining: idle keep-alive connections have nothing left to finish
+            connections.retain(|_, connection| !connection.is_idle());
+            if connections.is_empty() {
+                return Ok(());
+            }
+        }
+
+        epoll.wait(&mut events, TICK)?;
+        for event in &events {
+            match event.u64 {
+                LISTENER => accept_all(listener, &epoll, &mut connections, &mut next_token),
+                WAKER => {
+                    waker.reset();
+                    for (token, completion) in finished.try_iter() {
+                        if let Some(connection) = connections.get_mut(&token) {
+                            connection.complete(completion, &dispatcher);
+                            drive(&mut connections, token, &epoll, &dispatcher, &limits);
+                        }
+                    }
+                }
+                token => drive(&mut connections, token, &epoll, &dispatcher, &limits),
+            }
+        }
+
+        let now = Instant::now();
+        connections.retain(|_, connection| !connection.timed_out(now));
+    }
+}
+
+// Accepts every pending connection and registers it with the event loop
+fn accept_all(
+    listener: &TcpListener,
+    epoll: &Epoll,
+    connections: &mut HashMap<u64, Connection>,
+    next_token: &mut u64,
+) {
+    loop {
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => return,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => {
+                eprintln!("Failed to accept a client: {}", err);
+                return;
+            }
+        };
+        if stream.set_nonblocking(true).is_err() {
+            continue;
+        }
+        // Tokens aren't reused, so work finished for a closed connection can't reach another
+        let token = *next_token;
+        *next_token += 1;
+        if epoll
+            .add(stream.as_raw_fd(), libc::EPOLLIN as u32, token)
+            .is_ok()
+        {
+            connections.insert(token, Connection::new(stream, token));
+        }
+    }
+}
+
+// Advances a connection as far as it goes and registers what it waits for next, dropping
+// it once it is done
+fn drive(
+    connections: &mut HashMap<u64, Connection>,
+    token: u64,
+    epoll: &Epoll,
+    dispatcher: &Dispatcher,
+    limits: &Limits,
+) {
+    let keep = match connections.get_mut(&token) {
+        Some(connection) => match connection.advance(dispatcher, limits) {
+            Ok(Interest::Close) | Err(_) => false,
+            Ok(interest) => connection.listen_for(epoll, interest).is_ok(),
+        },
+        None => return,
+    };
+    if !keep {
+        connections.remove(&token); // Dropping the stream closes it and unregisters it
+    }
+}
+
+// What a connection waits for before it can make progress
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Interest {
+    Read,
+    Write,
+    Pool, // Nothing from the socket: the pool is working for the connection
+    Close,
+}
+
+// Where a connection is in the request/response cycle
+enum State {
+    ReadingHeaders,
+    ReadingBody(Box<HttpRequest>, BodyLength), // Boxed, the request is by far the largest state
+    Working,                                   // Waiting for the pool to hand back a Completion
+    Sending(Option<Body>), // Sending the output, then the next block of the body if there is one
+    Closing,               // Sending the last bytes before closing the connection
+}
+
+// A response body read while it is sent
+enum Body {
+    Fixed(io::Take<Box<dyn Read + Send>>), // Content-Length body, limited to the advertised length
+    Chunked(Box<dyn Read + Send>, bool), // Body of unknown length and whether the last chunk was sent
+    UntilClose(Box<dyn Read + Send>), // Body of unknown length for an HTTP/1.0 client, ended by closing
+}
+
+// Work the pool did for a connection
+enum Completion {
+    Response(io::Result<HttpResponse>, bool), // The handler's answer, and whether only its head is sent
+    // Streamed body bytes went into the handler's writer; what is left of the body, None once all of it did
+    Written(Box<HttpRequest>, Option<BodyLength>, io::Result<()>),
+    Block(Vec<u8>, io::Result<Option<Body>>), // The next framed piece of a response body, and the rest of the body
+    Panicked,                                 // The work panicked
+}
+
+// One client connection and its buffers
+struct Connection {
+    stream: TcpStream,
+    token: u64, // Names the connection to epoll and the pool
+    state: State,
+    input: Vec<u8>, // Received bytes not consumed yet (possibly pipelined requests)
+    chunked: Option<ChunkedDecoder>, // Decoding state of a chunked body being received
+    output: Vec<u8>, // Bytes waiting to be sent
+    written: usize, // How much of the output has been sent
+    keep_alive: bool,
+    served: bool,       // Whether a response was completed, so waiting means keep-alive
+    interest: Interest, // What the socket is registered for
+    last_active: Instant,
+}
+
+impl Connection {
+    fn new(stream: TcpStream, token: u64) -> Connection {
+        Connection {
+            stream,
+            token,
+            state: State::ReadingHeaders,
+            input: Vec::new(),
+            chunked: None,
+            output: Vec::new(),
+            written: 0,
+            keep_alive: true,
+            served: false,
+            interest: Interest::Read,
+            last_active: Instant::now(),
+        }
+    }
+
+    // Whether the connection sits between requests with nothing received
+    // A new connection is not idle: its client is about to send the first request
+    fn is_idle(&self) -> bool {
+        self.served
+            && matches!(self.state, State::ReadingHeaders)
+            && self.input.is_empty()
+            && self.output.is_empty()
+    }
+
+    // Whether the client has been silent (or not reading) for too long
+    // Time spent waiting for the pool doesn't count against the client
+    fn timed_out(&self, now: Instant) -> bool {
+        let limit = if self.is_idle() {
+            KEEP_ALIVE_TIMEOUT
+        } else {
+            READ_TIMEOUT
+        };
+        !matches!(self.state, State::Working) && now.duration_since(self.last_active) > limit
+    }
+
+    // Switches the socket registration to what the connection waits for when needed
+    // While the pool works the socket is left out entirely, so neither a hangup nor
+    // pipelined requests wake the loop for nothing
+    fn listen_for(&mut self, epoll: &Epoll, interest: Interest) -> io::Result<()> {
+        if interest != self.interest {
+            let fd = self.stream.as_raw_fd();
+            let events = match interest {
+                Interest::Write => libc::EPOLLOUT,
+                _ => libc::EPOLLIN,
+            } as u32;
+            match (self.interest, interest) {
+                (_, Interest::Pool) => epoll.delete(fd)?,
+                (Interest::Pool, _) => epoll.add(fd, events, self.token)?,
+                _ => epoll.modify(fd, events, self.token)?,
+            }
+            self.interest = interest;
+        }
+        Ok(())
+    }
+
+    // Makes as much progress as the socket allows, returning what to wait for next
+    fn advance(&mut self, dispatcher: &Dispatcher, limits: &Limits) -> io::Result<Interest> {
+        self.last_active = Instant::now();
+        loop {
+            // Pending output always goes first
+            if self.written < self.output.len() {
+                match self.stream.write(&self.output[self.written..]) {
+                    Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+                    Ok(written) => self.written += written,
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                        return Ok(Interest::Write)
+                    }
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                    Err(err) => return Err(err),
+                }
+                continue;
+            }
+            self.output.clear();
+            self.written = 0;
+
+            match mem::replace(&mut self.state, State::Closing) {
+                State::ReadingHeaders => match connection::head_end(&self.input, limits) {
+                    Ok(Some(head_end)) => {
+                        let rest = self.input.split_off(head_end);
+                        let head = mem::replace(&mut self.input, rest);
+                        match HttpRequest::parse(&head) {
+                            Ok(request) => self.start_request(request, dispatcher, limits),
+                            Err(err) => self.reject_error(&err),
+                        }
+                    }
+                    Ok(None) => {
+                        self.state = State::ReadingHeaders;
+                        if !self.fill_input()? {
+                            // Nothing more yet; a clean close between requests is fine
+                            return Ok(if self.is_closed() {
+                                Interest::Close
+                            } else {
+                                Interest::Read
+                            });
+                        }
+                    }
+                    Err(err) => self.reject_error(&err),
+                },
+                State::ReadingBody(request, length) => {
+                    if !self.read_body(*request, length, dispatcher, limits)? {
+                        return Ok(Interest::Read);
+                    }
+                }
+                State::Working => {
+                    self.state = State::Working;
+                    return Ok(Interest::Pool);
+                }
+                State::Sending(Some(body)) => self.read_block(body, dispatcher),
+                State::Sending(None) => {
+                    if !self.finish_response() {
+                        return Ok(Interest::Close);
+                    }
+                }
+                State::Closing => {
+                    let _ = self.stream.shutdown(Shutdown::Write); // Tell the client no more data is coming
+                    return Ok(Interest::Close);
+                }
+            }
+        }
+    }
+
+    // Takes back what the pool did for the connection; advance carries on from there
+    fn complete(&mut self, completion: Completion, dispatcher: &Dispatcher) {
+        match completion {
+            Completion::Response(Ok(response), head_only) => {
+                self.queue_response(response, head_only)
+            }
+            Completion::Response(Err(_), _) => self.reject(HttpResponse::with_status(
+                ResponseStatus::InternalServerError,
+            )),
+            Completion::Written(_, _, Err(err)) => self.reject_error(&err),
+            Completion::Written(request, Some(length), Ok(())) => {
+                self.state = State::ReadingBody(request, length)
+            }
+            Completion::Written(request, None, Ok(())) => self.respond(*request, dispatcher),
+            Completion::Block(block, Ok(body)) => {
+                self.output = block;
+                self.state = State::Sending(body);
+            }
+            // Part of the response may be out already, so all that is left is to close,
+            // as a thread of the threaded backend would
+            Completion::Block(_, Err(_)) | Completion::Panicked => {
+                self.keep_alive = false;
+                self.state = State::Closing;
+            }
+        }
+    }
+
+    // Reads whatever the socket has into the input buffer
+    // Returns false when there is nothing to read right now or the client closed its side
+    fn fill_input(&mut self) -> io::Result<bool> {
+        let mut chunk = [0; 4096];
+        loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => {
+                    self.keep_alive = false;
+                    self.state = State::Closing;
+                    return Ok(false);
+                }
+                Ok(read) => {
+                    self.input.extend_from_slice(&chunk[..read]);
+                    return Ok(true);
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    // Whether the client closed its side of the connection
+    fn is_closed(&self) -> bool {
+        matches!(self.state, State::Closing)
+    }
+
+    // Handles a request whose head has been parsed
+    fn start_request(
+        &mut self,
+        mut request: HttpRequest,
+        dispatcher: &Dispatcher,
+        limits: &Limits,
+    ) {
+        if let Ok(peer_addr) = self.stream.peer_addr() {
+            request.set_peer_addr(peer_addr); // Let handlers see who they are talking to
+        }
+        self.keep_alive = wants_keep_alive(&request);
+
+        // Answer from the headers alone when possible, so rejected uploads don't transfer their body
+        match connection::precheck(&request, dispatcher.handler.as_ref(), limits) {
+            BodySink::Reject(rejection) => return self.reject(rejection),
+            BodySink::Stream(streamed) => request.streamed_body = Some(streamed),
+            BodySink::Buffer => {}
+        }
+        if connection::expects_continue(&request, &self.input) {
+            self.output
+                .extend_from_slice(b"HTTP/1.1 100 Continue\r\n\r\n"); // The client waits for this
+        }
+        match request.body_length() {
+            Ok(length) => self.state = State::ReadingBody(Box::new(request), length),
+            Err(err) => self.reject_error(&err),
+        }
+    }
+
+    // Collects the request body from the input, reading more when needed
+    // A body the handler chose to stream goes to the pool, piece by piece as it arrives,
+    // to be written into the handler's writer
+    // Returns false while waiting for the rest of the body to arrive
+    fn read_body(
+        &mut self,
+        mut request: HttpRequest,
+        length: BodyLength,
+        dispatcher: &Dispatcher,
+        limits: &Limits,
+    ) -> io::Result<bool> {
+        match length {
+            BodyLength::None => {}
+            BodyLength::Fixed(length) if request.streamed_body.is_some() => {
+                let take = self.input.len().min(length);
+                if take == 0 && length > 0 {
+                    return self.wait_for_body(request, BodyLength::Fixed(length));
+                }
+                let bytes = self.input.drain(..take).collect();
+                let rest = (take < length).then_some(BodyLength::Fixed(length - take));
+                self.write_streamed(request, bytes, rest, dispatcher);
+                return Ok(true);
+            }
+            BodyLength::Fixed(length) => {
+                if length > limits.max_body_size {
+                    self.reject(HttpResponse::with_status(ResponseStatus::PayloadTooLarge));
+                    return Ok(true);
+                }
+                if self.input.len() < length {
+                    return self.wait_for_body(request, BodyLength::Fixed(length));
+                }
+                let rest = self.input.split_off(length);
+                request.request_body = mem::replace(&mut self.input, rest);
+            }
+            BodyLength::Chunked => {
+                // Only the bytes that arrived since the last call are decoded; the decoder
+                // enforces the body size limit as it goes, unless the writer does
+                let limit = match request.streamed_body {
+                    Some(_) => u64::MAX,
+                    None => limits.max_body_size as u64,
+                };
+                let decoder = self
+                    .chunked
+                    .get_or_insert_with(|| ChunkedDecoder::new(limit));
+                let used = match decoder.feed(&self.input) {
+                    Ok(used) => used,
+                    Err(err) => {
+                        self.chunked = None;
+                        self.reject_error(&err);
+                        return Ok(true);
+                    }
+                };
+                self.input.drain(..used);
+                let decoded = match request.streamed_body {
+                    Some(_) => decoder.take_body(),
+                    None => Vec::new(),
+                };
+                let rest = match self.chunked.take_if(|decoder| decoder.is_done()) {
+                    Some(decoder) => {
+                        let (body, trailers) = decoder.into_parts();
+                        request.request_body = body; // Empty when it was streamed
+                        request.request_trailers = trailers;
+                        None
+                    }
+                    None => Some(BodyLength::Chunked),
+                };
+                if request.streamed_body.is_some() && (!decoded.is_empty() || rest.is_none()) {
+                    self.write_streamed(request, decoded, rest, dispatcher);
+                    return Ok(true);
+                }
+                if let Some(rest) = rest {
+                    return self.wait_for_body(request, rest);
+                }
+            }
+        }
+        if request.streamed_body.is_some() {
+            self.write_streamed(request, Vec::new(), None, dispatcher); // Only flushes the writer
+        } else {
+            self.respond(request, dispatcher);
+        }
+        Ok(true)
+    }
+
+    // Reads more of a body, keeping the request until it is complete
+    // Returns false when nothing arrived yet
+    fn wait_for_body(&mut self, request: HttpRequest, length: BodyLength) -> io::Result<bool> {
+        self.state = State::ReadingBody(Box::new(request), length);
+        let received = self.fill_input()?;
+        if self.is_closed() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "truncated request body",
+            ));
+        }
+        Ok(received)
+    }
+
+    // Has the pool write part of a streamed body into the handler's writer, flushing it
+    // after the last part (`rest` is None)
+    fn write_streamed(
+        &mut self,
+        mut request: HttpRequest,
+        bytes: Vec<u8>,
+        rest: Option<BodyLength>,
+        dispatcher: &Dispatcher,
+    ) {
+        self.state = State::Working;
+        dispatcher.run(self.token, move || {
+            let written = match &mut request.streamed_body {
+                Some(streamed) => streamed.write_all(&bytes).and_then(|()| match rest {
+                    Some(_) => Ok(()),
+                    None => streamed.flush(),
+                }),
+                None => Ok(()),
+            };
+            Completion::Written(Box::new(request), rest, written)
+        });
+    }
+
+    // Has the pool run the handler
+    fn respond(&mut self, mut request: HttpRequest, dispatcher: &Dispatcher) {
+        self.state = State::Working;
+        let handler = Arc::clone(&dispatcher.handler);
+        dispatcher.run(self.token, move || {
+            let response = handler.handle(&mut request).map(|mut response| {
+                connection::fit_version(&request, &mut response);
+                response
+            });
+            Completion::Response(response, *request.method() == Method::Head)
+        });
+    }
+
+    // Has the pool read the next block of a response body
+    fn read_block(&mut self, body: Body, dispatcher: &Dispatcher) {
+        self.state = State::Working;
+        dispatcher.run(self.token, move || {
+            let mut block = Vec::new();
+            let body = next_block(body, &mut block);
+            Completion::Block(block, body)
+        });
+    }
+
+    // Queues a response head, along with its body unless it has to be streamed
+    fn queue_response(&mut self, mut response: HttpResponse, head_only: bool) {
+        if !self.keep_alive {
+            response.add_header("Connection", "close");
+        }
+        // Writing into a Vec can't fail
+        let _ = response.write_head_to(&mut self.output);
+        let body = if head_only || !response.allows_body() {
+            None
+        } else {
+            match response.response_body {
+                ResponseBody::Bytes(bytes) => {
+                    self.output.extend_from_slice(&bytes);
+                    None
+                }
+                ResponseBody::Reader(reader) => {
+                    Some(Body::Fixed(reader.take(response.content_length as u64)))
+                }
+                ResponseBody::Stream(reader) if response.close_delimited => {
+                    Some(Body::UntilClose(reader))
+                }
+                ResponseBody::Stream(reader) => Some(Body::Chunked(reader, false)),
+            }
+        };
+        self.state = State::Sending(body);
+    }
+
+    // Sends an error response and closes the connection afterwards
+    fn reject(&mut self, response: HttpResponse) {
+        self.keep_alive = false;
+        self.queue_response(response, false);
+    }
+
+    // Answers a malformed request with the matching status, or just closes the connection
+    fn reject_error(&mut self, err: &io::Error) {
+        match connection::error_status(err) {
+            Some(status) => self.reject(HttpResponse::with_status(status)),
+            None => {
+                self.keep_alive = false;
+                self.state = State::Closing;
+            }
+        }
+    }
+
+    // Moves on once a response has been sent
+    // Returns false when the connection should be closed
+    fn finish_response(&mut self) -> bool {
+        if self.keep_alive {
+            self.state = State::ReadingHeaders; // Pipelined requests may already be in the input
+            self.served = true;
+            true
+        } else {
+            let _ = self.stream.shutdown(Shutdown::Write);
+            false
+        }
+    }
+}
+
+// HTTP/1.1 connections stay open unless the client asks otherwise
+fn wants_keep_alive(request: &HttpRequest) -> bool {
+    *request.version() == Version::V1_1
+        && !request
+            .header_values("Connection")
+            .flat_map(|value| value.split(','))
+            .any(|option| option.trim().eq_ignore_ascii_case("close"))
+}
+
+// Reads the next block of a response body into `output`, framed as the body is sent
+// Returns the body again unless it is complete
+fn next_block(body: Body, output: &mut Vec<u8>) -> io::Result<Option<Body>> {
+    let mut block = vec![0; BLOCK_SIZE];
+    match body {
+        Body::Fixed(mut reader) => {
+            let read = read_retrying(&mut reader, &mut block)?;
+            if read == 0 {
+                if reader.limit() > 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "response body shorter than its length",
+                    ));
+                }
+                return Ok(None);
+            }
+            output.extend_from_slice(&block[..read]);
+            Ok(Some(Body::Fixed(reader)))
+        }
+        Body::UntilClose(mut reader) => {
+            let read = read_retrying(&mut reader, &mut block)?;
+            if read == 0 {
+                return Ok(None);
+            }
+            output.extend_from_slice(&block[..read]);
+            Ok(Some(Body::UntilClose(reader)))
+        }
+        Body::Chunked(_, true) => Ok(None),
+        Body::Chunked(mut reader, false) => {
+            let read = read_retrying(&mut reader, &mut block)?;
+            if read == 0 {
+                output.extend_from_slice(b"0\r\n\r\n"); // Last chunk
+                return Ok(Some(Body::Chunked(reader, true)));
+            }
+            output.extend_from_slice(format!("{:X}\r\n", read).as_bytes());
+            output.extend_from_slice(&block[..read]);
+            output.extend_from_slice(b"\r\n");
+            Ok(Some(Body::Chunked(reader, false)))
+        }
+    }
+}
+
+// Reads from a blocking reader (a file or a rendered listing), retrying interrupted reads
+fn read_retrying(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    loop {
+        match reader.read(buf) {
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            result => return result,
+        }
+    }
+}
+
+// Work for a pool thread
+type Job = Box<dyn FnOnce() + Send>;
+
+// Threads running jobs for the event loops; dropping the pool waits for them to finish
+struct Pool {
+    jobs: Option<Sender<Job>>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl Pool {
+    fn new(size: usize) -> io::Result<Pool> {
+        let (jobs, queue) = mpsc::channel::<Job>();
+        let queue = Arc::new(Mutex::new(queue));
+        let threads = (0..size)
+            .map(|index| {
+                let queue = Arc::clone(&queue);
+                thread::Builder::new()
+                    .name(format!("simple-http-pool-{}", index))
+                    .spawn(move || run_jobs(&queue))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(Pool {
+            jobs: Some(jobs),
+            threads,
+        })
+    }
+
+    // A sender for an event loop to queue jobs with
+    fn jobs(&self) -> Sender<Job> {
+        self.jobs.clone().expect("pool is running")
+    }
+}
+
+impl Drop for Pool {
+    fn drop(&mut self) {
+        self.jobs = None; // The threads stop once every sender is gone and the queue is empty
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+// Runs queued jobs until the pool stops
+fn run_jobs(queue: &Mutex<Receiver<Job>>) {
+    loop {
+        let job = queue
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .recv();
+        match job {
+            Ok(job) => job(),
+            Err(_) => return,
+        }
+    }
+}
+
+// What an event loop needs to hand work to the pool and get the results back
+struct Dispatcher {
+    handler: Arc<dyn Handler>,
+    jobs: Sender<Job>,
+    done: Sender<(u64, Completion)>, // Read by the event loop once the waker wakes it
+    waker: Arc<Waker>,
+}
+
+impl Dispatcher {
+    // Runs `work` for the connection with `token` on the pool
+    fn run(&self, token: u64, work: impl FnOnce() -> Completion + Send + 'static) {
+        let done = self.done.clone();
+        let waker = Arc::clone(&self.waker);
+        let job: Job = Box::new(move || {
+            let completion =
+                panic::catch_unwind(AssertUnwindSafe(work)).unwrap_or(Completion::Panicked);
+            if done.send((token, completion)).is_ok() {
+                waker.wake();
+            }
+        });
+        // The pool outlives the event loops, but should it be gone the loop does the work
+        if let Err(mpsc::SendError(job)) = self.jobs.send(job) {
+            job();
+        }
+    }
+}
+
+// An eventfd that wakes an event loop when the pool finished work for it
+struct Waker {
+    fd: RawFd,
+}
+
+impl Waker {
+    fn new() -> io::Result<Waker> {
+        // SAFETY: eventfd has no memory safety preconditions
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Waker { fd })
+    }
+
+    // Makes the eventfd readable; the counter can't overflow before the loop resets it
+    fn wake(&self) {
+        let one: u64 = 1;
+        // SAFETY: the eventfd is open for as long as the Waker, and `one` is the 8 bytes
+        // eventfd expects
+        unsafe { libc::write(self.fd, (&one as *const u64).cast(), 8) };
+    }
+
+    // Makes the eventfd unreadable again until the next wake
+    fn reset(&self) {
+        let mut count: u64 = 0;
+        // SAFETY: as in wake; `count` has room for the 8 bytes read
+        unsafe { libc::read(self.fd, (&mut count as *mut u64).cast(), 8) };
+    }
+}
+
+impl Drop for Waker {
+    fn drop(&mut self) {
+        // SAFETY: the descriptor is owned by this Waker and closed only here
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+// Owns an epoll instance
+struct Epoll {
+    fd: RawFd,
+}
+
+impl Epoll {
+    fn new() -> io::Result<Epoll> {
+        // SAFETY: epoll_create1 has no memory safety preconditions
+        let fd = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Epoll { fd })
+    }
+
+    fn control(&self, op: libc::c_int, fd: RawFd, events: u32, token: u64) -> io::Result<()> {
+        let mut event = libc::epoll_event { events, u64: token };
+        // SAFETY: `event` is a valid epoll_event for the duration of the call
+        if unsafe { libc::epoll_ctl(self.fd, op, fd, &mut event) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn add(&self, fd: RawFd, events: u32, token: u64) -> io::Result<()> {
+        self.control(libc::EPOLL_CTL_ADD, fd, events, token)
+    }
+
+    fn modify(&self, fd: RawFd, events: u32, token: u64) -> io::Result<()> {
+        self.control(libc::EPOLL_CTL_MOD, fd, events, token)
+    }
+
+    fn delete(&self, fd: RawFd) -> io::Result<()> {
+        self.control(libc::EPOLL_CTL_DEL, fd, 0, 0)
+    }
+
+    // Waits for events, filling `events` with the ones that occurred
+    fn wait(&self, events: &mut Vec<libc::epoll_event>, timeout: Duration) -> io::Result<()> {
+        events.clear();
+        // SAFETY: the kernel writes at most `capacity` events into the vector's buffer
+        let count = unsafe {
+            libc::epoll_wait(
+                self.fd,
+                events.as_mut_ptr(),
+                events.capacity() as libc::c_int,
+                timeout.as_millis() as libc::c_int,
+            )
+        };
+        if count < 0 {
+            let err = io::Error::last_os_error();
+            return if err.kind() == io::ErrorKind::Interrupted {
+                Ok(())
+            } else {
+                Err(err)
+            };
+        }
+        // SAFETY: epoll_wait initialized the first `count` events
+        unsafe { events.set_len(count as usize) };
+        Ok(())
+    }
+}
+
+impl Drop for Epoll {
+    fn drop(&mut self) {
+        // SAFETY: the descriptor is owned by this Epoll and closed only here
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::http::request::HttpRequest;
+    use crate::http::response::{HttpResponse, ResponseStatus};
+    use crate::server::{Server, ServerHandle};
+    use crate::testing::{echo, exchange, StreamingEcho};
+    use std::io;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    // An epoll server with one event loop answering with the request body
+    fn spawn_echo() -> ServerHandle {
+        Server::builder()
+            .bind("127.0.0.1:0")
+            .handler(echo)
+            .spawn_epoll(1)
+            .unwrap()
+    }
+
+    #[test]
+    fn keeps_connections_alive_between_requests() {
+        let server = spawn_echo();
+        let pieces: [&[u8]; 2] = [
+            b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 3\r\n\r\none",
+            b"POST / HTTP/1.1\r\nHost: a\r\nConnection: close\r\nContent-Length: 3\r\n\r\ntwo",
+        ];
+        let response = exchange(server.addr(), &pieces);
+        assert_eq!(
+            response.matches("HTTP/1.1 200 OK\r\n").count(),
+            2,
+            "{}",
+            response
+        );
+        let first = response.find("one").expect(&response);
+        assert!(
+            response[first..].ends_with("Connection: close\r\n\r\ntwo"),
+            "{}",
+            response
+        );
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn answers_pipelined_requests_in_order() {
+        let server = spawn_echo();
+        let pipelined = concat!(
+            "POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 1\r\n\r\n1",
+            "POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n1\r\n2\r\n0\r\n\r\n",
+            "POST / HTTP/1.1\r\nHost: a\r\nConnection: close\r\nContent-Length: 1\r\n\r\n3",
+        );
+        let response = exchange(server.addr(), &[pipelined.as_bytes()]);
+        let bodies: Vec<&str> = response
+            .split("HTTP/1.1 200 OK\r\n")
+            .skip(1)
+            .map(|response| &response[response.find("\r\n\r\n").unwrap() + 4..])
+            .collect();
+        assert_eq!(bodies, ["1", "2", "3"], "{}", response);
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn decodes_chunked_bodies_as_they_arrive() {
+        let server = spawn_echo();
+        let pieces: [&[u8]; 5] = [
+            b"POST / HTTP/1.1\r\nHost: a\r\nConnection: close\r\nTransfer-Encoding: chunked\r\n\r\n",
+            b"5\r",
+            b"\nhel",
+            b"lo\r\n6\r\n world\r\n0\r\nX-Sum",
+            b": 1\r\n\r\n",
+        ];
+        let response = exchange(server.addr(), &pieces);
+        assert!(
+            response.ends_with("\r\n\r\nhello world\nX-Sum: 1"),
+            "{}",
+            response
+        );
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn answers_http_1_0_clients_and_closes() {
+        let server = spawn_echo();
+        let response = exchange(
+            server.addr(),
+            &[b"POST / HTTP/1.0\r\nContent-Length: 2\r\n\r\nhi"],
+        );
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("Connection: close\r\n"), "{}", response);
+        assert!(response.ends_with("\r\n\r\nhi"), "{}", response);
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn serves_others_while_a_handler_blocks() {
+        let slow = |request: &mut HttpRequest| -> io::Result<HttpResponse> {
+            if request.target() == "/slow" {
+                thread::sleep(Duration::from_secs(2));
+            }
+            Ok(HttpResponse::with_status(ResponseStatus::NoContent))
+        };
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .handler(slow)
+            .spawn_epoll(1)
+            .unwrap();
+        let addr = server.addr();
+        let blocked = thread::spawn(move || {
+            exchange(
+                addr,
+                &[b"GET /slow HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n"],
+            )
+        });
+        thread::sleep(Duration::from_millis(100));
+        let started = Instant::now();
+        let response = exchange(
+            addr,
+            &[b"GET /fast HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n"],
+        );
+        assert!(response.starts_with("HTTP/1.1 204"), "{}", response);
+        assert!(
+            started.elapsed() < Duration::from_secs(1),
+            "{:?}",
+            started.elapsed()
+        );
+        assert!(blocked.join().unwrap().starts_with("HTTP/1.1 204"));
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn streams_bodies_past_the_body_limit() {
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .handler(StreamingEcho::default())
+            .max_body_size(4)
+            .spawn_epoll(1)
+            .unwrap();
+        let pieces: [&[u8]; 2] = [
+            b"PUT / HTTP/1.1\r\nHost: a\r\nConnection: close\r\nContent-Length: 11\r\n\r\nhello",
+            b" world",
+        ];
+        let response = exchange(server.addr(), &pieces);
+        assert!(response.ends_with("\r\n\r\nhello world"), "{}", response);
+
+        let chunked =
+            b"PUT / HTTP/1.1\r\nHost: a\r\nConnection: close\r\nTransfer-Encoding: chunked\r\n\r\n";
+        let pieces: [&[u8]; 3] = [
+            chunked,
+            b"6\r\nhello \r\n",
+            b"5\r\nworld\r\n0\r\nX-Sum: 1\r\n\r\n",
+        ];
+        let response = exchange(server.addr(), &pieces);
+        assert!(
+            response.ends_with("\r\n\r\nhello world\nX-Sum: 1"),
+            "{}",
+            response
+        );
+        server.shutdown().unwrap();
+    }
+}
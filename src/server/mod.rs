@@ -0,0 +1,513 @@
+mod connection;
+#[cfg(all(feature = "epoll", target_os = "linux"))]
+mod epoll;
+#[cfg(feature = "tokio")]
+mod tokio;
+#[cfg(unix)]
+pub mod upgrade;
+#[cfg(target_os = "linux")]
+pub mod watch;
+mod workers;
+
+use crate::config::Config;
+use crate::http::file_cache::FileCache;
+use crate::http::handler::{FileServer, Handler};
+use crate::http::middleware::{Chain, Middleware};
+use crate::http::router::Router;
+use crate::http::tus::TusHandler;
+use connection::Limits;
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use std::env;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+const DEFAULT_PORT: u16 = 5500;
+const LISTEN_BACKLOG: i32 = 1024; // Pending connections each SO_REUSEPORT listener queues
+const READ_TIMEOUT: Duration = Duration::from_secs(30); // How long an idle client may hold its connection
+const ACCEPT_TIMEOUT: Duration = Duration::from_secs(1); // How long a blocked accept waits before checking for shutdown
+
+// Configures a Server before it starts listening
+//
+//     let handle = Server::builder()
+//         .bind("127.0.0.1:0")
+//         .root("public")
+//         .middleware(Logger)
+//         .spawn()?;
+//     println!("listening on {}", handle.addr());
+//     handle.shutdown()?;
+pub struct ServerBuilder {
+    addrs: io::Result<Vec<SocketAddr>>, // Resolved bind addresses; the first one that binds is used
+    listeners: Vec<TcpListener>,        // Already listening sockets to use instead of binding
+    root: Option<PathBuf>,
+    config: Config,
+    handler: Option<Box<dyn Handler>>,
+    middlewares: Vec<Box<dyn Middleware>>,
+    limits: Limits,
+    workers: usize,
+}
+
+impl ServerBuilder {
+    // Sets the address to listen on (default 127.0.0.1:5500); port 0 picks a free port
+    pub fn bind(mut self, addr: impl ToSocketAddrs) -> ServerBuilder {
+        self.addrs = addr.to_socket_addrs().map(|addrs| addrs.collect());
+        self
+    }
+
+    // Serves on an already listening socket instead of binding `bind`'s address, e.g. one
+    // inherited from the process being upgraded (see the `upgrade` module)
+    // Each listener added gets its own worker, as if they were bound by `workers`
+    pub fn listener(mut self, listener: TcpListener) -> ServerBuilder {
+        self.listeners.push(listener);
+        self
+    }
+
+    // Sets the directory static files are served from (default: the current directory)
+    // Ignored when a custom handler is set
+    pub fn root(mut self, root: impl Into<PathBuf>) -> ServerBuilder {
+        self.root = Some(root.into());
+        self
+    }
+
+    // Sets the options of the default static file server (see Config)
+    // Ignored when a custom handler is set
+    pub fn config(mut self, config: Config) -> ServerBuilder {
+        self.config = config;
+        self
+    }
+
+    // Replaces the default static file router with a custom handler
+    pub fn handler(mut self, handler: impl Handler + 'static) -> ServerBuilder {
+        self.handler = Some(Box::new(handler));
+        self
+    }
+
+    // Wraps the handler in a middleware; the first one added is the outermost
+    pub fn middleware(mut self, middleware: impl Middleware + 'static) -> ServerBuilder {
+        self.middlewares.push(Box::new(middleware));
+        self
+    }
+
+    // Sets the largest request head accepted, in bytes
+    pub fn max_header_size(mut self, size: usize) -> ServerBuilder {
+        self.limits.max_header_size = size;
+        self
+    }
+
+    // Sets the largest request body accepted, in bytes
+    pub fn max_body_size(mut self, size: usize) -> ServerBuilder {
+        self.limits.max_body_size = size;
+        self
+    }
+
+    // Runs `count` accept loops, each with its own listener bound to the same address with
+    // SO_REUSEPORT so the kernel spreads connections between them (Unix only, and only with
+    // the threaded backend). Workers that stop are restarted; see ServerHandle::restart_worker
+    pub fn workers(mut self, count: usize) -> ServerBuilder {
+        self.workers = count.max(1);
+        self
+    }
+
+    // Binds the listening sockets (one per worker) and assembles the handler
+    pub fn build(self) -> io::Result<Server> {
+        let workers = self.workers.max(self.listeners.len());
+        if workers > 1 && !cfg!(unix) {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "SO_REUSEPORT workers need a Unix system",
+            ));
+        }
+        let mut listeners = self.listeners;
+        if listeners.is_empty() {
+            listeners.push(if workers > 1 {
+                bind_first(&self.addrs?, bind_reuse_port)?
+            } else {
+                TcpListener::bind(&self.addrs?[..])?
+            });
+        }
+        // The other workers join the first listener on its address
+        let addr = listeners[0].local_addr()?;
+        while listeners.len() < workers {
+            listeners.push(bind_reuse_port(addr)?);
+        }
+        for listener in &listeners {
+            // Blocked accepts return now and then, so a stopped accept loop notices without
+            // closing a socket that another process may be sharing
+            SockRef::from(listener).set_read_timeout(Some(ACCEPT_TIMEOUT))?;
+        }
+        let mut file_cache = None;
+        #[cfg(target_os = "linux")]
+        let mut watcher = None;
+        let handler = match self.handler {
+            Some(handler) => handler,
+            None => {
+                let root = match self.root {
+                    Some(root) => root,
+                    None => env::current_dir()?,
+                };
+                let files = FileServer::with_config(root, &self.config);
+                file_cache = files.cached_files().cloned();
+                #[cfg(target_os = "linux")]
+                if self.config.watch.enabled {
+                    watcher = watch_root(files.root(), file_cache.clone());
+                }
+                let root = files.root().clone();
+                let router = Router::for_file_server(files);
+                Box::new(if self.config.uploads.enabled {
+                    router.uploads(TusHandler::with_config(root, &self.config.uploads))
+                } else {
+                    router
+                })
+            }
+        };
+        let handler = self
+            .middlewares
+            .into_iter()
+            .fold(Chain::new(handler), |chain, middleware| {
+                chain.with(middleware)
+            });
+
+        Ok(Server {
+            listeners,
+            handler: Arc::new(handler),
+            limits: self.limits,
+            shutdown: Arc::new(AtomicBool::new(false)),
+            file_cache,
+            #[cfg(target_os = "linux")]
+            watcher,
+        })
+    }
+
+    // Builds the server and serves requests on the current thread until it fails
+    pub fn run(self) -> io::Result<()> {
+        self.build()?.run()
+    }
+
+    // Builds the server and serves requests on a background thread
+    pub fn spawn(self) -> io::Result<ServerHandle> {
+        self.build()?.spawn()
+    }
+}
+
+// An HTTP server bound to a socket, handling each connection on its own thread
+pub struct Server {
+    listeners: Vec<TcpListener>, // One per worker; a single one without workers
+    handler: Arc<dyn Handler>,
+    limits: Limits,
+    shutdown: Arc<AtomicBool>,          // Set to stop accepting connections
+    file_cache: Option<Arc<FileCache>>, // Of the default file server
+    #[cfg(target_os = "linux")]
+    watcher: Option<Arc<watch::Watcher>>, // Of the default file server's root
+}
+
+impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder {
+            addrs: Ok(vec![SocketAddr::new(
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                DEFAULT_PORT,
+            )]),
+            listeners: Vec::new(),
+            root: None,
+            config: Config::default(),
+            handler: None,
+            middlewares: Vec::new(),
+            limits: Limits::default(),
+            workers: 1,
+        }
+    }
+
+    // Returns the address the server is listening on
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listeners[0].local_addr()
+    }
+
+    // The listening sockets, one per worker; they stay open until the server stops, so they
+    // can be handed over to a new process on upgrade (see the `upgrade` module)
+    pub fn listeners(&self) -> &[TcpListener] {
+        &self.listeners
+    }
+
+    // The in-memory file cache of the default file server, e.g. for its statistics
+    // None with a custom handler or when the cache is disabled
+    pub fn file_cache(&self) -> Option<&Arc<FileCache>> {
+        self.file_cache.as_ref()
+    }
+
+    // The watcher of the default file server's root, to subscribe to changes
+    // None with a custom handler, when watching is disabled, or if inotify failed
+    #[cfg(target_os = "linux")]
+    pub fn watcher(&self) -> Option<&Arc<watch::Watcher>> {
+        self.watcher.as_ref()
+    }
+
+    // Accepts connections until shut down, then waits for the ones in progress
+    pub fn run(self) -> io::Result<()> {
+        let (control, messages) = mpsc::channel();
+        self.serve(control, messages)
+    }
+
+    // Runs the server on a background thread, returning a handle to stop it
+    pub fn spawn(self) -> io::Result<ServerHandle> {
+        let addr = self.local_addr()?;
+        let shutdown = Arc::clone(&self.shutdown);
+        let (control, messages) = mpsc::channel();
+        let handle_control = (self.listeners.len() > 1).then(|| control.clone());
+        let thread = thread::Builder::new()
+            .name("simple-http".to_string())
+            .spawn(move || self.serve(control, messages))?;
+        Ok(ServerHandle {
+            addr,
+            shutdown,
+            thread,
+            control: handle_control,
+        })
+    }
+
+    // Runs a single accept loop, or supervises the workers when there are several
+    fn serve(self, control: Sender<Control>, messages: Receiver<Control>) -> io::Result<()> {
+        if self.listeners.len() > 1 {
+            return workers::supervise(self, control, messages);
+        }
+        let active = Arc::new(ActiveConnections::default());
+        accept_loop(
+            &self.listeners[0],
+            &self.handler,
+            self.limits,
+            &self.shutdown,
+            &active,
+        );
+        active.wait_idle();
+        Ok(())
+    }
+
+    // Takes the listener for backends that run their own accept loop instead of workers
+    #[cfg(any(feature = "tokio", all(feature = "epoll", target_os = "linux")))]
+    fn single_listener(&mut self) -> io::Result<TcpListener> {
+        if self.listeners.len() > 1 {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "workers are only supported by the threaded backend",
+            ));
+        }
+        Ok(self.listeners.remove(0))
+    }
+}
+
+// Messages to the worker supervisor
+enum Control {
+    Exited(usize, u64), // A worker's accept loop ended (worker index, generation)
+    Restart(usize),     // Replace a worker with a fresh one
+    Shutdown,           // Stop all workers
+}
+
+// Accepts connections on one listener until `stop` is set, handling each on its own thread
+fn accept_loop(
+    listener: &TcpListener,
+    handler: &Arc<dyn Handler>,
+    limits: Limits,
+    stop: &AtomicBool,
+    active: &Arc<ActiveConnections>,
+) {
+    for stream in listener.incoming() {
+        // Woken up by ServerHandle::shutdown; a connection accepted meanwhile is still
+        // served, as the socket may be shared with a new process after an upgrade
+        let stopping = stop.load(Ordering::SeqCst);
+        if stopping && stream.is_err() {
+            break;
+        }
+        let stream = match stream {
+            Ok(stream) => stream,
+            // The accept timeout expired; check the flag again
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                continue
+            }
+            Err(e) => {
+                eprintln!("Failed to accept a client: {}", e);
+                continue;
+            }
+        };
+
+        let handler = Arc::clone(handler);
+        let guard = ActiveConnections::enter(active);
+        let spawned = thread::Builder::new().spawn(move || {
+            let _guard = guard; // Counts the connection until the thread is done
+            let mut stream = stream;
+            let _ = stream.set_read_timeout(Some(READ_TIMEOUT));
+            // Errors here concern a single client and were already answered if possible
+            let _ = connection::handle_client(&mut stream, handler.as_ref(), &limits);
+        });
+        if let Err(e) = spawned {
+            eprintln!("Failed to start a connection thread: {}", e);
+        }
+        if stopping {
+            break;
+        }
+    }
+}
+
+// Watches the root so changed files leave the file cache as soon as they change
+// The server works without the watcher, so failing to start it is only reported
+#[cfg(target_os = "linux")]
+fn watch_root(
+    root: &std::path::Path,
+    file_cache: Option<Arc<FileCache>>,
+) -> Option<Arc<watch::Watcher>> {
+    let watcher = match watch::Watcher::new(root) {
+        Ok(watcher) => watcher,
+        Err(err) => {
+            eprintln!("Not watching {} for changes: {}", root.display(), err);
+            return None;
+        }
+    };
+    if let Some(file_cache) = file_cache {
+        watcher.on_change(move |change| match change.path() {
+            Some(path) => file_cache.remove_below(path),
+            None => file_cache.clear(), // Changes were lost
+        });
+    }
+    Some(Arc::new(watcher))
+}
+
+// Binds the first address that can be bound, like TcpListener::bind does
+fn bind_first(
+    addrs: &[SocketAddr],
+    bind: fn(SocketAddr) -> io::Result<TcpListener>,
+) -> io::Result<TcpListener> {
+    let mut last_error = io::Error::new(io::ErrorKind::InvalidInput, "no address to bind to");
+    for addr in addrs {
+        match bind(*addr) {
+            Ok(listener) => return Ok(listener),
+            Err(err) => last_error = err,
+        }
+    }
+    Err(last_error)
+}
+
+// Binds a listener that other listeners in this process can share the address with
+fn bind_reuse_port(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(LISTEN_BACKLOG)?;
+    Ok(socket.into())
+}
+
+// Controls a server running on a background thread
+pub struct ServerHandle {
+    addr: SocketAddr,
+    shutdown: Arc<AtomicBool>,
+    thread: JoinHandle<io::Result<()>>,
+    control: Option<Sender<Control>>, // Reaches the worker supervisor, if there is one
+}
+
+impl ServerHandle {
+    // Returns the address the server is listening on
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    // Stops accepting connections and waits for the ones in progress to finish
+    pub fn shutdown(self) -> io::Result<()> {
+        self.shutdown.store(true, Ordering::SeqCst);
+        if let Some(control) = &self.control {
+            let _ = control.send(Control::Shutdown);
+        }
+        // Blocked accept loops check the flag when a connection arrives or the accept
+        // timeout expires, so make one arrive now
+        let _ = TcpStream::connect(wake_addr(self.addr));
+        self.join()
+    }
+
+    // Replaces one SO_REUSEPORT worker with a fresh accept loop on the same listener; the
+    // new loop starts before the old one stops. Connections the old worker accepted are
+    // finished normally
+    pub fn restart_worker(&self, index: usize) -> io::Result<()> {
+        let control = self
+            .control
+            .as_ref()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Unsupported, "server has no workers"))?;
+        control
+            .send(Control::Restart(index))
+            .map_err(|_| io::Error::new(io::ErrorKind::NotConnected, "server has stopped"))
+    }
+
+    // Waits for the server to stop
+    pub fn join(self) -> io::Result<()> {
+        self.thread
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("server thread panicked")))
+    }
+}
+
+// Address to connect to in order to reach a listener bound to `addr`
+fn wake_addr(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => {
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), addr.port())
+        }
+        IpAddr::V6(ip) if ip.is_unspecified() => {
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), addr.port())
+        }
+        _ => addr,
+    }
+}
+
+// Counts connections being handled, so shutdown can wait for them
+#[derive(Default)]
+struct ActiveConnections {
+    count: Mutex<usize>,
+    idle: Condvar, // Notified when the count drops to zero
+}
+
+// Keeps a connection counted while alive
+struct ConnectionGuard(Arc<ActiveConnections>);
+
+impl ActiveConnections {
+    fn enter(active: &Arc<ActiveConnections>) -> ConnectionGuard {
+        *active
+            .count
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) += 1;
+        ConnectionGuard(Arc::clone(active))
+    }
+
+    fn wait_idle(&self) {
+        let mut count = self
+            .count
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        while *count > 0 {
+            count = self
+                .idle
+                .wait(count)
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut count = self
+            .0
+            .count
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        *count -= 1;
+        if *count == 0 {
+            self.0.idle.notify_all();
+        }
+    }
+}
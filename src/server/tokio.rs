@@ -0,0 +1,557 @@
+// Async backend, enabled with the `tokio` cargo feature
+//
+// Accepting connections and reading request heads and bodies is async, so idle and slow
+// clients only cost a task. Handlers are synchronous and may touch the disk, so they run on
+// tokio's blocking pool, as do reads from response bodies; the bytes are then written
+// back asynchronously. Parsing and response types are shared with the threaded backend.
+
+use super::connection::{self, Limits};
+use super::{ActiveConnections, Server, ServerBuilder, ServerHandle, ACCEPT_TIMEOUT, READ_TIMEOUT};
+use crate::http::chunked::ChunkedDecoder;
+use crate::http::handler::{BodySink, Handler};
+use crate::http::request::{BodyLength, HttpRequest, Method, StreamedBody};
+use crate::http::response::{HttpResponse, ResponseBody, ResponseStatus};
+use ::tokio::io::{AsyncReadExt, AsyncWriteExt};
+use ::tokio::net::{TcpListener, TcpStream};
+use ::tokio::runtime;
+use ::tokio::task;
+use ::tokio::time::timeout;
+use std::future::Future;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+
+const BLOCK_SIZE: usize = 64 * 1024; // How much of a response body is read from the blocking pool at a time
+const BODY_CHUNK: usize = 64 * 1024; // Request bodies grow by this much as bytes arrive
+
+impl ServerBuilder {
+    // Builds the server and serves requests on the current tokio runtime
+    pub async fn run_async(self) -> io::Result<()> {
+        self.build()?.run_async().await
+    }
+
+    // Builds the server and serves requests on a new tokio runtime in the background
+    pub fn spawn_async(self) -> io::Result<ServerHandle> {
+        self.build()?.spawn_async()
+    }
+}
+
+impl Server {
+    // Accepts connections on the current tokio runtime until shut down, then waits for
+    // the ones in progress
+    pub async fn run_async(mut self) -> io::Result<()> {
+        let listener = self.single_listener()?;
+        listener.set_nonblocking(true)?;
+        let listener = TcpListener::from_std(listener)?;
+        let active = Arc::new(ActiveConnections::default());
+        loop {
+            // Accepting gives up now and then to check the flag, as the wake-up connection
+            // from ServerHandle::shutdown may go to a process sharing the socket after an upgrade
+            let accepted = timeout(ACCEPT_TIMEOUT, listener.accept()).await;
+            // Woken up by ServerHandle::shutdown; a connection accepted meanwhile is still served
+            let stopping = self.shutdown.load(Ordering::SeqCst);
+            let stream = match accepted {
+                Ok(Ok((stream, _))) => stream,
+                _ if stopping => break,
+                Err(_) => continue, // Nothing arrived in time
+                Ok(Err(e)) => {
+                    eprintln!("Failed to accept a client: {}", e);
+                    continue;
+                }
+            };
+
+            let handler = Arc::clone(&self.handler);
+            let limits = self.limits;
+            let guard = ActiveConnections::enter(&active);
+            task::spawn(async move {
+                let _guard = guard; // Counts the connection until the task is done
+                                    // Errors here concern a single client and were already answered if possible
+                let _ = handle_client(stream, handler, limits).await;
+            });
+            if stopping {
+                break;
+            }
+        }
+        blocking(move || active.wait_idle()).await
+    }
+
+    // Runs the server on a new multi-threaded tokio runtime on a background thread
+    pub fn spawn_async(self) -> io::Result<ServerHandle> {
+        let runtime = runtime::Builder::new_multi_thread().enable_all().build()?;
+        let addr = self.local_addr()?;
+        let shutdown = Arc::clone(&self.shutdown);
+        let thread = thread::Builder::new()
+            .name("simple-http".to_string())
+            .spawn(move || runtime.block_on(self.run_async()))?;
+        Ok(ServerHandle {
+            addr,
+            shutdown,
+            thread,
+            control: None,
+        })
+    }
+}
+
+// Handles one client connection: reads a request, runs the handler and writes the response
+async fn handle_client(
+    mut stream: TcpStream,
+    handler: Arc<dyn Handler>,
+    limits: Limits,
+) -> io::Result<()> {
+    let (mut request, leftover) = match read_head(&mut stream, &limits).await {
+        Ok(head) => head,
+        Err(err) => {
+            // Malformed requests get an error status before the connection is closed
+            if let Some(status) = connection::error_status(&err) {
+                reject(&mut stream, HttpResponse::with_status(status)).await?;
+            }
+            return Err(err);
+        }
+    };
+    if let Ok(peer_addr) = stream.peer_addr() {
+        request.set_peer_addr(peer_addr); // Let handlers see who they are talking to
+    }
+
+    // Answer from the headers alone when possible, so rejected uploads don't transfer their body
+    let precheck_handler = Arc::clone(&handler);
+    let (mut request, sink) = blocking(move || {
+        let sink = connection::precheck(&request, precheck_handler.as_ref(), &limits);
+        (request, sink)
+    })
+    .await?;
+    match sink {
+        BodySink::Reject(rejection) => return reject(&mut stream, rejection).await,
+        BodySink::Stream(streamed) => request.streamed_body = Some(streamed),
+        BodySink::Buffer => {}
+    }
+    if connection::expects_continue(&request, &leftover) {
+        stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await?; // The client waits for this before sending the body
+    }
+
+    if let Err(err) = read_body(&mut stream, &mut request, leftover, &limits).await {
+        if let Some(status) = connection::error_status(&err) {
+            reject(&mut stream, HttpResponse::with_status(status)).await?;
+        }
+        return Err(err);
+    }
+
+    let head_only = *request.method() == Method::Head;
+    let response = blocking(move || {
+        let mut response = handler.handle(&mut request)?;
+        connection::fit_version(&request, &mut response);
+        Ok(response)
+    })
+    .await?;
+    match response {
+        Ok(response) => write_response(&mut stream, response, head_only).await,
+        Err(err) => {
+            reject(
+                &mut stream,
+                HttpResponse::with_status(ResponseStatus::InternalServerError),
+            )
+            .await?;
+            Err(err)
+        }
+    }
+}
+
+// Reads the request head (request line and headers) from the client
+// Returns the parsed request along with any body bytes received after the head
+async fn read_head(stream: &mut TcpStream, limits: &Limits) -> io::Result<(HttpRequest, Vec<u8>)> {
+    let mut buffer = Vec::new(); // Everything received so far
+    let mut chunk = [0; 1024];
+    loop {
+        let read = with_timeout(stream.read(&mut chunk)).await?;
+        if read == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed before request head",
+            ));
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+        if let Some(head_end) = connection::head_end(&buffer, limits)? {
+            return connection::split_head(buffer, head_end);
+        }
+    }
+}
+
+// Reads the request body into the request, framed either by Content-Length or by chunked
+// transfer encoding; chunked bodies are decoded as their bytes arrive
+// A body the handler chose to stream goes into its writer instead of request_body
+async fn read_body(
+    stream: &mut TcpStream,
+    request: &mut HttpRequest,
+    leftover: Vec<u8>,
+    limits: &Limits,
+) -> io::Result<()> {
+    if let Some(streamed) = request.streamed_body.take() {
+        let streamed = stream_body(stream, request, leftover, streamed).await?;
+        request.streamed_body = Some(streamed);
+        return Ok(());
+    }
+    let length = match request.body_length()? {
+        BodyLength::None => return Ok(()),
+        BodyLength::Fixed(length) => length,
+        BodyLength::Chunked => {
+            let mut decoder = ChunkedDecoder::new(limits.max_body_size as u64);
+            let mut input = leftover;
+            loop {
+                decoder.feed(&input)?; // Whatever follows the body is discarded with the connection
+                if decoder.is_done() {
+                    break;
+                }
+                input.clear();
+                input.reserve(BODY_CHUNK);
+                if with_timeout(stream.read_buf(&mut input)).await? == 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "truncated chunked body",
+                    ));
+                }
+            }
+            (request.request_body, request.request_trailers) = decoder.into_parts();
+            return Ok(());
+        }
+    };
+
+    if length > limits.max_body_size {
+        return Err(io::Error::new(
+            io::ErrorKind::FileTooLarge,
+            "request body too large",
+        ));
+    }
+    // Memory follows the bytes received rather than the declared length, so clients that
+    // announce large bodies and send nothing don't cost more than their headers
+    let mut body = leftover;
+    body.truncate(length);
+    while body.len() < length {
+        let missing = length - body.len();
+        body.reserve(missing.min(BODY_CHUNK));
+        let mut rest = (&mut *stream).take(missing as u64);
+        if with_timeout(rest.read_buf(&mut body)).await? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "truncated request body",
+            ));
+        }
+    }
+    request.request_body = body;
+    Ok(())
+}
+
+// Copies the request body into the handler's writer as it arrives, writing on the blocking
+// pool; the writer enforces its own limit, so the server's body size limit doesn't apply
+async fn stream_body(
+    stream: &mut TcpStream,
+    request: &mut HttpRequest,
+    leftover: Vec<u8>,
+    mut streamed: StreamedBody,
+) -> io::Result<StreamedBody> {
+    let length = request.body_length()?;
+    let mut decoder = (length == BodyLength::Chunked).then(|| ChunkedDecoder::new(u64::MAX));
+    let mut remaining = match length {
+        BodyLength::Fixed(length) => length,
+        _ => 0,
+    };
+    let mut input = leftover;
+    loop {
+        let (data, done) = match &mut decoder {
+            Some(decoder) => {
+                decoder.feed(&input)?;
+                (decoder.take_body(), decoder.is_done())
+            }
+            None => {
+                input.truncate(remaining);
+                remaining -= input.len();
+                (mem::take(&mut input), remaining == 0)
+            }
+        };
+        if !data.is_empty() {
+            streamed = blocking(move || streamed.write_all(&data).map(|()| streamed)).await??;
+        }
+        if done {
+            break;
+        }
+        input.clear();
+        input.reserve(BODY_CHUNK);
+        if with_timeout(stream.read_buf(&mut input)).await? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "truncated request body",
+            ));
+        }
+    }
+    if let Some(decoder) = decoder {
+        request.request_trailers = decoder.into_parts().1;
+    }
+    blocking(move || streamed.flush().map(|()| streamed)).await?
+}
+
+// Writes a response to the client; HEAD responses get the headers only
+async fn write_response(
+    stream: &mut TcpStream,
+    response: HttpResponse,
+    head_only: bool,
+) -> io::Result<()> {
+    let mut head = Vec::new();
+    response.write_head_to(&mut head)?;
+    stream.write_all(&head).await?;
+    if head_only || !response.allows_body() {
+        return stream.flush().await;
+    }
+
+    match response.response_body {
+        ResponseBody::Bytes(bytes) => stream.write_all(&bytes).await?,
+        ResponseBody::Reader(reader) => {
+            // Never send more than the advertised length
+            let length = response.content_length as u64;
+            let mut reader = reader.take(length);
+            let mut copied = 0;
+            loop {
+                let (rest, block) = read_block(reader).await?;
+                if block.is_empty() {
+                    break;
+                }
+                stream.write_all(&block).await?;
+                copied += block.len() as u64;
+                reader = rest;
+            }
+            if copied < length {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "response body shorter than its length",
+                ));
+            }
+        }
+        ResponseBody::Stream(mut reader) if response.close_delimited => loop {
+            let (rest, block) = read_block(reader).await?;
+            if block.is_empty() {
+                break;
+            }
+            stream.write_all(&block).await?;
+            reader = rest;
+        },
+        ResponseBody::Stream(mut reader) => {
+            loop {
+                let (rest, block) = read_block(reader).await?;
+                if block.is_empty() {
+                    break;
+                }
+                stream
+                    .write_all(format!("{:X}\r\n", block.len()).as_bytes())
+                    .await?;
+                stream.write_all(&block).await?;
+                stream.write_all(b"\r\n").await?;
+                reader = rest;
+            }
+            stream.write_all(b"0\r\n\r\n").await?; // Last chunk
+        }
+    }
+    stream.flush().await
+}
+
+// Sends an error response and closes the connection; any unread body is discarded
+async fn reject(stream: &mut TcpStream, mut response: HttpResponse) -> io::Result<()> {
+    response.add_header("Connection", "close");
+    write_response(stream, response, false).await?;
+    stream.shutdown().await // Tell the client no more data is coming
+}
+
+// Reads the next block of a response body on the blocking pool; an empty block means the end
+async fn read_block<R: Read + Send + 'static>(mut reader: R) -> io::Result<(R, Vec<u8>)> {
+    blocking(move || {
+        let mut block = vec![0; BLOCK_SIZE];
+        let read = loop {
+            match reader.read(&mut block) {
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                result => break result?,
+            }
+        };
+        block.truncate(read);
+        Ok((reader, block))
+    })
+    .await?
+}
+
+// Runs blocking work (handlers, file reads) on tokio's blocking pool
+async fn blocking<T: Send + 'static>(work: impl FnOnce() -> T + Send + 'static) -> io::Result<T> {
+    task::spawn_blocking(work).await.map_err(io::Error::other)
+}
+
+// Fails reads from clients that stay silent for too long
+async fn with_timeout<T>(read: impl Future<Output = io::Result<T>>) -> io::Result<T> {
+    timeout(READ_TIMEOUT, read)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "client timed out"))?
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::http::request::HttpRequest;
+    use crate::http::response::{HttpResponse, ResponseStatus};
+    use crate::server::Server;
+    use crate::testing::{echo, exchange, StreamingEcho};
+    use std::io;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn decodes_chunked_bodies_as_they_arrive() {
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .handler(echo)
+            .max_body_size(16)
+            .spawn_async()
+            .unwrap();
+        let head = b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n";
+        let pieces: [&[u8]; 5] = [
+            head,
+            b"5\r\nhel",
+            b"lo\r\n",
+            b"6\r\n world\r\n0\r",
+            b"\nX-Sum: 1\r\n\r\n",
+        ];
+        let response = exchange(server.addr(), &pieces);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(
+            response.ends_with("\r\n\r\nhello world\nX-Sum: 1"),
+            "{}",
+            response
+        );
+
+        // The decoded size counts against the body limit
+        let pieces: [&[u8]; 3] = [
+            head,
+            b"a\r\n0123456789\r\n",
+            b"a\r\n0123456789\r\n0\r\n\r\n",
+        ];
+        let response = exchange(server.addr(), &pieces);
+        assert!(response.starts_with("HTTP/1.1 413 "), "{}", response);
+
+        let pieces: [&[u8]; 2] = [head, b"5\r\nhello\r\nzz\r\n"];
+        let response = exchange(server.addr(), &pieces);
+        assert!(response.starts_with("HTTP/1.1 400 "), "{}", response);
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn reads_bodies_of_a_declared_length() {
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .handler(echo)
+            .spawn_async()
+            .unwrap();
+        let pieces: [&[u8]; 3] = [
+            b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 11\r\n\r\nhel",
+            b"lo wo",
+            b"rld",
+        ];
+        let response = exchange(server.addr(), &pieces);
+        assert!(response.ends_with("\r\n\r\nhello world"), "{}", response);
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn streams_bodies_past_the_body_limit() {
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .handler(StreamingEcho::default())
+            .max_body_size(4)
+            .spawn_async()
+            .unwrap();
+        let pieces: [&[u8]; 2] = [
+            b"PUT / HTTP/1.1\r\nHost: a\r\nContent-Length: 11\r\n\r\nhello",
+            b" world",
+        ];
+        let response = exchange(server.addr(), &pieces);
+        assert!(response.ends_with("\r\n\r\nhello world"), "{}", response);
+
+        let chunked = b"PUT / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n";
+        let pieces: [&[u8]; 3] = [
+            chunked,
+            b"6\r\nhello \r\n",
+            b"5\r\nworld\r\n0\r\nX-Sum: 1\r\n\r\n",
+        ];
+        let response = exchange(server.addr(), &pieces);
+        assert!(
+            response.ends_with("\r\n\r\nhello world\nX-Sum: 1"),
+            "{}",
+            response
+        );
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn answers_http_1_0_clients_and_expectations() {
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .handler(|request: &mut HttpRequest| {
+                let body = io::Cursor::new(request.request_body.clone());
+                Ok(HttpResponse::builder().reader(body, None).build())
+            })
+            .spawn_async()
+            .unwrap();
+        // The body of unknown length ends with the connection, as it can't be chunked
+        let pieces: [&[u8]; 1] = [b"POST / HTTP/1.0\r\nContent-Length: 2\r\n\r\nhi"];
+        let response = exchange(server.addr(), &pieces);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(!response.contains("Transfer-Encoding"), "{}", response);
+        assert!(response.ends_with("\r\n\r\nhi"), "{}", response);
+
+        let pieces: [&[u8]; 2] = [
+            b"POST / HTTP/1.1\r\nHost: a\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\n",
+            b"hello",
+        ];
+        let response = exchange(server.addr(), &pieces);
+        assert!(
+            response.starts_with("HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\n"),
+            "{}",
+            response
+        );
+        assert!(
+            response.ends_with("\r\n5\r\nhello\r\n0\r\n\r\n"),
+            "{}",
+            response
+        );
+
+        let pieces: [&[u8]; 1] = [b"HEAD / HTTP/1.1\r\nHost: a\r\n\r\n"];
+        let response = exchange(server.addr(), &pieces);
+        assert!(response.ends_with("\r\n\r\n"), "{}", response);
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn serves_others_while_a_handler_blocks() {
+        let slow = |request: &mut HttpRequest| -> io::Result<HttpResponse> {
+            match request.target() {
+                "/slow" => thread::sleep(Duration::from_secs(2)),
+                "/fail" => return Err(io::Error::other("handler failed")),
+                _ => {}
+            }
+            Ok(HttpResponse::with_status(ResponseStatus::NoContent))
+        };
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .handler(slow)
+            .spawn_async()
+            .unwrap();
+        let addr = server.addr();
+        let blocked =
+            thread::spawn(move || exchange(addr, &[b"GET /slow HTTP/1.1\r\nHost: a\r\n\r\n"]));
+        thread::sleep(Duration::from_millis(100));
+        let started = Instant::now();
+        let response = exchange(addr, &[b"GET /fast HTTP/1.1\r\nHost: a\r\n\r\n"]);
+        assert!(response.starts_with("HTTP/1.1 204"), "{}", response);
+        assert!(
+            started.elapsed() < Duration::from_secs(1),
+            "{:?}",
+            started.elapsed()
+        );
+        let response = exchange(addr, &[b"GET /fail HTTP/1.1\r\nHost: a\r\n\r\n"]);
+        assert!(response.starts_with("HTTP/1.1 500 "), "{}", response);
+        assert!(blocked.join().unwrap().starts_with("HTTP/1.1 204"));
+        server.shutdown().unwrap();
+    }
+}
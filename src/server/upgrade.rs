@@ -0,0 +1,333 @@
+// Zero-downtime binary upgrades by handing the listening socket to a new process (Unix)
+//
+// Like nginx's USR2 upgrade: the running server starts its executable again with the same
+// arguments and lets it inherit the listening sockets across exec, passing their
+// descriptor numbers in SIMPLE_HTTP_LISTEN_FDS. The new process adopts them with
+// ServerBuilder::listener instead of binding, and once it is serving it reports back
+// through a pipe named in SIMPLE_HTTP_READY_FD. Only then does the old process shut down,
+// draining its connections. Both processes accept from the same socket in between, so no
+// connection is refused; if the new process fails to start, the old one keeps serving.
+//
+//     upgrade::block_signals()?; // Before any thread is started
+//     let mut builder = Server::builder();
+//     for listener in upgrade::inherited_listeners()? {
+//         builder = builder.listener(listener);
+//     }
+//     let server = builder.build()?;
+//     let fds: Vec<RawFd> = server.listeners().iter().map(AsRawFd::as_raw_fd).collect();
+//     let handle = server.spawn()?;
+//     upgrade::notify_ready()?;
+//     if upgrade::wait_for_signal()? == Signal::Upgrade {
+//         upgrade::spawn_successor(&fds)?;
+//     }
+//     handle.shutdown()?;
+
+use socket2::Socket;
+use std::env;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::mem::MaybeUninit;
+use std::net::TcpListener;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::{Child, Command};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+pub const LISTEN_FDS_VAR: &str = "SIMPLE_HTTP_LISTEN_FDS"; // Comma separated inherited listener descriptors
+pub const READY_FD_VAR: &str = "SIMPLE_HTTP_READY_FD"; // Pipe to report readiness on
+const READY_TIMEOUT: Duration = Duration::from_secs(10); // How long a new process may take to start serving
+
+// Inherited descriptors get exactly one owner: each variable is adopted at most once per
+// process, and a descriptor may only be named once across both variables
+static LISTENERS_ADOPTED: AtomicBool = AtomicBool::new(false);
+static READY_PIPE_ADOPTED: AtomicBool = AtomicBool::new(false);
+
+// What a control signal asks for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signal {
+    Upgrade,  // SIGUSR2: start a new process on the same sockets, then drain
+    Shutdown, // SIGTERM or SIGINT: drain and exit
+}
+
+// Blocks the control signals in the calling thread and every thread it starts afterwards,
+// so that wait_for_signal receives them instead of their default action killing the process
+// Call it at the start of main, before any thread is started
+pub fn block_signals() -> io::Result<()> {
+    let set = signal_set();
+    // SAFETY: `set` is an initialized signal set, and the old mask may be left out (null)
+    let result = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut()) };
+    if result != 0 {
+        return Err(io::Error::from_raw_os_error(result));
+    }
+    Ok(())
+}
+
+// Waits for the next control signal; they must have been blocked with block_signals
+pub fn wait_for_signal() -> io::Result<Signal> {
+    let set = signal_set();
+    let mut signal = 0;
+    // SAFETY: both pointers are to live locals; sigwait only reads the set and writes the signal
+    let result = unsafe { libc::sigwait(&set, &mut signal) };
+    if result != 0 {
+        return Err(io::Error::from_raw_os_error(result));
+    }
+    Ok(if signal == libc::SIGUSR2 {
+        Signal::Upgrade
+    } else {
+        Signal::Shutdown
+    })
+}
+
+// SIGUSR2, SIGTERM and SIGINT
+fn signal_set() -> libc::sigset_t {
+    // SAFETY: sigemptyset initializes the set before sigaddset changes it and it is assumed
+    // initialized; the signal numbers are valid, so neither call can fail
+    unsafe {
+        let mut set = MaybeUninit::<libc::sigset_t>::uninit();
+        libc::sigemptyset(set.as_mut_ptr());
+        for signal in [libc::SIGUSR2, libc::SIGTERM, libc::SIGINT] {
+            libc::sigaddset(set.as_mut_ptr(), signal);
+        }
+        set.assume_init()
+    }
+}
+
+// Listeners handed over by the process that started this one; empty when started normally
+pub fn inherited_listeners() -> io::Result<Vec<TcpListener>> {
+    let fds = match listed_fds(LISTEN_FDS_VAR)? {
+        Some(fds) => fds,
+        None => return Ok(Vec::new()),
+    };
+    if LISTENERS_ADOPTED.swap(true, Ordering::SeqCst) {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "inherited listeners were already adopted",
+        ));
+    }
+    let mut listeners = Vec::new();
+    for fd in fds {
+        inherited_fd(fd)?;
+        // SAFETY: the descriptor is open (checked above) and was handed to this process to
+        // take over; it is listed once and the list adopted once, so this is its only owner
+        let socket = unsafe { Socket::from_raw_fd(fd) };
+        if !socket.is_listener()? {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("inherited descriptor {} is not a listening socket", fd),
+            ));
+        }
+        listeners.push(socket.into());
+    }
+    Ok(listeners)
+}
+
+// Tells the process that started this one that it is serving, so the old one can drain
+// Does nothing when the process was started normally
+pub fn notify_ready() -> io::Result<()> {
+    let fd = match listed_fds(READY_FD_VAR)?.as_deref() {
+        Some(&[fd]) => fd,
+        Some(_) => return Err(not_descriptors(READY_FD_VAR)),
+        None => return Ok(()),
+    };
+    // A listener can't double as the pipe, or two owners would close it
+    if listed_fds(LISTEN_FDS_VAR)?.is_some_and(|listeners| listeners.contains(&fd)) {
+        return Err(not_descriptors(READY_FD_VAR));
+    }
+    if READY_PIPE_ADOPTED.swap(true, Ordering::SeqCst) {
+        return Ok(()); // Already reported
+    }
+    inherited_fd(fd)?;
+    // SAFETY: the descriptor is open (checked above), was handed to this process for the
+    // report, is not one of the listeners and is adopted once, so this is its only owner
+    let mut pipe = unsafe { File::from_raw_fd(fd) };
+    pipe.write_all(b"1") // Dropping the file closes our end
+}
+
+// Parses the descriptor numbers in an environment variable, or None if it isn't set
+// Standard streams and descriptors listed twice are rejected, as they'd get two owners
+fn listed_fds(var: &str) -> io::Result<Option<Vec<RawFd>>> {
+    let value = match env::var(var) {
+        Ok(value) => value,
+        Err(_) => return Ok(None),
+    };
+    let mut fds = Vec::new();
+    for fd in value.split(',') {
+        match fd.trim().parse::<RawFd>() {
+            Ok(fd) if fd > 2 && !fds.contains(&fd) => fds.push(fd),
+            _ => return Err(not_descriptors(var)),
+        }
+    }
+    Ok(Some(fds))
+}
+
+fn not_descriptors(var: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("{} is not a list of distinct descriptors above 2", var),
+    )
+}
+
+// Makes sure an inherited descriptor is open, marking it close-on-exec again so later
+// child processes don't inherit it
+fn inherited_fd(fd: RawFd) -> io::Result<()> {
+    // SAFETY: fcntl has no memory safety preconditions; a closed descriptor fails with EBADF
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Starts a new copy of this program that takes over `listeners`, and waits until it
+// reports being ready. On failure the new process is killed and an error returned, so the
+// caller can keep serving
+pub fn spawn_successor(listeners: &[RawFd]) -> io::Result<Child> {
+    let (mut ready, ready_writer) = pipe()?;
+    let fds: Vec<String> = listeners.iter().map(|fd| fd.to_string()).collect();
+    let mut inherit = listeners.to_vec();
+    inherit.push(ready_writer.as_raw_fd());
+
+    // The path this program was started with finds the new binary once it has replaced
+    // the old file; current_exe would name the deleted one
+    let program = match env::args_os().next() {
+        Some(program) => PathBuf::from(program),
+        None => env::current_exe()?,
+    };
+    let mut command = Command::new(program);
+    command
+        .args(env::args_os().skip(1))
+        .env(LISTEN_FDS_VAR, fds.join(","))
+        .env(READY_FD_VAR, ready_writer.as_raw_fd().to_string());
+    // Between fork and exec, only in the child: keep the descriptors open across exec and
+    // let the new program start with the control signals unblocked, as a fresh one would
+    let signals = signal_set();
+    // SAFETY: the closure runs in the forked child, where only async-signal-safe calls are
+    // allowed; it calls pthread_sigmask and fcntl on a copied signal set and descriptor
+    // list, without allocating or taking locks
+    unsafe {
+        command.pre_exec(move || {
+            libc::pthread_sigmask(libc::SIG_UNBLOCK, &signals, std::ptr::null_mut());
+            for &fd in &inherit {
+                if libc::fcntl(fd, libc::F_SETFD, 0) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+    let mut child = command.spawn()?;
+    drop(ready_writer); // Only the child holds the write end now, so its exit ends our read
+
+    // Read on another thread so a process that hangs before reporting can be given up on
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut byte = [0];
+        let _ = sender.send(ready.read(&mut byte));
+    });
+    match receiver.recv_timeout(READY_TIMEOUT) {
+        Ok(Ok(1)) => Ok(child),
+        outcome => {
+            let _ = child.kill();
+            let _ = child.wait();
+            Err(match outcome {
+                Ok(Err(err)) => err,
+                Err(_) => io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "new process did not start serving in time",
+                ),
+                Ok(Ok(_)) => io::Error::other("new process exited before serving"),
+            })
+        }
+    }
+}
+
+// Creates a pipe whose ends are closed on exec: (read end, write end)
+// The flag is set as the pipe is made, so a fork on another thread can't inherit the ends
+#[cfg(not(target_vendor = "apple"))]
+fn pipe() -> io::Result<(File, File)> {
+    let mut fds = [0; 2];
+    // SAFETY: `fds` has room for the two descriptors pipe2 writes
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: pipe2 just opened both descriptors, and nothing else refers to them
+    Ok(unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) })
+}
+
+// macOS has no pipe2, so there the flag is set right after the pipe is made
+#[cfg(target_vendor = "apple")]
+fn pipe() -> io::Result<(File, File)> {
+    let mut fds = [0; 2];
+    // SAFETY: `fds` has room for the two descriptors pipe writes
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: pipe just opened both descriptors, and nothing else refers to them
+    let ends = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+    for fd in fds {
+        // SAFETY: the descriptors are open and owned by `ends`; this only sets a flag on them
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(ends)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Whether a descriptor is closed on exec
+    fn cloexec(fd: RawFd) -> bool {
+        // SAFETY: fcntl has no memory safety preconditions; the descriptor is open
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+        assert!(flags >= 0);
+        flags & libc::FD_CLOEXEC != 0
+    }
+
+    #[test]
+    fn pipes_are_closed_on_exec() {
+        let (mut reader, mut writer) = pipe().unwrap();
+        assert!(cloexec(reader.as_raw_fd()) && cloexec(writer.as_raw_fd()));
+        writer.write_all(b"1").unwrap();
+        drop(writer);
+        let mut read = String::new();
+        reader.read_to_string(&mut read).unwrap();
+        assert_eq!(read, "1");
+    }
+
+    #[test]
+    fn inherited_descriptors_are_closed_on_exec_again() {
+        let (reader, _writer) = pipe().unwrap();
+        // SAFETY: as in cloexec; the descriptor stays owned by `reader`
+        assert!(unsafe { libc::fcntl(reader.as_raw_fd(), libc::F_SETFD, 0) } >= 0);
+        inherited_fd(reader.as_raw_fd()).unwrap();
+        assert!(cloexec(reader.as_raw_fd()));
+    }
+
+    #[test]
+    fn parses_descriptor_lists() {
+        // Variables of their own, so tests running in parallel don't see each other's
+        let cases = [
+            ("3", Some(vec![3])),
+            ("5, 4,7", Some(vec![5, 4, 7])),
+            ("2", None),   // Standard streams
+            ("4,4", None), // Listed twice
+            ("4,x", None), // Not a number
+            ("", None),
+        ];
+        for (index, (value, expected)) in cases.into_iter().enumerate() {
+            let var = format!("SIMPLE_HTTP_TEST_FDS_{}", index);
+            env::set_var(&var, value);
+            match expected {
+                Some(fds) => assert_eq!(listed_fds(&var).unwrap(), Some(fds), "{:?}", value),
+                None => assert!(listed_fds(&var).is_err(), "{:?}", value),
+            }
+            env::remove_var(&var);
+        }
+        assert_eq!(listed_fds("SIMPLE_HTTP_TEST_FDS_UNSET").unwrap(), None);
+    }
+}
@@ -0,0 +1,404 @@
+// Watches the document root for changes with Linux inotify
+//
+// Every directory below the root gets a watch; directories created or moved in later are
+// watched as they appear (and the files already in them reported as created), and the
+// watches of removed or moved out directories are dropped. Interested parts of the server
+// subscribe to the changes: the file cache drops changed files, and live reload tells
+// browsers. inotify has a per-user limit on watches (fs.inotify.max_user_watches); when it
+// runs out, the directories that couldn't be watched are reported once and the rest keep
+// being watched, so features that depend on the watcher degrade instead of failing.
+// Symlinks aren't followed: a directory reached through a link is only watched at its
+// target, if that is below the root (the file server serves no other), so its changes are
+// reported under the target's path rather than the link's.
+
+use std::collections::HashMap;
+use std::ffi::{CString, OsStr};
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use walkdir::WalkDir;
+
+const TICK: i32 = 500; // How often (in ms) the watch thread checks for stop, when idle
+const BUFFER_SIZE: usize = 64 * 1024; // Room for many events per read
+
+// What changed below the root
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    Created(PathBuf),  // A file or directory appeared (also when moved in)
+    Modified(PathBuf), // A file's content or metadata changed
+    Removed(PathBuf),  // A file or directory went away (also when moved out)
+    Rescan,            // Changes were lost (the event queue overflowed or an event was malformed)
+}
+
+impl Change {
+    // The path that changed, or None if anything may have
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            Change::Created(path) | Change::Modified(path) | Change::Removed(path) => Some(path),
+            Change::Rescan => None,
+        }
+    }
+}
+
+type Subscriber = Arc<Mutex<dyn FnMut(&Change) -> bool + Send>>; // Returns false to unsubscribe
+
+// State shared with the watch thread
+struct Shared {
+    subscribers: Mutex<Vec<Subscriber>>,
+    complete: AtomicBool, // Whether every directory is watched
+    stop: AtomicBool,
+}
+
+// Watches a directory tree until dropped
+pub struct Watcher {
+    root: PathBuf,
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Watcher {
+    // Starts watching `root` and everything below it
+    pub fn new(root: impl Into<PathBuf>) -> io::Result<Watcher> {
+        let root = root.into();
+        let shared = Arc::new(Shared {
+            subscribers: Mutex::new(Vec::new()),
+            complete: AtomicBool::new(true),
+            stop: AtomicBool::new(false),
+        });
+        let mut inotify = Inotify::new(Arc::clone(&shared))?;
+        inotify.watch_tree(&root, false)?;
+        let thread = thread::Builder::new()
+            .name("simple-http-watch".to_string())
+            .spawn(move || inotify.run())?;
+        Ok(Watcher {
+            root,
+            shared,
+            thread: Some(thread),
+        })
+    }
+
+    // The watched directory
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    // Whether every directory is watched; false once the watch limit ran out
+    pub fn is_complete(&self) -> bool {
+        self.shared.complete.load(Ordering::Relaxed)
+    }
+
+    // Calls `callback` on the watch thread for every change
+    pub fn on_change(&self, mut callback: impl FnMut(&Change) + Send + 'static) {
+        self.subscribe_with(move |change| {
+            callback(change);
+            true
+        });
+    }
+
+    // Returns a channel that receives every change until it is dropped
+    pub fn subscribe(&self) -> Receiver<Change> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribe_with(move |change| sender.send(change.clone()).is_ok());
+        receiver
+    }
+
+    fn subscribe_with(&self, subscriber: impl FnMut(&Change) -> bool + Send + 'static) {
+        lock(&self.shared.subscribers).push(Arc::new(Mutex::new(subscriber)));
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        self.shared.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// The inotify instance and its watches, owned by the watch thread
+struct Inotify {
+    fd: RawFd,
+    watches: HashMap<i32, PathBuf>, // Watch descriptor -> watched directory
+    shared: Arc<Shared>,
+}
+
+impl Inotify {
+    fn new(shared: Arc<Shared>) -> io::Result<Inotify> {
+        // SAFETY: inotify_init1 has no memory safety preconditions
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Inotify {
+            fd,
+            watches: HashMap::new(),
+            shared,
+        })
+    }
+
+    // Watches a directory and the ones below it; with `report`, the files and directories
+    // found are reported as created (they appeared before their directory was watched)
+    // Only failing to watch `dir` itself is an error
+    fn watch_tree(&mut self, dir: &Path, report: bool) -> io::Result<()> {
+        let mut entries = WalkDir::new(dir).follow_links(false).into_iter();
+        while let Some(entry) = entries.next() {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(_) => continue, // Gone or unreadable
+            };
+            if report && entry.depth() > 0 {
+                self.notify(&Change::Created(entry.path().to_path_buf()));
+            }
+            if !entry.file_type().is_dir() {
+                continue;
+            }
+            match self.add_watch(entry.path()) {
+                Ok(()) => {}
+                Err(err) if entry.depth() == 0 => return Err(err),
+                Err(err) if err.raw_os_error() == Some(libc::ENOSPC) => {
+                    self.limit_reached(entry.path());
+                    entries.skip_current_dir(); // Its subdirectories can't be watched either
+                }
+                Err(_) => entries.skip_current_dir(), // E.g. removed while walking
+            }
+        }
+        Ok(())
+    }
+
+    fn add_watch(&mut self, dir: &Path) -> io::Result<()> {
+        let path = CString::new(dir.as_os_str().as_bytes())?;
+        let mask = libc::IN_CREATE
+            | libc::IN_DELETE
+            | libc::IN_MODIFY
+            | libc::IN_ATTRIB
+            | libc::IN_CLOSE_WRITE
+            | libc::IN_MOVED_FROM
+            | libc::IN_MOVED_TO
+            | libc::IN_ONLYDIR
+            | libc::IN_EXCL_UNLINK;
+        // SAFETY: `path` is a NUL-terminated string that outlives the call
+        let wd = unsafe { libc::inotify_add_watch(self.fd, path.as_ptr(), mask) };
+        if wd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.watches.insert(wd, dir.to_path_buf());
+        Ok(())
+    }
+
+    // Drops the watches of a directory that went away and of those below it
+    fn unwatch_tree(&mut self, dir: &Path) {
+        let gone: Vec<i32> = self
+            .watches
+            .iter()
+            .filter(|(_, path)| path.starts_with(dir))
+            .map(|(&wd, _)| wd)
+            .collect();
+        for wd in gone {
+            self.watches.remove(&wd);
+            // SAFETY: no memory is involved; fails harmlessly (EINVAL) if the kernel already
+            // dropped the watch
+            unsafe { libc::inotify_rm_watch(self.fd, wd) };
+        }
+    }
+
+    // Reports the watch limit running out, once
+    fn limit_reached(&self, dir: &Path) {
+        if self.shared.complete.swap(false, Ordering::Relaxed) {
+            eprintln!(
+                "Out of inotify watches at {} (raise fs.inotify.max_user_watches); \
+                 changes in the directories left unwatched won't be noticed",
+                dir.display()
+            );
+        }
+    }
+
+    // Reads and dispatches events until the watcher is dropped
+    fn run(mut self) {
+        let mut buffer = vec![0u8; BUFFER_SIZE];
+        while !self.shared.stop.load(Ordering::Relaxed) {
+            let mut poll = libc::pollfd {
+                fd: self.fd,
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            // SAFETY: `poll` is one valid pollfd, matching the count of 1
+            if unsafe { libc::poll(&mut poll, 1, TICK) } <= 0 {
+                continue; // Timed out or interrupted
+            }
+            // SAFETY: the kernel writes at most `buffer.len()` bytes into the buffer
+            let read = unsafe { libc::read(self.fd, buffer.as_mut_ptr().cast(), buffer.len()) };
+            if read < 0 {
+                let err = io::Error::last_os_error();
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+                ) {
+                    continue;
+                }
+                eprintln!("Stopped watching for changes: {}", err);
+                return;
+            }
+            self.dispatch(&buffer[..read as usize]);
+        }
+    }
+
+    // Turns a buffer of raw inotify events into changes
+    // The kernel only returns whole events; a buffer that ends inside one is malformed, and
+    // as its changes can't be trusted, subscribers are told to rescan instead
+    fn dispatch(&mut self, mut events: &[u8]) {
+        let header = mem::size_of::<libc::inotify_event>();
+        while !events.is_empty() {
+            if events.len() < header {
+                self.notify(&Change::Rescan);
+                return;
+            }
+            // SAFETY: at least `header` bytes are left, and read_unaligned copes with the
+            // kernel only aligning events to 4 bytes; every bit pattern is a valid event
+            let event: libc::inotify_event =
+                unsafe { std::ptr::read_unaligned(events.as_ptr().cast()) };
+            let end = match header.checked_add(event.len as usize) {
+                Some(end) if end <= events.len() => end,
+                _ => {
+                    self.notify(&Change::Rescan);
+                    return;
+                }
+            };
+            let name = &events[header..end];
+            let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+            events = &events[end..];
+            self.handle(event.wd, event.mask, OsStr::from_bytes(name));
+        }
+    }
+
+    fn handle(&mut self, wd: i32, mask: u32, name: &OsStr) {
+        if mask & libc::IN_Q_OVERFLOW != 0 {
+            self.notify(&Change::Rescan);
+            return;
+        }
+        if mask & libc::IN_IGNORED != 0 {
+            self.watches.remove(&wd); // The directory was removed or unmounted
+            return;
+        }
+        let path = match self.watches.get(&wd) {
+            Some(dir) if !name.is_empty() => dir.join(name),
+            _ => return, // Events about the directory itself are reported by its parent
+        };
+        let is_dir = mask & libc::IN_ISDIR != 0;
+        if mask & (libc::IN_CREATE | libc::IN_MOVED_TO) != 0 {
+            self.notify(&Change::Created(path.clone()));
+            if is_dir {
+                let _ = self.watch_tree(&path, true); // Gone again already if it fails
+            }
+        } else if mask & (libc::IN_DELETE | libc::IN_MOVED_FROM) != 0 {
+            if is_dir {
+                self.unwatch_tree(&path);
+            }
+            self.notify(&Change::Removed(path));
+        } else if mask & (libc::IN_MODIFY | libc::IN_ATTRIB | libc::IN_CLOSE_WRITE) != 0 {
+            self.notify(&Change::Modified(path));
+        }
+    }
+
+    // Calls the subscribers on a copy of the list, so they may subscribe in turn
+    fn notify(&self, change: &Change) {
+        let subscribers = lock(&self.shared.subscribers).clone();
+        let done: Vec<Subscriber> = subscribers
+            .into_iter()
+            .filter(|subscriber| !(*lock(subscriber))(change))
+            .collect();
+        if !done.is_empty() {
+            lock(&self.shared.subscribers)
+                .retain(|subscriber| !done.iter().any(|done| Arc::ptr_eq(subscriber, done)));
+        }
+    }
+}
+
+impl Drop for Inotify {
+    fn drop(&mut self) {
+        // SAFETY: the descriptor is owned by this Inotify and closed only here
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+// Subscribers stay usable even if one of them panicked
+fn lock<T: ?Sized>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use std::fs;
+    use std::time::{Duration, Instant};
+
+    // Waits for `expected` among the changes received, failing after a few seconds
+    fn wait_for(changes: &Receiver<Change>, expected: Change) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+            match changes.recv_timeout(left) {
+                Ok(change) if change == expected => return,
+                Ok(_) => {}
+                Err(_) => break,
+            }
+        }
+        panic!("no {:?}", expected);
+    }
+
+    #[test]
+    fn reports_changes_below_the_root() {
+        let dir = TempDir::new("watch");
+        let watcher = Watcher::new(dir.path()).unwrap();
+        assert!(watcher.is_complete());
+        let changes = watcher.subscribe();
+        let file = dir.join("a.txt");
+        fs::write(&file, "a").unwrap();
+        wait_for(&changes, Change::Created(file.clone()));
+        fs::write(&file, "b").unwrap();
+        wait_for(&changes, Change::Modified(file.clone()));
+        fs::remove_file(&file).unwrap();
+        wait_for(&changes, Change::Removed(file));
+    }
+
+    #[test]
+    fn watches_directories_as_they_appear() {
+        let dir = TempDir::new("watch-new");
+        let watcher = Watcher::new(dir.path()).unwrap();
+        let changes = watcher.subscribe();
+        let nested = dir.mkdir("sub/deeper");
+        wait_for(&changes, Change::Created(dir.join("sub")));
+        let file = nested.join("a.txt");
+        fs::write(&file, "a").unwrap();
+        wait_for(&changes, Change::Created(file));
+        fs::remove_dir_all(dir.join("sub")).unwrap();
+        wait_for(&changes, Change::Removed(dir.join("sub")));
+    }
+
+    #[test]
+    fn subscribers_may_subscribe() {
+        let dir = TempDir::new("watch-subscribe");
+        let watcher = Arc::new(Watcher::new(dir.path()).unwrap());
+        let (sender, subscribed) = mpsc::channel();
+        let weak = Arc::downgrade(&watcher);
+        watcher.subscribe_with(move |_| {
+            if let Some(watcher) = weak.upgrade() {
+                let _ = sender.send(watcher.subscribe());
+            }
+            false // Once is enough
+        });
+        fs::write(dir.join("a.txt"), "a").unwrap();
+        let changes = subscribed
+            .recv_timeout(Duration::from_secs(5))
+            .expect("the callback did not run");
+        fs::write(dir.join("b.txt"), "b").unwrap();
+        wait_for(&changes, Change::Created(dir.join("b.txt")));
+        assert_eq!(lock(&watcher.shared.subscribers).len(), 1); // The first one unsubscribed
+    }
+}
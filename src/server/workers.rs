@@ -0,0 +1,198 @@
+// SO_REUSEPORT workers: several accept loops, each with its own listener bound to the
+// same address, so the kernel spreads incoming connections between them
+//
+// A supervisor starts the workers, restarts any that stop, replaces them on request and
+// stops them all on shutdown. Workers share the handler and the count of connections in
+// progress, so shutdown still waits for every request. A worker keeps its listener across
+// restarts, so connections queued on it are not lost and the sockets handed over on an
+// upgrade stay the ones in use.
+
+use super::{accept_loop, ActiveConnections, Control, Server};
+use crate::http::handler::Handler;
+use std::io;
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+const TICK: Duration = Duration::from_secs(1); // How often the shutdown flag is checked
+
+// One running accept loop
+struct Worker {
+    listener: Arc<TcpListener>,
+    stop: Arc<AtomicBool>,
+    generation: u64, // Tells the exit of this worker apart from the one it replaced
+    thread: JoinHandle<()>,
+}
+
+impl Worker {
+    // Makes the accept loop return; it notices within the listener's accept timeout
+    // The socket is left alone, as a process taking over on upgrade may share it
+    fn stop(self) {
+        self.stop.store(true, Ordering::SeqCst);
+        let _ = self.thread.join();
+    }
+}
+
+// Shared by every worker of a server
+struct Shared {
+    handler: Arc<dyn Handler>,
+    limits: super::Limits,
+    active: Arc<ActiveConnections>,
+    control: Sender<Control>,
+}
+
+// Runs the workers until shutdown, then waits for the connections in progress
+pub(super) fn supervise(
+    server: Server,
+    control: Sender<Control>,
+    messages: Receiver<Control>,
+) -> io::Result<()> {
+    let shared = Shared {
+        handler: server.handler,
+        limits: server.limits,
+        active: Arc::new(ActiveConnections::default()),
+        control,
+    };
+
+    let mut workers = Vec::with_capacity(server.listeners.len());
+    for (index, listener) in server.listeners.into_iter().enumerate() {
+        workers.push(start(&shared, index, 0, Arc::new(listener))?);
+    }
+
+    while !server.shutdown.load(Ordering::SeqCst) {
+        match messages.recv_timeout(TICK) {
+            Ok(Control::Shutdown) | Err(RecvTimeoutError::Disconnected) => break,
+            Ok(Control::Restart(index)) if index < workers.len() => {
+                restart(&shared, &mut workers, index)
+            }
+            Ok(Control::Restart(index)) => eprintln!("No worker {} to restart", index),
+            Ok(Control::Exited(index, generation)) => {
+                // Exits of replaced workers are expected
+                if workers[index].generation == generation
+                    && !server.shutdown.load(Ordering::SeqCst)
+                {
+                    eprintln!("Worker {} stopped unexpectedly, restarting it", index);
+                    restart(&shared, &mut workers, index);
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+        }
+    }
+
+    for worker in workers {
+        worker.stop();
+    }
+    shared.active.wait_idle();
+    Ok(())
+}
+
+// Starts an accept loop on its own thread
+fn start(
+    shared: &Shared,
+    index: usize,
+    generation: u64,
+    listener: Arc<TcpListener>,
+) -> io::Result<Worker> {
+    let stop = Arc::new(AtomicBool::new(false));
+    let thread = {
+        let listener = Arc::clone(&listener);
+        let stop = Arc::clone(&stop);
+        let handler = Arc::clone(&shared.handler);
+        let limits = shared.limits;
+        let active = Arc::clone(&shared.active);
+        let control = shared.control.clone();
+        thread::Builder::new()
+            .name(format!("simple-http-worker-{}", index))
+            .spawn(move || {
+                // Reports the exit even if the accept loop panics
+                let _exited = ExitNotice {
+                    control,
+                    index,
+                    generation,
+                };
+                accept_loop(&listener, &handler, limits, &stop, &active);
+            })?
+    };
+    Ok(Worker {
+        listener,
+        stop,
+        generation,
+        thread,
+    })
+}
+
+// Replaces a worker with a fresh accept loop on the same listener; the new loop starts
+// before the old one stops, so the listener keeps accepting connections throughout
+fn restart(shared: &Shared, workers: &mut [Worker], index: usize) {
+    let generation = workers[index].generation + 1;
+    let listener = Arc::clone(&workers[index].listener);
+    let replacement = start(shared, index, generation, listener);
+    match replacement {
+        Ok(worker) => std::mem::replace(&mut workers[index], worker).stop(),
+        Err(err) => eprintln!("Failed to restart worker {}: {}", index, err),
+    }
+}
+
+// Tells the supervisor when a worker thread ends
+struct ExitNotice {
+    control: Sender<Control>,
+    index: usize,
+    generation: u64,
+}
+
+impl Drop for ExitNotice {
+    fn drop(&mut self) {
+        let _ = self
+            .control
+            .send(Control::Exited(self.index, self.generation));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::Server;
+    use crate::testing::{echo, exchange};
+    use std::io;
+
+    const REQUEST: &[u8] = b"GET / HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n";
+
+    #[test]
+    fn workers_share_the_address_and_survive_restarts() {
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .handler(echo)
+            .workers(3)
+            .build()
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        assert_eq!(server.listeners().len(), 3);
+        for listener in server.listeners() {
+            assert_eq!(listener.local_addr().unwrap(), addr);
+        }
+
+        let handle = server.spawn().unwrap();
+        for round in 0..3 {
+            for _ in 0..6 {
+                let response = exchange(addr, &[REQUEST]);
+                assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+            }
+            handle.restart_worker(round).unwrap();
+        }
+        handle.shutdown().unwrap();
+    }
+
+    #[test]
+    fn restarting_needs_workers() {
+        let handle = Server::builder()
+            .bind("127.0.0.1:0")
+            .handler(echo)
+            .spawn()
+            .unwrap();
+        let err = handle.restart_worker(0).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        handle.shutdown().unwrap();
+    }
+}
@@ -0,0 +1,119 @@
+// Helpers shared by the unit tests
+
+use crate::http::handler::{BodySink, Handler};
+use crate::http::request::{HttpRequest, StreamedBody};
+use crate::http::response::{HttpResponse, ResponseStatus};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0); // Keeps directories of one process apart
+
+// A directory below the system temp dir, removed with everything in it when dropped
+pub(crate) struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    // Creates an empty directory; `name` only makes leftovers easier to recognize
+    pub(crate) fn new(name: &str) -> TempDir {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let path = std::env::temp_dir().join(format!(
+            "simple-http-test-{}-{}-{}",
+            std::process::id(),
+            id,
+            name
+        ));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TempDir { path }
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // The path of `relative` below the directory
+    pub(crate) fn join(&self, relative: impl AsRef<Path>) -> PathBuf {
+        self.path.join(relative)
+    }
+
+    // Creates a directory (and its parents) below the directory
+    pub(crate) fn mkdir(&self, relative: &str) -> PathBuf {
+        let path = self.join(relative);
+        fs::create_dir_all(&path).unwrap();
+        path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+// A handler answering with the request body, then its trailer fields as `name: value` lines
+pub(crate) fn echo(request: &mut HttpRequest) -> io::Result<HttpResponse> {
+    let mut body = request.request_body.clone();
+    for (name, value) in &request.request_trailers {
+        body.extend_from_slice(format!("\n{}: {}", name, value).as_bytes());
+    }
+    let mut response = HttpResponse::with_status(ResponseStatus::OK);
+    response.set_body("text/plain", body);
+    Ok(response)
+}
+
+// A handler that has request bodies streamed to it, answering like `echo`
+#[derive(Default)]
+pub(crate) struct StreamingEcho {
+    received: Arc<Mutex<Vec<u8>>>, // Body of the request in progress
+}
+
+// Appends to the body the StreamingEcho is receiving
+struct Received(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Received {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Handler for StreamingEcho {
+    fn handle(&self, request: &mut HttpRequest) -> io::Result<HttpResponse> {
+        assert!(request.streamed_body.is_some() && request.request_body.is_empty());
+        request.request_body = std::mem::take(&mut *self.received.lock().unwrap());
+        echo(request)
+    }
+
+    fn body_sink(&self, _request: &HttpRequest) -> BodySink {
+        let received = Received(Arc::clone(&self.received));
+        BodySink::Stream(StreamedBody::new(received))
+    }
+}
+
+// Sends a request to a server in pieces that arrive separately, and returns everything it
+// sent back until it closed the connection
+pub(crate) fn exchange(addr: SocketAddr, pieces: &[&[u8]]) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    stream.set_nodelay(true).unwrap();
+    for piece in pieces {
+        stream.write_all(piece).unwrap();
+        thread::sleep(Duration::from_millis(20));
+    }
+    let mut response = Vec::new();
+    let _ = stream.read_to_end(&mut response); // A reset after the response is fine too
+    String::from_utf8_lossy(&response).into_owned()
+}